@@ -0,0 +1,441 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for the [canonical JSON] encoding and [signed JSON] objects used
+//! throughout Matrix.
+//!
+//! Signing a JSON object in Matrix works by removing the `signatures` and
+//! `unsigned` fields from the object, encoding the rest of the object as
+//! canonical JSON, and signing the resulting string. The signature is then
+//! added to the object under the `signatures.{user_id}.{key_id}` field.
+//!
+//! The [`Account::sign_json`] and [`Ed25519PublicKey::verify_json`] methods
+//! take care of all of this.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use anyhow::Result;
+//! use serde_json::json;
+//! use vodozemac::{canonical_json::to_canonical_json, olm::Account};
+//!
+//! fn main() -> Result<()> {
+//!     assert_eq!(to_canonical_json(&json!({"b": "2", "a": "1"}))?, r#"{"a":"1","b":"2"}"#);
+//!
+//!     let account = Account::new();
+//!     let mut device_keys = json!({
+//!         "user_id": "@alice:example.org",
+//!         "device_id": "DEVICEID",
+//!         "keys": {
+//!             "ed25519:DEVICEID": account.ed25519_key().to_base64(),
+//!             "curve25519:DEVICEID": account.curve25519_key().to_base64(),
+//!         },
+//!     });
+//!
+//!     account.sign_json("@alice:example.org", "DEVICEID", &mut device_keys)?;
+//!     account.ed25519_key().verify_json("@alice:example.org", "DEVICEID", &device_keys)?;
+//!
+//!     Ok(())
+//! }
+//! ```
+//!
+//! [canonical JSON]: https://spec.matrix.org/v1.2/appendices/#canonical-json
+//! [signed JSON]: https://spec.matrix.org/v1.2/appendices/#signing-json
+//! [`Account::sign_json`]: crate::olm::Account::sign_json
+
+use std::fmt::Write;
+
+use serde_json::{Map, Number, Value};
+use thiserror::Error;
+
+use crate::{Ed25519PublicKey, Ed25519Signature, SignatureError};
+
+/// The biggest integer canonical JSON allows, numbers need to be in the
+/// `[-(2**53)+1, (2**53)-1]` range.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// Error type describing the ways a JSON value can fail to be encoded as
+/// canonical JSON or signed.
+#[derive(Debug, Error)]
+pub enum CanonicalJsonError {
+    /// The JSON value isn't an object, only objects can be signed.
+    #[error("The JSON value isn't an object")]
+    NotAnObject,
+    /// The JSON value contains a number that can't be represented in canonical
+    /// JSON, either because it isn't an integer or because it's out of range.
+    #[error("The JSON value contains a number which isn't allowed in canonical JSON: {0}")]
+    InvalidNumber(Number),
+    /// The `signatures` field, or the signatures of the given user, aren't a
+    /// JSON object.
+    #[error("The signatures of the JSON object aren't a valid JSON object")]
+    InvalidSignatures,
+}
+
+/// Error type describing signature verification failures of signed JSON
+/// objects.
+#[derive(Debug, Error)]
+pub enum JsonSignatureError {
+    /// The JSON object couldn't be encoded as canonical JSON.
+    #[error(transparent)]
+    CanonicalJson(#[from] CanonicalJsonError),
+    /// The JSON object doesn't contain a signature for the given user and key
+    /// ID.
+    #[error("The JSON object doesn't contain a signature from {user_id} using the key {key_id}")]
+    MissingSignature {
+        /// The user ID the signature was expected from.
+        user_id: String,
+        /// The key ID the signature was expected to use.
+        key_id: String,
+    },
+    /// The signature couldn't be decoded or it failed to be verified.
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+}
+
+/// Encode the given JSON value as canonical JSON.
+///
+/// Object keys are sorted by their codepoints, all insignificant whitespace
+/// is removed and numbers are encoded as integers.
+pub fn to_canonical_json(value: &Value) -> Result<String, CanonicalJsonError> {
+    let mut output = String::new();
+    write_value(value, &mut output)?;
+
+    Ok(output)
+}
+
+/// Encode the given JSON object as canonical JSON, leaving out the
+/// `signatures` and `unsigned` fields.
+///
+/// This is the string that gets signed when a JSON object is signed.
+pub fn to_signable_json(value: &Value) -> Result<String, CanonicalJsonError> {
+    let object = value.as_object().ok_or(CanonicalJsonError::NotAnObject)?;
+    let entries = object.iter().filter(|(key, _)| *key != "signatures" && *key != "unsigned");
+
+    let mut output = String::new();
+    write_object(entries, &mut output)?;
+
+    Ok(output)
+}
+
+pub(crate) fn key_id(device_id: &str) -> String {
+    format!("ed25519:{device_id}")
+}
+
+/// Sign the given JSON object and insert the signature into the
+/// `signatures.{user_id}.{ed25519:device_id}` field, existing signatures are
+/// preserved.
+pub(crate) fn sign_json(
+    user_id: &str,
+    device_id: &str,
+    value: &mut Value,
+    sign: impl FnOnce(&[u8]) -> Ed25519Signature,
+) -> Result<(), CanonicalJsonError> {
+    let signature = sign(to_signable_json(value)?.as_bytes());
+
+    let object = value.as_object_mut().ok_or(CanonicalJsonError::NotAnObject)?;
+    let signatures = object
+        .entry("signatures")
+        .or_insert_with(|| Map::new().into())
+        .as_object_mut()
+        .ok_or(CanonicalJsonError::InvalidSignatures)?
+        .entry(user_id)
+        .or_insert_with(|| Map::new().into())
+        .as_object_mut()
+        .ok_or(CanonicalJsonError::InvalidSignatures)?;
+
+    signatures.insert(key_id(device_id), signature.to_base64().into());
+
+    Ok(())
+}
+
+/// Verify the signature found in the `signatures.{user_id}.{ed25519:device_id}`
+/// field of the given JSON object.
+pub(crate) fn verify_json(
+    public_key: &Ed25519PublicKey,
+    user_id: &str,
+    device_id: &str,
+    value: &Value,
+) -> Result<(), JsonSignatureError> {
+    let key_id = key_id(device_id);
+
+    let signature = value
+        .get("signatures")
+        .and_then(|s| s.get(user_id))
+        .and_then(|s| s.get(&key_id))
+        .and_then(|s| s.as_str());
+
+    if let Some(signature) = signature {
+        let signature = Ed25519Signature::from_base64(signature)?;
+        let message = to_signable_json(value)?;
+
+        Ok(public_key.verify(message.as_bytes(), &signature)?)
+    } else {
+        Err(JsonSignatureError::MissingSignature { user_id: user_id.to_owned(), key_id })
+    }
+}
+
+fn write_value(value: &Value, output: &mut String) -> Result<(), CanonicalJsonError> {
+    match value {
+        // serde_json already encodes these in their canonical form, strings
+        // are UTF-8 encoded and only the necessary characters get escaped.
+        Value::Null | Value::Bool(_) | Value::String(_) => push_display(output, value),
+        Value::Number(number) => push_display(output, canonical_integer(number)?),
+        Value::Array(array) => {
+            output.push('[');
+
+            for (i, value) in array.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+
+                write_value(value, output)?;
+            }
+
+            output.push(']');
+        }
+        Value::Object(object) => write_object(object.iter(), output)?,
+    }
+
+    Ok(())
+}
+
+fn write_object<'a>(
+    entries: impl Iterator<Item = (&'a String, &'a Value)>,
+    output: &mut String,
+) -> Result<(), CanonicalJsonError> {
+    // Don't rely on the map being sorted, serde_json might be compiled with
+    // the `preserve_order` feature.
+    let mut entries: Vec<_> = entries.collect();
+    entries.sort_unstable_by_key(|(key, _)| *key);
+
+    output.push('{');
+
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            output.push(',');
+        }
+
+        push_display(output, Value::from(key.as_str()));
+        output.push(':');
+        write_value(value, output)?;
+    }
+
+    output.push('}');
+
+    Ok(())
+}
+
+fn canonical_integer(number: &Number) -> Result<i64, CanonicalJsonError> {
+    let integer = if let Some(integer) = number.as_i64() {
+        Some(integer)
+    } else {
+        // Floats are allowed as long as they represent an integer, `1e10` is
+        // encoded as `10000000000`.
+        number
+            .as_f64()
+            .filter(|f| f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER as f64)
+            .map(|f| f as i64)
+    };
+
+    integer
+        .filter(|i| i.abs() <= MAX_SAFE_INTEGER)
+        .ok_or_else(|| CanonicalJsonError::InvalidNumber(number.clone()))
+}
+
+fn push_display(output: &mut String, value: impl std::fmt::Display) {
+    // Writing into a `String` never fails.
+    let _ = write!(output, "{value}");
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use serde_json::{json, Value};
+
+    use super::{to_canonical_json, to_signable_json, CanonicalJsonError, JsonSignatureError};
+    use crate::{olm::Account, Ed25519SecretKey};
+
+    fn canonical(json: &str) -> String {
+        let value: Value =
+            serde_json::from_str(json).expect("The test vector should be valid JSON");
+        to_canonical_json(&value).expect("The test vector should be encodable as canonical JSON")
+    }
+
+    #[test]
+    fn canonical_json_test_vectors() {
+        // Test vectors from the canonical JSON section of the Matrix spec.
+        assert_eq!(canonical("{}"), "{}");
+        assert_eq!(canonical(r#"{"one": 1, "two": "Two"}"#), r#"{"one":1,"two":"Two"}"#);
+        assert_eq!(canonical(r#"{"b": "2", "a": "1"}"#), r#"{"a":"1","b":"2"}"#);
+        assert_eq!(canonical(r#"{"b":"2","a":"1"}"#), r#"{"a":"1","b":"2"}"#);
+        assert_eq!(
+            canonical(
+                r#"{
+                    "auth": {
+                        "success": true,
+                        "mxid": "@john.doe:example.com",
+                        "profile": {
+                            "display_name": "John Doe",
+                            "three_pids": [
+                                {
+                                    "medium": "email",
+                                    "address": "john.doe@example.org"
+                                },
+                                {
+                                    "medium": "msisdn",
+                                    "address": "123456789"
+                                }
+                            ]
+                        }
+                    }
+                }"#
+            ),
+            concat!(
+                r#"{"auth":{"mxid":"@john.doe:example.com","profile":{"display_name":"John Doe","#,
+                r#""three_pids":[{"address":"john.doe@example.org","medium":"email"},"#,
+                r#"{"address":"123456789","medium":"msisdn"}]},"success":true}}"#
+            )
+        );
+        assert_eq!(canonical(r#"{"a": "日本語"}"#), r#"{"a":"日本語"}"#);
+        assert_eq!(canonical(r#"{"本": 2, "日": 1}"#), r#"{"日":1,"本":2}"#);
+        assert_eq!(canonical(r#"{"a": "日"}"#), r#"{"a":"日"}"#);
+        assert_eq!(canonical(r#"{"a": null}"#), r#"{"a":null}"#);
+        assert_eq!(canonical(r#"{"a": -0, "b": 1e10}"#), r#"{"a":0,"b":10000000000}"#);
+    }
+
+    #[test]
+    fn invalid_numbers() {
+        assert_matches!(
+            to_canonical_json(&json!({ "a": 1.5 })),
+            Err(CanonicalJsonError::InvalidNumber(_))
+        );
+        assert_matches!(
+            to_canonical_json(&json!({ "a": 9007199254740992u64 })),
+            Err(CanonicalJsonError::InvalidNumber(_))
+        );
+        assert_matches!(
+            to_canonical_json(&json!({ "a": -9007199254740992i64 })),
+            Err(CanonicalJsonError::InvalidNumber(_))
+        );
+        assert_eq!(
+            to_canonical_json(&json!({ "a": 9007199254740991u64 }))
+                .expect("The biggest safe integer should be allowed"),
+            r#"{"a":9007199254740991}"#
+        );
+    }
+
+    #[test]
+    fn signing_test_vectors() {
+        // Test vectors from the signing JSON section of the Matrix spec.
+        let secret_key = Ed25519SecretKey::from_slice(&[
+            96, 144, 193, 3, 213, 231, 175, 107, 21, 169, 112, 253, 86, 62, 215, 85, 73, 230, 21,
+            151, 25, 174, 92, 60, 49, 222, 228, 49, 111, 183, 92, 13,
+        ])
+        .expect("The seed should be a valid Ed25519 secret key");
+        let public_key = secret_key.public_key();
+
+        let mut value = json!({});
+        super::sign_json("domain", "1", &mut value, |m| secret_key.sign(m))
+            .expect("An empty object should be signable");
+
+        assert_eq!(
+            value,
+            json!({
+                "signatures": {
+                    "domain": {
+                        "ed25519:1": "K8280/U9SSy9IVtjBuVeLr+HpOB4BQFWbg+UZaADMtTdGYI7Geitb76LTrr5QV/7Xg4ahLwYGYZzuHGZKM5ZAQ"
+                    }
+                }
+            })
+        );
+        public_key.verify_json("domain", "1", &value).expect("The signature should be valid");
+
+        let mut value = json!({ "one": 1, "two": "Two" });
+        super::sign_json("domain", "1", &mut value, |m| secret_key.sign(m))
+            .expect("The object should be signable");
+
+        assert_eq!(
+            value,
+            json!({
+                "one": 1,
+                "signatures": {
+                    "domain": {
+                        "ed25519:1": "KqmLSbO39/Bzb0QIYE82zqLwsA+PDzYIpIRA2sRQ4sL53+sN6/fpNSoqE7BP7vBZhG6kYdD13EIMJpvhJI+6Bw"
+                    }
+                },
+                "two": "Two"
+            })
+        );
+        public_key.verify_json("domain", "1", &value).expect("The signature should be valid");
+    }
+
+    #[test]
+    fn signing_ignores_signatures_and_unsigned() {
+        let account = Account::new();
+        let mut value = json!({
+            "one": 1,
+            "signatures": {
+                "@bob:example.org": {
+                    "ed25519:BOBDEVICE": "c2lnbmF0dXJl"
+                }
+            },
+            "unsigned": {
+                "age": 10
+            }
+        });
+
+        assert_eq!(
+            to_signable_json(&value).expect("The object should be signable"),
+            r#"{"one":1}"#
+        );
+
+        account
+            .sign_json("@alice:example.org", "DEVICEID", &mut value)
+            .expect("The object should be signable");
+
+        // Existing signatures are kept around.
+        assert_eq!(value["signatures"]["@bob:example.org"]["ed25519:BOBDEVICE"], "c2lnbmF0dXJl");
+
+        // Changing the unsigned field doesn't invalidate the signature.
+        value["unsigned"]["age"] = json!(20);
+        account
+            .ed25519_key()
+            .verify_json("@alice:example.org", "DEVICEID", &value)
+            .expect("The signature should be valid");
+
+        // Changing the signed content does invalidate it.
+        value["one"] = json!(2);
+        assert_matches!(
+            account.ed25519_key().verify_json("@alice:example.org", "DEVICEID", &value),
+            Err(JsonSignatureError::Signature(_))
+        );
+
+        assert_matches!(
+            account.ed25519_key().verify_json("@alice:example.org", "OTHERDEVICE", &value),
+            Err(JsonSignatureError::MissingSignature { .. })
+        );
+        assert_matches!(
+            account.sign_json("@alice:example.org", "DEVICEID", &mut json!([])),
+            Err(CanonicalJsonError::NotAnObject)
+        );
+        assert_matches!(
+            account.sign_json(
+                "@alice:example.org",
+                "DEVICEID",
+                &mut json!({ "signatures": "invalid" })
+            ),
+            Err(CanonicalJsonError::InvalidSignatures)
+        );
+    }
+}
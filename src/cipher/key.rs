@@ -17,6 +17,7 @@ use aes::{
     cipher::{generic_array::GenericArray, IvSizeUser, KeySizeUser},
     Aes256,
 };
+use chacha20poly1305::{Key as ChaChaKey, Nonce as ChaChaNonce};
 use hkdf::Hkdf;
 use sha2::Sha256;
 use zeroize::Zeroize;
@@ -111,3 +112,52 @@ impl CipherKeys {
         Aes256Iv::from_slice(self.aes_iv.as_slice())
     }
 }
+
+/// The keys for the ChaCha20-Poly1305 based message versions.
+///
+/// Every message key is only ever used to encrypt a single message, so the
+/// nonce can be derived from the message key together with the cipher key.
+#[derive(Zeroize)]
+#[zeroize(drop)]
+pub(super) struct AeadKeys {
+    key: Box<[u8; 32]>,
+    nonce: Box<[u8; 12]>,
+}
+
+impl AeadKeys {
+    const OLM_HKDF_INFO: &'static [u8] = b"OLM_AEAD_KEYS";
+    const MEGOLM_HKDF_INFO: &'static [u8] = b"MEGOLM_AEAD_KEYS";
+
+    pub fn new(message_key: &[u8; 32]) -> Self {
+        Self::new_helper(message_key, Self::OLM_HKDF_INFO)
+    }
+
+    pub fn new_megolm(message_key: &[u8; 128]) -> Self {
+        Self::new_helper(message_key, Self::MEGOLM_HKDF_INFO)
+    }
+
+    fn new_helper(message_key: &[u8], info: &[u8]) -> Self {
+        let mut expanded_keys = Box::new([0u8; 44]);
+
+        let hkdf: Hkdf<Sha256> = Hkdf::new(Some(&[0]), message_key);
+        hkdf.expand(info, expanded_keys.as_mut_slice()).expect("Can't expand message key");
+
+        let mut key = Box::new([0u8; 32]);
+        let mut nonce = Box::new([0u8; 12]);
+
+        key.copy_from_slice(&expanded_keys[0..32]);
+        nonce.copy_from_slice(&expanded_keys[32..44]);
+
+        expanded_keys.zeroize();
+
+        Self { key, nonce }
+    }
+
+    pub fn key(&self) -> &ChaChaKey {
+        ChaChaKey::from_slice(self.key.as_slice())
+    }
+
+    pub fn nonce(&self) -> &ChaChaNonce {
+        ChaChaNonce::from_slice(self.nonce.as_slice())
+    }
+}
@@ -14,6 +14,7 @@
 // limitations under the License.
 
 mod key;
+mod padding;
 
 use aes::{
     cipher::{
@@ -22,8 +23,14 @@ use aes::{
     },
     Aes256,
 };
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305, Tag,
+};
 use hmac::{digest::MacError, Hmac, Mac as MacT};
-use key::CipherKeys;
+use key::{AeadKeys, CipherKeys};
+pub(crate) use padding::unpad;
+pub use padding::{PaddingError, PaddingScheme};
 use sha2::Sha256;
 use thiserror::Error;
 
@@ -54,6 +61,8 @@ impl Mac {
 pub(crate) enum MessageMac {
     Truncated([u8; Mac::TRUNCATED_LEN]),
     Full(Mac),
+    /// The authentication tag of a message encrypted using an [`AeadCipher`].
+    Tag([u8; AeadCipher::TAG_LENGTH]),
 }
 
 impl MessageMac {
@@ -61,6 +70,7 @@ impl MessageMac {
         match self {
             MessageMac::Truncated(m) => m.as_ref(),
             MessageMac::Full(m) => m.as_bytes(),
+            MessageMac::Tag(t) => t.as_ref(),
         }
     }
 }
@@ -77,6 +87,12 @@ impl From<[u8; Mac::TRUNCATED_LEN]> for MessageMac {
     }
 }
 
+impl From<[u8; AeadCipher::TAG_LENGTH]> for MessageMac {
+    fn from(t: [u8; AeadCipher::TAG_LENGTH]) -> Self {
+        Self::Tag(t)
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum DecryptionError {
     #[error("Failed decrypting, invalid padding")]
@@ -192,3 +208,66 @@ impl Cipher {
         Ok(())
     }
 }
+
+/// A ChaCha20-Poly1305 based cipher, used by the message versions which
+/// authenticate the ciphertext using an AEAD instead of a separate HMAC.
+///
+/// Unlike the AES-CBC based [`Cipher`], the ciphertext isn't padded.
+pub struct AeadCipher {
+    keys: AeadKeys,
+}
+
+impl AeadCipher {
+    pub const TAG_LENGTH: usize = 16;
+
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self { keys: AeadKeys::new(key) }
+    }
+
+    pub fn new_megolm(key: &[u8; 128]) -> Self {
+        Self { keys: AeadKeys::new_megolm(key) }
+    }
+
+    pub fn encrypt(
+        &self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> (Vec<u8>, [u8; Self::TAG_LENGTH]) {
+        let cipher = ChaCha20Poly1305::new(self.keys.key());
+        let mut ciphertext = plaintext.to_vec();
+
+        // Encryption only fails if the plaintext is larger than what
+        // ChaCha20-Poly1305 supports, which is far beyond the size of a message.
+        let tag = cipher
+            .encrypt_in_place_detached(self.keys.nonce(), associated_data, &mut ciphertext)
+            .expect("We should be able to encrypt the message");
+
+        let mut tag_bytes = [0u8; Self::TAG_LENGTH];
+        tag_bytes.copy_from_slice(&tag);
+
+        (ciphertext, tag_bytes)
+    }
+
+    pub fn decrypt(
+        &self,
+        ciphertext: &[u8],
+        tag: &[u8; Self::TAG_LENGTH],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, MacError> {
+        let cipher = ChaCha20Poly1305::new(self.keys.key());
+        let mut plaintext = ciphertext.to_vec();
+
+        // Decryption only fails if the authentication tag doesn't match, which
+        // is the same as a MAC failing to be verified.
+        cipher
+            .decrypt_in_place_detached(
+                self.keys.nonce(),
+                associated_data,
+                &mut plaintext,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| MacError)?;
+
+        Ok(plaintext)
+    }
+}
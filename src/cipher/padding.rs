@@ -0,0 +1,141 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The byte that marks the end of the plaintext, it's followed by zero bytes
+/// up to the padded length.
+const PADDING_MARKER: u8 = 0x80;
+
+/// Error type describing a plaintext whose padding, added by a
+/// [`PaddingScheme`], is malformed.
+#[derive(Debug, Error)]
+#[error("The padding marker of the plaintext is missing or followed by non-zero bytes")]
+pub struct PaddingError;
+
+/// The scheme deciding how long a plaintext gets padded before it gets
+/// encrypted, hiding its exact length.
+///
+/// The padding itself is the same for all schemes, a single `0x80` byte
+/// followed by zero bytes, the receiving side doesn't need to know which
+/// scheme was used. Plaintexts are padded to at least 32 bytes, so short
+/// messages, like reactions, can't be told apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaddingScheme {
+    /// The Padmé scheme, which adds at most 12% of overhead while leaving only
+    /// a logarithmic number of possible lengths for a given order of magnitude.
+    #[default]
+    Padme,
+    /// Pad the plaintext to the next power of two. This leaves fewer possible
+    /// lengths than Padmé, at the cost of up to 100% of overhead.
+    PowerOfTwo,
+}
+
+impl PaddingScheme {
+    const MIN_PADDED_LENGTH: usize = 32;
+
+    /// The length a plaintext of the given length gets padded to, including
+    /// the padding marker.
+    fn padded_length(&self, length: usize) -> usize {
+        let length = (length + 1).max(Self::MIN_PADDED_LENGTH);
+
+        match self {
+            PaddingScheme::Padme => {
+                // Keep only the top `floor(log2(E)) + 1` bits of the length,
+                // `E` being the exponent of the length, rounding up.
+                let exponent = usize::BITS - 1 - length.leading_zeros();
+                let exponent_bits = u32::BITS - exponent.leading_zeros();
+                let mask = (1usize << (exponent - exponent_bits)) - 1;
+
+                (length + mask) & !mask
+            }
+            PaddingScheme::PowerOfTwo => length.next_power_of_two(),
+        }
+    }
+
+    pub(crate) fn pad(&self, plaintext: &[u8]) -> Vec<u8> {
+        let padded_length = self.padded_length(plaintext.len());
+        let mut padded = Vec::with_capacity(padded_length);
+
+        padded.extend_from_slice(plaintext);
+        padded.push(PADDING_MARKER);
+        padded.resize(padded_length, 0);
+
+        padded
+    }
+}
+
+/// Remove the padding added by [`PaddingScheme::pad()`].
+pub(crate) fn unpad(mut padded: Vec<u8>) -> Result<Vec<u8>, PaddingError> {
+    let marker = padded.iter().rposition(|b| *b != 0).ok_or(PaddingError)?;
+
+    if padded[marker] == PADDING_MARKER {
+        padded.truncate(marker);
+        Ok(padded)
+    } else {
+        Err(PaddingError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{unpad, PaddingScheme};
+
+    #[test]
+    fn padded_length() {
+        let padme = PaddingScheme::Padme;
+        let power_of_two = PaddingScheme::PowerOfTwo;
+
+        assert_eq!(padme.padded_length(0), 32);
+        assert_eq!(padme.padded_length(31), 32);
+        assert_eq!(padme.padded_length(32), 36);
+        assert_eq!(padme.padded_length(99), 104);
+        assert_eq!(padme.padded_length(1000), 1024);
+        assert_eq!(padme.padded_length(1100), 1152);
+
+        assert_eq!(power_of_two.padded_length(0), 32);
+        assert_eq!(power_of_two.padded_length(32), 64);
+        assert_eq!(power_of_two.padded_length(1100), 2048);
+
+        for length in 0..5000 {
+            let padded_length = padme.padded_length(length);
+
+            assert!(padded_length > length);
+            assert!(length < 32 || padded_length - length - 1 <= (length + 1) * 12 / 100);
+        }
+    }
+
+    #[test]
+    fn padding_roundtrip() {
+        for scheme in [PaddingScheme::Padme, PaddingScheme::PowerOfTwo] {
+            for plaintext in [b"".as_ref(), b"\x80\x00", &[0u8; 100], "👍".as_bytes()] {
+                let padded = scheme.pad(plaintext);
+
+                assert_eq!(padded.len(), scheme.padded_length(plaintext.len()));
+                assert_eq!(unpad(padded).expect("The padding should be valid"), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_padding() {
+        unpad(vec![]).expect_err("An empty plaintext isn't padded");
+        unpad(vec![0; 32]).expect_err("The padding marker is missing");
+        unpad(b"plaintext\x00\x00".to_vec()).expect_err("The padding marker is missing");
+        unpad(b"plaintext\x80\x01".to_vec()).expect_err("The padding contains non-zero bytes");
+
+        assert_eq!(unpad(b"plaintext\x80".to_vec()).expect("The padding is valid"), b"plaintext");
+    }
+}
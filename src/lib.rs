@@ -66,7 +66,7 @@
 //!
 //! - [Olm](https://matrix-org.github.io/vodozemac/vodozemac/olm/index.html)
 //! - [Megolm](https://matrix-org.github.io/vodozemac/vodozemac/megolm/index.html)
-//! - [libolm pickle format](#legacy-pickles) (read-only)
+//! - [libolm pickle format](#legacy-pickles)
 //! - [Modern pickle format](#modern-pickles)
 //! - [SAS (Short Authentication Strings)](https://matrix-org.github.io/vodozemac/vodozemac/sas/index.html)
 //!
@@ -113,7 +113,8 @@
 //!
 //! The legacy pickle format is a simple binary format used by libolm.
 //! Implemented for interoperability with current clients which are using
-//! libolm. Only *unpickling* is supported.
+//! libolm. Both *unpickling* and *pickling* are supported, the latter makes it
+//! possible to migrate back to libolm.
 //!
 //! ## Modern pickles
 //!
@@ -212,6 +213,7 @@ mod cipher;
 mod types;
 mod utilities;
 
+pub mod canonical_json;
 pub mod hazmat;
 pub mod megolm;
 pub mod olm;
@@ -240,7 +242,7 @@ pub enum PickleError {
 }
 
 /// Error type describing the various ways libolm pickles can fail to be
-/// decoded.
+/// decoded or encoded.
 #[cfg(feature = "libolm-compat")]
 #[derive(Debug, thiserror::Error)]
 pub enum LibolmPickleError {
@@ -266,6 +268,18 @@ pub enum LibolmPickleError {
     /// The payload of the pickle could not be decoded.
     #[error(transparent)]
     Decode(#[from] matrix_pickle::DecodeError),
+    /// The object could not be encoded as a pickle.
+    #[error(transparent)]
+    Encode(#[from] matrix_pickle::EncodeError),
+    /// The session uses a configuration that libolm doesn't support, only
+    /// sessions using version 1 of the [`olm::SessionConfig`] or the
+    /// [`megolm::SessionConfig`] can be converted into a libolm pickle.
+    #[error("The session uses a configuration which libolm doesn't support")]
+    UnsupportedSessionConfig,
+    /// The pickle is a version 1 libolm account pickle. Such pickles didn't
+    /// store the Ed25519 key correctly, libolm refuses to load them as well.
+    #[error("The pickle is a legacy libolm account pickle which can't be restored")]
+    LegacyAccountPickle,
 }
 
 /// Error type describing the different ways message decoding can fail.
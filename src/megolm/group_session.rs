@@ -12,14 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 
 use super::{
     default_config, message::MegolmMessage, ratchet::Ratchet, session_config::Version,
-    session_keys::SessionKey, SessionConfig,
+    session_keys::SessionKey, SessionConfig, SessionConfigError,
 };
 use crate::{
-    cipher::Cipher,
+    cipher::{AeadCipher, Cipher},
     types::Ed25519Keypair,
     utilities::{pickle, unpickle},
     PickleError,
@@ -59,6 +60,18 @@ impl GroupSession {
         Self { signing_key, ratchet: Ratchet::new(), config }
     }
 
+    /// Construct a new group session, using the given random number generator
+    /// to create the ratchet state and signing key pair.
+    ///
+    /// This is only useful to create deterministic test vectors, use
+    /// [`GroupSession::new()`] otherwise.
+    pub fn new_with_rng(config: SessionConfig, rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        let signing_key = Ed25519Keypair::new_with_rng(rng);
+        let ratchet = Ratchet::new_with_rng(rng);
+
+        Self { signing_key, ratchet, config }
+    }
+
     /// Returns the globally unique session ID, in base64-encoded form.
     ///
     /// A session ID is the public part of the Ed25519 key pair associated with
@@ -85,20 +98,69 @@ impl GroupSession {
     /// The resulting ciphertext is MAC-ed, then signed with the group session's
     /// Ed25519 key pair and finally base64-encoded.
     pub fn encrypt(&mut self, plaintext: impl AsRef<[u8]>) -> MegolmMessage {
-        let cipher = Cipher::new_megolm(self.ratchet.as_bytes());
+        self.encrypt_helper(plaintext.as_ref(), &[])
+    }
+
+    /// Encrypt the `plaintext` with the group session, the MAC of the
+    /// resulting message additionally covers the given `associated_data`.
+    ///
+    /// The associated data isn't part of the message, receivers need to pass
+    /// the same associated data to [`InboundGroupSession::decrypt_with_ad()`]
+    /// to be able to decrypt the message. Only sessions using
+    /// [`SessionConfig::version_3()`] or a later version
+    /// support associated data.
+    ///
+    /// [`InboundGroupSession::decrypt_with_ad()`]: super::InboundGroupSession::decrypt_with_ad
+    pub fn encrypt_with_ad(
+        &mut self,
+        plaintext: impl AsRef<[u8]>,
+        associated_data: impl AsRef<[u8]>,
+    ) -> Result<MegolmMessage, SessionConfigError> {
+        if self.config.supports_associated_data() {
+            Ok(self.encrypt_helper(plaintext.as_ref(), associated_data.as_ref()))
+        } else {
+            Err(SessionConfigError::AssociatedDataUnsupported(self.config.version()))
+        }
+    }
+
+    fn encrypt_helper(&mut self, plaintext: &[u8], associated_data: &[u8]) -> MegolmMessage {
+        let message_index = self.message_index();
+        let key = self.ratchet.as_bytes();
 
         let message = match self.config.version {
             Version::V1 => MegolmMessage::encrypt_truncated_mac(
-                self.message_index(),
-                &cipher,
+                message_index,
+                &Cipher::new_megolm(key),
                 &self.signing_key,
-                plaintext.as_ref(),
+                plaintext,
             ),
             Version::V2 => MegolmMessage::encrypt_full_mac(
-                self.message_index(),
-                &cipher,
+                message_index,
+                &Cipher::new_megolm(key),
+                &self.signing_key,
+                plaintext,
+            ),
+            Version::V3 => MegolmMessage::encrypt_with_ad(
+                message_index,
+                &Cipher::new_megolm(key),
+                &self.signing_key,
+                plaintext,
+                associated_data,
+            ),
+            Version::V4 => MegolmMessage::encrypt_aead(
+                message_index,
+                &AeadCipher::new_megolm(key),
                 &self.signing_key,
-                plaintext.as_ref(),
+                plaintext,
+                associated_data,
+            ),
+            Version::V5 => MegolmMessage::encrypt_padded(
+                message_index,
+                &AeadCipher::new_megolm(key),
+                &self.signing_key,
+                plaintext,
+                associated_data,
+                self.config.padding_scheme().unwrap_or_default(),
             ),
         };
 
@@ -146,40 +208,28 @@ impl GroupSession {
         pickle: &str,
         pickle_key: &[u8],
     ) -> Result<Self, crate::LibolmPickleError> {
-        use matrix_pickle::Decode;
-        use zeroize::Zeroize;
-
-        use crate::{
-            megolm::libolm::LibolmRatchetPickle,
-            utilities::{unpickle_libolm, LibolmEd25519Keypair},
-        };
-
-        #[derive(Zeroize, Decode)]
-        #[zeroize(drop)]
-        struct Pickle {
-            version: u32,
-            ratchet: LibolmRatchetPickle,
-            ed25519_keypair: LibolmEd25519Keypair,
-        }
+        use self::libolm::{Pickle, PICKLE_VERSION};
+        use crate::utilities::unpickle_libolm;
 
-        impl TryFrom<Pickle> for GroupSession {
-            type Error = crate::LibolmPickleError;
-
-            fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
-                // Removing the borrow doesn't work and clippy complains about
-                // this on nightly.
-                #[allow(clippy::needless_borrow)]
-                let ratchet = (&pickle.ratchet).into();
-                let signing_key =
-                    Ed25519Keypair::from_expanded_key(&pickle.ed25519_keypair.private_key)?;
-
-                Ok(Self { ratchet, signing_key, config: SessionConfig::version_1() })
-            }
-        }
+        unpickle_libolm::<Pickle, _>(pickle, pickle_key, &[PICKLE_VERSION])
+    }
 
-        const PICKLE_VERSION: u32 = 1;
+    /// Pickle a [`GroupSession`] into the libolm legacy pickle format.
+    ///
+    /// The pickle can be restored using the
+    /// [`GroupSession::from_libolm_pickle`] method, or can be used in the
+    /// [`libolm`] C library.
+    ///
+    /// Only sessions using version 1 of the [`SessionConfig`] can be pickled,
+    /// since libolm doesn't support any other version.
+    ///
+    /// [`libolm`]: https://gitlab.matrix.org/matrix-org/olm/
+    #[cfg(feature = "libolm-compat")]
+    pub fn to_libolm_pickle(&self, pickle_key: &[u8]) -> Result<String, crate::LibolmPickleError> {
+        use self::libolm::Pickle;
+        use crate::utilities::pickle_libolm;
 
-        unpickle_libolm::<Pickle, _>(pickle, pickle_key, PICKLE_VERSION)
+        pickle_libolm::<Pickle>(self.try_into()?, pickle_key)
     }
 }
 
@@ -215,3 +265,60 @@ impl From<GroupSessionPickle> for GroupSession {
         Self { ratchet: pickle.ratchet, signing_key: pickle.signing_key, config: pickle.config }
     }
 }
+
+#[cfg(feature = "libolm-compat")]
+mod libolm {
+    use matrix_pickle::{Decode, Encode};
+    use zeroize::Zeroize;
+
+    use super::GroupSession;
+    use crate::{
+        megolm::{libolm::LibolmRatchetPickle, session_config::Version, SessionConfig},
+        utilities::LibolmEd25519Keypair,
+        Ed25519Keypair, LibolmPickleError,
+    };
+
+    pub(super) const PICKLE_VERSION: u32 = 1;
+
+    #[derive(Zeroize, Encode, Decode)]
+    #[zeroize(drop)]
+    pub(super) struct Pickle {
+        version: u32,
+        ratchet: LibolmRatchetPickle,
+        ed25519_keypair: LibolmEd25519Keypair,
+    }
+
+    impl TryFrom<&GroupSession> for Pickle {
+        type Error = LibolmPickleError;
+
+        fn try_from(session: &GroupSession) -> Result<Self, Self::Error> {
+            if session.config.version != Version::V1 {
+                return Err(LibolmPickleError::UnsupportedSessionConfig);
+            }
+
+            Ok(Self {
+                version: PICKLE_VERSION,
+                ratchet: (&session.ratchet).into(),
+                ed25519_keypair: LibolmEd25519Keypair {
+                    public_key: session.signing_key.public_key().as_bytes().to_owned(),
+                    private_key: session.signing_key.expanded_secret_key(),
+                },
+            })
+        }
+    }
+
+    impl TryFrom<Pickle> for GroupSession {
+        type Error = LibolmPickleError;
+
+        fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
+            // Removing the borrow doesn't work and clippy complains about
+            // this on nightly.
+            #[allow(clippy::needless_borrow)]
+            let ratchet = (&pickle.ratchet).into();
+            let signing_key =
+                Ed25519Keypair::from_expanded_key(&pickle.ed25519_keypair.private_key)?;
+
+            Ok(Self { ratchet, signing_key, config: SessionConfig::version_1() })
+        }
+    }
+}
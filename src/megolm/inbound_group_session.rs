@@ -19,7 +19,6 @@ use hmac::digest::MacError;
 use serde::{Deserialize, Serialize};
 use subtle::ConstantTimeEq;
 use thiserror::Error;
-use zeroize::Zeroize;
 
 use super::{
     default_config,
@@ -30,7 +29,7 @@ use super::{
     GroupSession, SessionConfig,
 };
 use crate::{
-    cipher::{Cipher, Mac, MessageMac},
+    cipher::{unpad, AeadCipher, Cipher, Mac, MessageMac, PaddingError},
     types::{Ed25519PublicKey, SignatureError},
     utilities::{base64_encode, pickle, unpickle},
     PickleError,
@@ -72,6 +71,11 @@ pub enum DecryptionError {
     #[error("Failed decrypting Megolm message, invalid padding")]
     InvalidPadding(#[from] UnpadError),
 
+    /// The plaintext of a message using a padded Megolm version isn't padded
+    /// correctly.
+    #[error("Failed decrypting Megolm message, invalid plaintext padding: {0}")]
+    InvalidPlaintextPadding(#[from] PaddingError),
+
     /// The session is missing the correct message key to decrypt the message,
     /// The Session has been ratcheted forwards and the message key isn't
     /// available anymore.
@@ -80,6 +84,24 @@ pub enum DecryptionError {
         first known index {0}, index of the message {1}"
     )]
     UnknownMessageIndex(u32, u32),
+
+    /// The MAC or the authentication tag of a message covering associated data
+    /// was invalid. The signature of the message was valid, so the associated
+    /// data most likely differs from the one the message was encrypted with.
+    #[error("Failed decrypting Megolm message, the associated data doesn't match")]
+    AssociatedDataMismatch,
+
+    /// Associated data was given, but the session uses a Megolm version whose
+    /// messages don't authenticate associated data.
+    #[error(
+        "Failed decrypting Megolm message, Megolm version {0} doesn't support associated data"
+    )]
+    AssociatedDataUnsupported(u8),
+
+    /// The message uses a message version that doesn't match the Megolm
+    /// version of the session.
+    #[error("Failed decrypting Megolm message, the message version {0} doesn't match the session")]
+    MessageVersionMismatch(u8),
 }
 
 #[derive(Deserialize)]
@@ -298,37 +320,137 @@ impl InboundGroupSession {
         }
     }
 
-    fn verify_mac(&self, cipher: &Cipher, message: &MegolmMessage) -> Result<(), DecryptionError> {
-        match self.config.version {
+    fn decrypt_with_ratchet(
+        config: SessionConfig,
+        ratchet: &Ratchet,
+        message: &MegolmMessage,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DecryptionError> {
+        match config.version {
             Version::V1 => {
+                let cipher = Cipher::new_megolm(ratchet.as_bytes());
+
                 if let MessageMac::Truncated(m) = &message.mac {
-                    Ok(cipher.verify_truncated_mac(&message.to_mac_bytes(), m)?)
+                    cipher.verify_truncated_mac(&message.to_mac_bytes(), m)?;
+                    Ok(cipher.decrypt(&message.ciphertext)?)
                 } else {
-                    Err(DecryptionError::InvalidMACLength(Mac::TRUNCATED_LEN, Mac::LENGTH))
+                    Err(DecryptionError::InvalidMACLength(
+                        Mac::TRUNCATED_LEN,
+                        message.mac.as_bytes().len(),
+                    ))
                 }
             }
             Version::V2 => {
+                let cipher = Cipher::new_megolm(ratchet.as_bytes());
+
+                if let MessageMac::Full(m) = &message.mac {
+                    cipher.verify_mac(&message.to_mac_bytes(), m)?;
+                    Ok(cipher.decrypt(&message.ciphertext)?)
+                } else {
+                    Err(DecryptionError::InvalidMACLength(
+                        Mac::LENGTH,
+                        message.mac.as_bytes().len(),
+                    ))
+                }
+            }
+            Version::V3 => {
+                let cipher = Cipher::new_megolm(ratchet.as_bytes());
+
                 if let MessageMac::Full(m) = &message.mac {
-                    Ok(cipher.verify_mac(&message.to_mac_bytes(), m)?)
+                    cipher.verify_mac(&message.to_mac_bytes_with_ad(associated_data), m).map_err(
+                        |e| {
+                            if message.mac_covers_associated_data() {
+                                DecryptionError::AssociatedDataMismatch
+                            } else {
+                                DecryptionError::InvalidMAC(e)
+                            }
+                        },
+                    )?;
+                    Ok(cipher.decrypt(&message.ciphertext)?)
+                } else {
+                    Err(DecryptionError::InvalidMACLength(
+                        Mac::LENGTH,
+                        message.mac.as_bytes().len(),
+                    ))
+                }
+            }
+            Version::V4 | Version::V5 => {
+                let cipher = AeadCipher::new_megolm(ratchet.as_bytes());
+
+                // The version byte is authenticated as well, but the tag would
+                // verify just fine if the message was padded while the session
+                // doesn't expect it to be or vice versa.
+                if message.is_padded() != (config.version == Version::V5) {
+                    Err(DecryptionError::MessageVersionMismatch(message.version))
+                } else if let MessageMac::Tag(t) = &message.mac {
+                    let plaintext = cipher
+                        .decrypt(
+                            &message.ciphertext,
+                            t,
+                            &message.to_aead_associated_data(associated_data),
+                        )
+                        .map_err(|_| DecryptionError::AssociatedDataMismatch)?;
+
+                    if message.is_padded() {
+                        Ok(unpad(plaintext)?)
+                    } else {
+                        Ok(plaintext)
+                    }
                 } else {
-                    Err(DecryptionError::InvalidMACLength(Mac::LENGTH, Mac::TRUNCATED_LEN))
+                    Err(DecryptionError::InvalidMACLength(
+                        AeadCipher::TAG_LENGTH,
+                        message.mac.as_bytes().len(),
+                    ))
                 }
             }
         }
     }
 
+    /// Decrypt the given [`MegolmMessage`].
+    ///
+    /// Unlike Olm decryption, this never modifies the persisted state of the
+    /// session. Only the initial ratchet is part of the pickle, the session can
+    /// always be asked to decrypt a message again. Detecting replayed messages
+    /// is up to the caller, using the message index of the
+    /// [`DecryptedMessage`].
     pub fn decrypt(
         &mut self,
         message: &MegolmMessage,
     ) -> Result<DecryptedMessage, DecryptionError> {
-        self.signing_key.verify(&message.to_signature_bytes(), &message.signature)?;
+        self.decrypt_helper(message, &[])
+    }
 
-        if let Some(ratchet) = self.find_ratchet(message.message_index) {
-            let cipher = Cipher::new_megolm(ratchet.as_bytes());
+    /// Decrypt the given [`MegolmMessage`], whose MAC covers the given
+    /// `associated_data`.
+    ///
+    /// The associated data needs to be the same as the one that was passed to
+    /// [`GroupSession::encrypt_with_ad()`], otherwise decryption fails with
+    /// [`DecryptionError::AssociatedDataMismatch`]. Only sessions using
+    /// [`SessionConfig::version_3()`] or a later version
+    /// support associated data.
+    pub fn decrypt_with_ad(
+        &mut self,
+        message: &MegolmMessage,
+        associated_data: impl AsRef<[u8]>,
+    ) -> Result<DecryptedMessage, DecryptionError> {
+        if self.config.supports_associated_data() {
+            self.decrypt_helper(message, associated_data.as_ref())
+        } else {
+            Err(DecryptionError::AssociatedDataUnsupported(self.config.version()))
+        }
+    }
 
-            self.verify_mac(&cipher, message)?;
+    fn decrypt_helper(
+        &mut self,
+        message: &MegolmMessage,
+        associated_data: &[u8],
+    ) -> Result<DecryptedMessage, DecryptionError> {
+        self.signing_key.verify(&message.to_signature_bytes(), &message.signature)?;
 
-            let plaintext = cipher.decrypt(&message.ciphertext)?;
+        let config = self.config;
+
+        if let Some(ratchet) = self.find_ratchet(message.message_index) {
+            let plaintext = Self::decrypt_with_ratchet(config, ratchet, message, associated_data)?;
 
             Ok(DecryptedMessage { plaintext, message_index: message.message_index })
         } else {
@@ -366,52 +488,42 @@ impl InboundGroupSession {
         Self::from(pickle)
     }
 
+    /// Create an [`InboundGroupSession`] object by unpickling a session pickle
+    /// in libolm legacy pickle format.
+    ///
+    /// Such pickles are encrypted and need to first be decrypted using
+    /// `pickle_key`.
+    ///
+    /// Version 1 pickles didn't record if the signing key was verified, such
+    /// sessions are restored with a verified signing key, just like libolm
+    /// does.
     #[cfg(feature = "libolm-compat")]
     pub fn from_libolm_pickle(
         pickle: &str,
         pickle_key: &[u8],
     ) -> Result<Self, crate::LibolmPickleError> {
-        use matrix_pickle::Decode;
-
-        use super::libolm::LibolmRatchetPickle;
+        use self::libolm::{Pickle, PICKLE_VERSIONS};
         use crate::utilities::unpickle_libolm;
 
-        #[derive(Zeroize, Decode)]
-        #[zeroize(drop)]
-        struct Pickle {
-            version: u32,
-            initial_ratchet: LibolmRatchetPickle,
-            latest_ratchet: LibolmRatchetPickle,
-            signing_key: [u8; 32],
-            signing_key_verified: bool,
-        }
-
-        impl TryFrom<Pickle> for InboundGroupSession {
-            type Error = crate::LibolmPickleError;
-
-            fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
-                // Removing the borrow doesn't work and clippy complains about
-                // this on nightly.
-                #[allow(clippy::needless_borrow)]
-                let initial_ratchet = (&pickle.initial_ratchet).into();
-                #[allow(clippy::needless_borrow)]
-                let latest_ratchet = (&pickle.latest_ratchet).into();
-                let signing_key = Ed25519PublicKey::from_slice(&pickle.signing_key)?;
-                let signing_key_verified = pickle.signing_key_verified;
-
-                Ok(Self {
-                    initial_ratchet,
-                    latest_ratchet,
-                    signing_key,
-                    signing_key_verified,
-                    config: SessionConfig::version_1(),
-                })
-            }
-        }
+        unpickle_libolm::<Pickle, _>(pickle, pickle_key, PICKLE_VERSIONS)
+    }
 
-        const PICKLE_VERSION: u32 = 2;
+    /// Pickle an [`InboundGroupSession`] into the libolm legacy pickle format.
+    ///
+    /// The pickle can be restored using the
+    /// [`InboundGroupSession::from_libolm_pickle`] method, or can be used in
+    /// the [`libolm`] C library.
+    ///
+    /// Only sessions using version 1 of the [`SessionConfig`] can be pickled,
+    /// since libolm doesn't support any other version.
+    ///
+    /// [`libolm`]: https://gitlab.matrix.org/matrix-org/olm/
+    #[cfg(feature = "libolm-compat")]
+    pub fn to_libolm_pickle(&self, pickle_key: &[u8]) -> Result<String, crate::LibolmPickleError> {
+        use self::libolm::Pickle;
+        use crate::utilities::pickle_libolm;
 
-        unpickle_libolm::<Pickle, _>(pickle, pickle_key, PICKLE_VERSION)
+        pickle_libolm::<Pickle>(self.try_into()?, pickle_key)
     }
 }
 
@@ -469,6 +581,88 @@ impl From<&GroupSession> for InboundGroupSession {
     }
 }
 
+#[cfg(feature = "libolm-compat")]
+mod libolm {
+    use matrix_pickle::{Decode, DecodeError, Encode};
+    use zeroize::Zeroize;
+
+    use super::InboundGroupSession;
+    use crate::{
+        megolm::{libolm::LibolmRatchetPickle, session_config::Version, SessionConfig},
+        Ed25519PublicKey, LibolmPickleError,
+    };
+
+    pub(super) const PICKLE_VERSION: u32 = 2;
+
+    pub(super) const PICKLE_VERSIONS: &[u32] = &[PICKLE_VERSION, 1];
+
+    #[derive(Zeroize, Encode)]
+    #[zeroize(drop)]
+    pub(super) struct Pickle {
+        version: u32,
+        initial_ratchet: LibolmRatchetPickle,
+        latest_ratchet: LibolmRatchetPickle,
+        signing_key: [u8; 32],
+        signing_key_verified: bool,
+    }
+
+    impl Decode for Pickle {
+        fn decode(reader: &mut impl std::io::Read) -> Result<Self, DecodeError> {
+            let version = u32::decode(reader)?;
+            let initial_ratchet = LibolmRatchetPickle::decode(reader)?;
+            let latest_ratchet = LibolmRatchetPickle::decode(reader)?;
+            let signing_key = <[u8; 32]>::decode(reader)?;
+
+            // Version 1 pickles didn't store the verification state of the
+            // signing key, they are considered to be verified.
+            let signing_key_verified = if version == 1 { true } else { bool::decode(reader)? };
+
+            Ok(Self { version, initial_ratchet, latest_ratchet, signing_key, signing_key_verified })
+        }
+    }
+
+    impl TryFrom<&InboundGroupSession> for Pickle {
+        type Error = LibolmPickleError;
+
+        fn try_from(session: &InboundGroupSession) -> Result<Self, Self::Error> {
+            if session.config.version != Version::V1 {
+                return Err(LibolmPickleError::UnsupportedSessionConfig);
+            }
+
+            Ok(Self {
+                version: PICKLE_VERSION,
+                initial_ratchet: (&session.initial_ratchet).into(),
+                latest_ratchet: (&session.latest_ratchet).into(),
+                signing_key: session.signing_key.as_bytes().to_owned(),
+                signing_key_verified: session.signing_key_verified,
+            })
+        }
+    }
+
+    impl TryFrom<Pickle> for InboundGroupSession {
+        type Error = LibolmPickleError;
+
+        fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
+            // Removing the borrow doesn't work and clippy complains about
+            // this on nightly.
+            #[allow(clippy::needless_borrow)]
+            let initial_ratchet = (&pickle.initial_ratchet).into();
+            #[allow(clippy::needless_borrow)]
+            let latest_ratchet = (&pickle.latest_ratchet).into();
+            let signing_key = Ed25519PublicKey::from_slice(&pickle.signing_key)?;
+            let signing_key_verified = pickle.signing_key_verified;
+
+            Ok(Self {
+                initial_ratchet,
+                latest_ratchet,
+                signing_key,
+                signing_key_verified,
+                config: SessionConfig::version_1(),
+            })
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::InboundGroupSession;
@@ -602,4 +796,38 @@ mod test {
             session.get_cipher_at(1000).unwrap().encrypt(b"")
         );
     }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_legacy_pickle_version() -> anyhow::Result<()> {
+        use matrix_pickle::Encode;
+
+        use super::libolm::Pickle;
+        use crate::utilities::encrypt_libolm_pickle;
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let mut group_session = GroupSession::new(SessionConfig::version_1());
+        let session = InboundGroupSession::import(
+            &InboundGroupSession::from(&group_session).export_at_first_known_index(),
+            SessionConfig::version_1(),
+        );
+        assert!(!session.signing_key_verified);
+
+        // Version 1 pickles are missing the signing key verification flag.
+        let mut encoded = Pickle::try_from(&session)?.encode_to_vec()?;
+        encoded[..4].copy_from_slice(&1u32.to_be_bytes());
+        encoded.pop();
+
+        let pickle = encrypt_libolm_pickle(&encoded, key);
+        let mut unpickled = InboundGroupSession::from_libolm_pickle(&pickle, key)?;
+
+        assert_eq!(session.session_id(), unpickled.session_id());
+        assert!(unpickled.signing_key_verified);
+
+        let plaintext = b"It's a secret to everybody";
+        let message = group_session.encrypt(plaintext);
+        assert_eq!(unpickled.decrypt(&message)?.plaintext, plaintext);
+
+        Ok(())
+    }
 }
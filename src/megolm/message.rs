@@ -15,11 +15,12 @@
 
 use prost::Message;
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 
 use crate::{
-    cipher::{Cipher, Mac, MessageMac},
+    cipher::{AeadCipher, Cipher, Mac, MessageMac, PaddingScheme},
     types::{Ed25519Keypair, Ed25519Signature},
-    utilities::{base64_decode, base64_encode, extract_mac, VarInt},
+    utilities::{base64_decode, base64_encode, extract_mac, extract_tag, VarInt},
     DecodeError,
 };
 #[cfg(feature = "low-level-api")]
@@ -27,6 +28,9 @@ use crate::{Ed25519PublicKey, SignatureError};
 
 const MAC_TRUNCATED_VERSION: u8 = 3;
 const VERSION: u8 = 4;
+const ASSOCIATED_DATA_VERSION: u8 = 5;
+const AEAD_VERSION: u8 = 6;
+const PADDED_VERSION: u8 = 7;
 
 /// An encrypted Megolm message.
 ///
@@ -46,6 +50,7 @@ pub struct MegolmMessage {
 impl MegolmMessage {
     const MESSAGE_TRUNCATED_SUFFIX_LENGTH: usize = Mac::TRUNCATED_LEN + Ed25519Signature::LENGTH;
     const MESSAGE_SUFFIX_LENGTH: usize = Mac::LENGTH + Ed25519Signature::LENGTH;
+    const MESSAGE_AEAD_SUFFIX_LENGTH: usize = AeadCipher::TAG_LENGTH + Ed25519Signature::LENGTH;
 
     /// The actual ciphertext of the message.
     pub fn ciphertext(&self) -> &[u8] {
@@ -57,6 +62,27 @@ impl MegolmMessage {
         self.message_index
     }
 
+    /// Does the MAC of this Megolm message cover associated data, i.e. does the
+    /// message need to be decrypted using
+    /// [`InboundGroupSession::decrypt_with_ad()`].
+    ///
+    /// [`InboundGroupSession::decrypt_with_ad()`]: crate::megolm::InboundGroupSession::decrypt_with_ad
+    pub fn mac_covers_associated_data(&self) -> bool {
+        matches!(self.version, ASSOCIATED_DATA_VERSION | AEAD_VERSION | PADDED_VERSION)
+    }
+
+    /// Has this Megolm message been encrypted using ChaCha20-Poly1305, i.e.
+    /// does it carry an authentication tag instead of a MAC.
+    pub fn uses_aead(&self) -> bool {
+        matches!(self.version, AEAD_VERSION | PADDED_VERSION)
+    }
+
+    /// Has the plaintext of this Megolm message been padded before it was
+    /// encrypted, hiding its exact length.
+    pub fn is_padded(&self) -> bool {
+        self.version == PADDED_VERSION
+    }
+
     /// Get the megolm message's mac.
     pub fn mac(&self) -> &[u8] {
         self.mac.as_bytes()
@@ -88,6 +114,9 @@ impl MegolmMessage {
     /// 0   1                                    N          N+8                N+72   bytes
     /// ```
     ///
+    /// Messages encrypted using ChaCha20-Poly1305, padded or not, carry a 16
+    /// byte authentication tag in place of the MAC.
+    ///
     /// The payload uses a format based on the Protocol Buffers encoding. It
     /// consists of the following key-value pairs:
     ///
@@ -148,6 +177,9 @@ impl MegolmMessage {
         match self.mac {
             MessageMac::Truncated(_) => self.mac = mac.truncate().into(),
             MessageMac::Full(_) => self.mac = mac.into(),
+            MessageMac::Tag(_) => {
+                unreachable!("Messages using an authentication tag don't get a MAC set")
+            }
         }
     }
 
@@ -204,6 +236,95 @@ impl MegolmMessage {
         Self::encrypt_helper(cipher, signing_key, message)
     }
 
+    pub(super) fn encrypt_with_ad(
+        message_index: u32,
+        cipher: &Cipher,
+        signing_key: &Ed25519Keypair,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Self {
+        let ciphertext = cipher.encrypt(plaintext);
+
+        let mut message = Self {
+            version: ASSOCIATED_DATA_VERSION,
+            ciphertext,
+            message_index,
+            mac: Mac([0u8; Mac::LENGTH]).into(),
+            signature: Ed25519Signature::from_slice(&[0; Ed25519Signature::LENGTH])
+                .expect("Can't create an empty signature"),
+        };
+
+        let mac = cipher.mac(&message.to_mac_bytes_with_ad(associated_data));
+        message.set_mac(mac);
+
+        Self::sign(signing_key, message)
+    }
+
+    pub(super) fn encrypt_aead(
+        message_index: u32,
+        cipher: &AeadCipher,
+        signing_key: &Ed25519Keypair,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Self {
+        Self::encrypt_aead_helper(
+            AEAD_VERSION,
+            message_index,
+            cipher,
+            signing_key,
+            plaintext,
+            associated_data,
+        )
+    }
+
+    pub(super) fn encrypt_padded(
+        message_index: u32,
+        cipher: &AeadCipher,
+        signing_key: &Ed25519Keypair,
+        plaintext: &[u8],
+        associated_data: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Self {
+        let mut padded = padding_scheme.pad(plaintext);
+
+        let message = Self::encrypt_aead_helper(
+            PADDED_VERSION,
+            message_index,
+            cipher,
+            signing_key,
+            &padded,
+            associated_data,
+        );
+        padded.zeroize();
+
+        message
+    }
+
+    fn encrypt_aead_helper(
+        version: u8,
+        message_index: u32,
+        cipher: &AeadCipher,
+        signing_key: &Ed25519Keypair,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Self {
+        let mut message = Self {
+            version,
+            ciphertext: Vec::new(),
+            message_index,
+            mac: [0u8; AeadCipher::TAG_LENGTH].into(),
+            signature: Ed25519Signature::from_slice(&[0; Ed25519Signature::LENGTH])
+                .expect("Can't create an empty signature"),
+        };
+
+        let (ciphertext, tag) =
+            cipher.encrypt(plaintext, &message.to_aead_associated_data(associated_data));
+        message.ciphertext = ciphertext;
+        message.mac = tag.into();
+
+        Self::sign(signing_key, message)
+    }
+
     fn encrypt_helper(
         cipher: &Cipher,
         signing_key: &Ed25519Keypair,
@@ -212,6 +333,10 @@ impl MegolmMessage {
         let mac = cipher.mac(&message.to_mac_bytes());
         message.set_mac(mac);
 
+        Self::sign(signing_key, message)
+    }
+
+    fn sign(signing_key: &Ed25519Keypair, mut message: MegolmMessage) -> Self {
         let signature = signing_key.sign(&message.to_signature_bytes());
         message.signature = signature;
 
@@ -222,6 +347,29 @@ impl MegolmMessage {
         self.encode_message()
     }
 
+    /// The bytes the MAC of the message gets calculated over, followed by the
+    /// length prefixed associated data.
+    pub(super) fn to_mac_bytes_with_ad(&self, associated_data: &[u8]) -> Vec<u8> {
+        let mut bytes = self.encode_message();
+        bytes.extend(associated_data.len().to_var_int());
+        bytes.extend(associated_data);
+
+        bytes
+    }
+
+    /// The associated data the AEAD authenticates, the encoded message without
+    /// the ciphertext, followed by the length prefixed associated data.
+    pub(super) fn to_aead_associated_data(&self, associated_data: &[u8]) -> Vec<u8> {
+        let mut bytes =
+            ProtobufMegolmMessage { message_index: self.message_index, ciphertext: Vec::new() }
+                .encode_header(self.version);
+
+        bytes.extend(associated_data.len().to_var_int());
+        bytes.extend(associated_data);
+
+        bytes
+    }
+
     pub(super) fn to_signature_bytes(&self) -> Vec<u8> {
         let mut message = self.encode_message();
         message.extend(self.mac.as_bytes());
@@ -272,8 +420,9 @@ impl TryFrom<&[u8]> for MegolmMessage {
         let version = *message.first().ok_or(DecodeError::MissingVersion)?;
 
         let suffix_length = match version {
-            VERSION => Self::MESSAGE_SUFFIX_LENGTH,
+            VERSION | ASSOCIATED_DATA_VERSION => Self::MESSAGE_SUFFIX_LENGTH,
             MAC_TRUNCATED_VERSION => Self::MESSAGE_TRUNCATED_SUFFIX_LENGTH,
+            AEAD_VERSION | PADDED_VERSION => Self::MESSAGE_AEAD_SUFFIX_LENGTH,
             _ => return Err(DecodeError::InvalidVersion(VERSION, version)),
         };
 
@@ -291,7 +440,11 @@ impl TryFrom<&[u8]> for MegolmMessage {
             let signature = Ed25519Signature::from_slice(signature_slice)?;
 
             let mac_slice = &message[message.len() - suffix_length..];
-            let mac = extract_mac(mac_slice, version == MAC_TRUNCATED_VERSION);
+            let mac = if matches!(version, AEAD_VERSION | PADDED_VERSION) {
+                extract_tag(mac_slice)
+            } else {
+                extract_mac(mac_slice, version == MAC_TRUNCATED_VERSION)
+            };
 
             Ok(MegolmMessage {
                 version,
@@ -317,19 +470,23 @@ impl ProtobufMegolmMessage {
     const CIPHER_TAG: &'static [u8; 1] = b"\x12";
 
     fn encode_manual(&self, version: u8) -> Vec<u8> {
-        // Prost optimizes away the message index if it's 0, libolm can't decode
-        // this, so encode our messages the pedestrian way instead.
-        let index = self.message_index.to_var_int();
         let ciphertext_len = self.ciphertext.len().to_var_int();
 
         [
-            [version].as_ref(),
-            Self::INDEX_TAG.as_ref(),
-            &index,
+            self.encode_header(version).as_ref(),
             Self::CIPHER_TAG.as_ref(),
             &ciphertext_len,
             &self.ciphertext,
         ]
         .concat()
     }
+
+    /// Encode the message without the ciphertext.
+    fn encode_header(&self, version: u8) -> Vec<u8> {
+        // Prost optimizes away the message index if it's 0, libolm can't decode
+        // this, so encode our messages the pedestrian way instead.
+        let index = self.message_index.to_var_int();
+
+        [[version].as_ref(), Self::INDEX_TAG.as_ref(), &index].concat()
+    }
 }
@@ -27,21 +27,23 @@ pub use inbound_group_session::{
     SessionOrdering,
 };
 pub use message::MegolmMessage;
-pub use session_config::SessionConfig;
+pub use session_config::{SessionConfig, SessionConfigError};
 pub use session_keys::{ExportedSessionKey, SessionKey, SessionKeyDecodeError};
 
+pub use crate::cipher::{PaddingError, PaddingScheme};
+
 fn default_config() -> SessionConfig {
     SessionConfig::version_1()
 }
 
 #[cfg(feature = "libolm-compat")]
 mod libolm {
-    use matrix_pickle::Decode;
+    use matrix_pickle::{Decode, Encode};
     use zeroize::Zeroize;
 
     use super::ratchet::Ratchet;
 
-    #[derive(Zeroize, Decode)]
+    #[derive(Zeroize, Encode, Decode)]
     #[zeroize(drop)]
     pub(crate) struct LibolmRatchetPickle {
         #[secret]
@@ -54,6 +56,12 @@ mod libolm {
             Ratchet::from_bytes(pickle.ratchet.clone(), pickle.index)
         }
     }
+
+    impl From<&Ratchet> for LibolmRatchetPickle {
+        fn from(ratchet: &Ratchet) -> Self {
+            Self { ratchet: Box::new(*ratchet.as_bytes()), index: ratchet.index() }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -72,6 +80,27 @@ mod test {
 
     const PICKLE_KEY: [u8; 32] = [0u8; 32];
 
+    #[test]
+    fn deterministic_session_creation() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut session =
+            GroupSession::new_with_rng(SessionConfig::version_1(), &mut StdRng::seed_from_u64(42));
+        let mut other_session =
+            GroupSession::new_with_rng(SessionConfig::version_1(), &mut StdRng::seed_from_u64(42));
+
+        assert_eq!(session.session_id(), other_session.session_id());
+        assert_eq!(session.session_key().to_base64(), other_session.session_key().to_base64());
+        assert_eq!(
+            session.encrypt("It's a secret to everybody").to_base64(),
+            other_session.encrypt("It's a secret to everybody").to_base64()
+        );
+
+        let different_session =
+            GroupSession::new_with_rng(SessionConfig::version_1(), &mut StdRng::seed_from_u64(43));
+        assert_ne!(session.session_id(), different_session.session_id());
+    }
+
     #[test]
     fn encrypting() -> Result<()> {
         let mut session = GroupSession::new(SessionConfig::version_1());
@@ -201,6 +230,150 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn associated_data() -> Result<()> {
+        use super::{DecryptionError, SessionConfigError};
+
+        let mut session = GroupSession::new(SessionConfig::version_3());
+        let mut inbound =
+            InboundGroupSession::new(&session.session_key(), SessionConfig::version_3());
+
+        let message = session.encrypt_with_ad("It's a secret to everybody", "room")?;
+        assert!(message.mac_covers_associated_data());
+
+        let decoded = MegolmMessage::from_base64(&message.to_base64())?;
+        assert_eq!(decoded, message);
+
+        assert!(matches!(
+            inbound.decrypt_with_ad(&message, "other room"),
+            Err(DecryptionError::AssociatedDataMismatch)
+        ));
+        assert!(matches!(inbound.decrypt(&message), Err(DecryptionError::AssociatedDataMismatch)));
+
+        let decrypted = inbound.decrypt_with_ad(&message, "room")?;
+        assert_eq!(decrypted.plaintext, b"It's a secret to everybody");
+
+        // Encrypting without associated data is the same as using empty
+        // associated data.
+        let message = session.encrypt("No associated data");
+        assert_eq!(inbound.decrypt_with_ad(&message, "")?.plaintext, b"No associated data");
+
+        let mut session = GroupSession::new(SessionConfig::version_2());
+        let mut inbound =
+            InboundGroupSession::new(&session.session_key(), SessionConfig::version_2());
+
+        assert!(matches!(
+            session.encrypt_with_ad("It's a secret to everybody", "room"),
+            Err(SessionConfigError::AssociatedDataUnsupported(2))
+        ));
+        let message = session.encrypt("It's a secret to everybody");
+        assert!(matches!(
+            inbound.decrypt_with_ad(&message, "room"),
+            Err(DecryptionError::AssociatedDataUnsupported(2))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn aead_session() -> Result<()> {
+        use super::DecryptionError;
+
+        // The sessions, including their version, survive a pickle roundtrip.
+        let session = GroupSession::new(SessionConfig::version_4());
+        let mut session = GroupSession::from_pickle(GroupSessionPickle::from_encrypted(
+            &session.pickle().encrypt(&PICKLE_KEY),
+            &PICKLE_KEY,
+        )?);
+        assert_eq!(session.session_config(), SessionConfig::version_4());
+
+        let inbound = InboundGroupSession::new(&session.session_key(), SessionConfig::version_4());
+        let mut inbound =
+            InboundGroupSession::from_pickle(InboundGroupSessionPickle::from_encrypted(
+                &inbound.pickle().encrypt(&PICKLE_KEY),
+                &PICKLE_KEY,
+            )?);
+
+        let plaintext = "It's a secret to everybody";
+        let message = session.encrypt(plaintext);
+        assert!(message.uses_aead());
+        assert_eq!(message.ciphertext().len(), plaintext.len());
+
+        let decoded = MegolmMessage::from_base64(&message.to_base64())?;
+        assert_eq!(decoded, message);
+        assert_eq!(inbound.decrypt(&decoded)?.plaintext, plaintext.as_bytes());
+
+        let message = session.encrypt_with_ad(plaintext, "room")?;
+        assert!(matches!(
+            inbound.decrypt_with_ad(&message, "other room"),
+            Err(DecryptionError::AssociatedDataMismatch)
+        ));
+        assert_eq!(inbound.decrypt_with_ad(&message, "room")?.plaintext, plaintext.as_bytes());
+
+        // A session using a different version can't decrypt the message.
+        let mut inbound = InboundGroupSession::new(&session.session_key(), Default::default());
+        assert!(matches!(
+            inbound.decrypt(&session.encrypt(plaintext)),
+            Err(DecryptionError::InvalidMACLength(32, 16))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn padded_session() -> Result<()> {
+        use super::{DecryptionError, PaddingScheme};
+
+        // The sessions, including their version and padding scheme, survive a
+        // pickle roundtrip.
+        let session = GroupSession::new(SessionConfig::version_5(PaddingScheme::PowerOfTwo));
+        let mut session = GroupSession::from_pickle(GroupSessionPickle::from_encrypted(
+            &session.pickle().encrypt(&PICKLE_KEY),
+            &PICKLE_KEY,
+        )?);
+        assert_eq!(session.session_config(), SessionConfig::version_5(PaddingScheme::PowerOfTwo));
+
+        // The receiving side doesn't need to know the padding scheme.
+        let inbound = InboundGroupSession::new(
+            &session.session_key(),
+            SessionConfig::version_5(PaddingScheme::Padme),
+        );
+        let mut inbound =
+            InboundGroupSession::from_pickle(InboundGroupSessionPickle::from_encrypted(
+                &inbound.pickle().encrypt(&PICKLE_KEY),
+                &PICKLE_KEY,
+            )?);
+
+        let plaintext = "It's a secret to everybody";
+        let message = session.encrypt(plaintext);
+        assert!(message.uses_aead());
+        assert!(message.is_padded());
+        assert_eq!(message.ciphertext().len(), 32);
+
+        let decoded = MegolmMessage::from_base64(&message.to_base64())?;
+        assert_eq!(decoded, message);
+        assert_eq!(inbound.decrypt(&decoded)?.plaintext, plaintext.as_bytes());
+
+        let plaintext = "A".repeat(100);
+        let message = session.encrypt_with_ad(&plaintext, "room")?;
+        assert_eq!(message.ciphertext().len(), 128);
+        assert!(matches!(
+            inbound.decrypt_with_ad(&message, "other room"),
+            Err(DecryptionError::AssociatedDataMismatch)
+        ));
+        assert_eq!(inbound.decrypt_with_ad(&message, "room")?.plaintext, plaintext.as_bytes());
+
+        // Padded messages aren't accepted by sessions expecting unpadded ones.
+        let mut unpadded =
+            InboundGroupSession::new(&session.session_key(), SessionConfig::version_4());
+        assert!(matches!(
+            unpadded.decrypt(&session.encrypt(&plaintext)),
+            Err(DecryptionError::MessageVersionMismatch(7))
+        ));
+
+        Ok(())
+    }
+
     #[test]
     fn group_session_pickling_roundtrip_is_identity() -> Result<()> {
         let session = GroupSession::new(Default::default());
@@ -290,6 +463,100 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_pickling() -> Result<()> {
+        let mut session = GroupSession::new(SessionConfig::version_1());
+        session.encrypt("Advance the ratchet");
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let pickle = session.to_libolm_pickle(key)?;
+
+        let olm = OlmOutboundGroupSession::unpickle(
+            pickle,
+            olm_rs::PicklingMode::Encrypted { key: key.to_vec() },
+        )?;
+
+        assert_eq!(olm.session_id(), session.session_id());
+        assert_eq!(olm.session_message_index(), session.message_index());
+
+        let mut inbound_session = InboundGroupSession::from(&session);
+
+        let plaintext = "It's a secret to everybody";
+        let message = olm.encrypt(plaintext).as_str().try_into()?;
+        let decrypted = inbound_session.decrypt(&message)?;
+
+        assert_eq!(decrypted.plaintext, plaintext.as_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_inbound_pickling() -> Result<()> {
+        let mut session = GroupSession::new(SessionConfig::version_1());
+        let inbound_session = InboundGroupSession::from(&session);
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let pickle = inbound_session.to_libolm_pickle(key)?;
+
+        let olm = OlmInboundGroupSession::unpickle(
+            pickle,
+            olm_rs::PicklingMode::Encrypted { key: key.to_vec() },
+        )?;
+
+        assert_eq!(olm.session_id(), inbound_session.session_id());
+        assert_eq!(olm.first_known_index(), inbound_session.first_known_index());
+
+        let plaintext = "It's a secret to everybody";
+        let message = session.encrypt(plaintext).to_base64();
+        let (decrypted, _) = olm.decrypt(message)?;
+
+        assert_eq!(decrypted, plaintext);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_pickling_roundtrip() -> Result<()> {
+        let key = b"DEFAULT_PICKLE_KEY";
+
+        let mut session = GroupSession::new(SessionConfig::version_1());
+        let mut inbound_session = InboundGroupSession::from(&session);
+        session.encrypt("Advance the ratchet");
+
+        let pickle = session.to_libolm_pickle(key)?;
+        let mut unpickled = GroupSession::from_libolm_pickle(&pickle, key)?;
+
+        assert_eq!(session.session_id(), unpickled.session_id());
+        assert_eq!(session.message_index(), unpickled.message_index());
+
+        let pickle = inbound_session.to_libolm_pickle(key)?;
+        let mut unpickled_inbound = InboundGroupSession::from_libolm_pickle(&pickle, key)?;
+
+        assert_eq!(inbound_session.session_id(), unpickled_inbound.session_id());
+
+        let plaintext = "It's a secret to everybody".as_bytes();
+        let message = unpickled.encrypt(plaintext);
+
+        assert_eq!(inbound_session.decrypt(&message)?.plaintext, plaintext);
+        assert_eq!(unpickled_inbound.decrypt(&message)?.plaintext, plaintext);
+
+        let session = GroupSession::new(SessionConfig::version_2());
+
+        assert!(matches!(
+            session.to_libolm_pickle(key),
+            Err(crate::LibolmPickleError::UnsupportedSessionConfig)
+        ));
+        assert!(matches!(
+            InboundGroupSession::from(&session).to_libolm_pickle(key),
+            Err(crate::LibolmPickleError::UnsupportedSessionConfig)
+        ));
+
+        Ok(())
+    }
+
     #[test]
     fn fuzz_corpus_decoding() {
         run_corpus("megolm-decoding", |data| {
@@ -14,7 +14,7 @@
 // limitations under the License.
 
 use hmac::{Hmac, Mac as _};
-use rand::{thread_rng, RngCore};
+use rand::{thread_rng, CryptoRng, RngCore};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{digest::CtOutput, Sha256};
 use subtle::{Choice, ConstantTimeEq};
@@ -137,8 +137,10 @@ impl Ratchet {
     const LAST_RATCHET_INDEX: usize = Self::RATCHET_PART_COUNT - 1;
 
     pub fn new() -> Self {
-        let mut rng = thread_rng();
+        Self::new_with_rng(&mut thread_rng())
+    }
 
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng)) -> Self {
         let mut ratchet =
             Self { inner: RatchetBytes(Box::new([0u8; Self::RATCHET_LENGTH])), counter: 0 };
 
@@ -13,21 +13,49 @@
 // limitations under the License.
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cipher::PaddingScheme;
+
+/// Error type describing an operation the [`SessionConfig`] of a Megolm
+/// session doesn't support.
+#[derive(Debug, Error)]
+pub enum SessionConfigError {
+    /// The session uses a Megolm version whose messages don't authenticate
+    /// associated data.
+    #[error("Megolm version {0} doesn't support associated data")]
+    AssociatedDataUnsupported(u8),
+    /// The config has a padding scheme while its Megolm version doesn't pad
+    /// messages, or it's missing the padding scheme for a version that does.
+    #[error("The padding scheme doesn't match the Megolm version {0}")]
+    PaddingSchemeMismatch(u8),
+}
 
 /// A struct to configure how Megolm sessions should work under the hood.
-/// Currently only the MAC truncation behaviour can be configured.
+/// The cipher, the MAC truncation behaviour, whether the MAC covers
+/// associated data and whether plaintexts get padded can be configured.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "SessionConfigPickle")]
 pub struct SessionConfig {
     pub(super) version: Version,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    padding_scheme: Option<PaddingScheme>,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub(super) enum Version {
     V1 = 1,
     V2 = 2,
+    V3 = 3,
+    V4 = 4,
+    V5 = 5,
 }
 
 impl SessionConfig {
+    fn new(version: Version) -> Self {
+        SessionConfig { version, padding_scheme: None }
+    }
+
     /// Get the numeric version of this `SessionConfig`.
     pub fn version(&self) -> u8 {
         self.version as u8
@@ -37,14 +65,54 @@ impl SessionConfig {
     /// Megolm uses AES-256 and HMAC with a truncated MAC to encrypt individual
     /// messages. The MAC will be truncated to 8 bytes.
     pub fn version_1() -> Self {
-        SessionConfig { version: Version::V1 }
+        Self::new(Version::V1)
     }
 
     /// Create a `SessionConfig` for the Megolm version 2. This version of
     /// Megolm uses AES-256 and HMAC to encrypt individual messages. The MAC
     /// won't be truncated.
     pub fn version_2() -> Self {
-        SessionConfig { version: Version::V2 }
+        Self::new(Version::V2)
+    }
+
+    /// Create a `SessionConfig` for the Megolm version 3. This version of
+    /// Megolm uses AES-256 and HMAC to encrypt individual messages, the MAC
+    /// won't be truncated and additionally covers the associated data passed
+    /// to [`GroupSession::encrypt_with_ad()`] and
+    /// [`InboundGroupSession::decrypt_with_ad()`].
+    ///
+    /// [`GroupSession::encrypt_with_ad()`]: crate::megolm::GroupSession::encrypt_with_ad
+    /// [`InboundGroupSession::decrypt_with_ad()`]: crate::megolm::InboundGroupSession::decrypt_with_ad
+    pub fn version_3() -> Self {
+        Self::new(Version::V3)
+    }
+
+    /// Create a `SessionConfig` for the Megolm version 4. This version of
+    /// Megolm uses ChaCha20-Poly1305 to encrypt and authenticate individual
+    /// messages. The ciphertext isn't padded and a 16 byte authentication tag
+    /// takes the place of the MAC. Like version 3, this version supports
+    /// associated data.
+    pub fn version_4() -> Self {
+        Self::new(Version::V4)
+    }
+
+    /// Create a `SessionConfig` for the Megolm version 5. This version of
+    /// Megolm works like version 4, but the plaintext gets padded before it
+    /// gets encrypted, hiding its exact length. The given [`PaddingScheme`]
+    /// decides how much padding is added, receivers strip the padding
+    /// regardless of the scheme.
+    pub fn version_5(padding_scheme: PaddingScheme) -> Self {
+        SessionConfig { padding_scheme: Some(padding_scheme), ..Self::new(Version::V5) }
+    }
+
+    /// The [`PaddingScheme`] messages get padded with, only used by the
+    /// Megolm version 5.
+    pub fn padding_scheme(&self) -> Option<PaddingScheme> {
+        self.padding_scheme
+    }
+
+    pub(super) fn supports_associated_data(&self) -> bool {
+        matches!(self.version, Version::V3 | Version::V4 | Version::V5)
     }
 }
 
@@ -53,3 +121,51 @@ impl Default for SessionConfig {
         Self::version_2()
     }
 }
+
+/// The serialized form of [`SessionConfig`], only the version 5 may, and has
+/// to, come with a padding scheme.
+#[derive(Deserialize)]
+struct SessionConfigPickle {
+    version: Version,
+    #[serde(default)]
+    padding_scheme: Option<PaddingScheme>,
+}
+
+impl TryFrom<SessionConfigPickle> for SessionConfig {
+    type Error = SessionConfigError;
+
+    fn try_from(pickle: SessionConfigPickle) -> Result<Self, Self::Error> {
+        if (pickle.version == Version::V5) != pickle.padding_scheme.is_some() {
+            Err(SessionConfigError::PaddingSchemeMismatch(pickle.version as u8))
+        } else {
+            Ok(SessionConfig { padding_scheme: pickle.padding_scheme, ..Self::new(pickle.version) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SessionConfig;
+    use crate::cipher::PaddingScheme;
+
+    #[test]
+    fn deserialized_padding_scheme_is_checked() -> Result<(), serde_json::Error> {
+        let config = SessionConfig::version_5(PaddingScheme::Padme);
+        let deserialized: SessionConfig = serde_json::from_str(&serde_json::to_string(&config)?)?;
+        assert_eq!(deserialized, config);
+
+        let config: SessionConfig = serde_json::from_str(r#"{"version":"V1"}"#)?;
+        assert_eq!(config, SessionConfig::version_1());
+
+        for invalid in [
+            r#"{"version":"V2","padding_scheme":"Padme"}"#,
+            r#"{"version":"V4","padding_scheme":"PowerOfTwo"}"#,
+            r#"{"version":"V5"}"#,
+        ] {
+            serde_json::from_str::<SessionConfig>(invalid)
+                .expect_err("A padding scheme not matching the version should be rejected");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for [dehydrated devices].
+//!
+//! A dehydrated device is an [`Account`] which gets encrypted and uploaded to
+//! the server while the user has no other device online. Other users can
+//! establish Olm sessions with the dehydrated device and send it to-device
+//! messages. Once the user logs in again, the device is rehydrated and the
+//! messages that were sent to it in the meantime can be decrypted.
+//!
+//! The format of the dehydrated device is specific to vodozemac, it isn't
+//! compatible with the format [MSC3814] proposes nor with libolm, and only
+//! vodozemac can rehydrate the device. The account is serialized as a `u32`
+//! pickle version in big-endian byte order, currently `2`, followed by the
+//! JSON encoded [`AccountPickle`]. This way the dehydrated device contains the
+//! full state of the account, like the one-time key config, the cache of
+//! consumed one-time keys, the timestamps of the fallback keys, all previous
+//! fallback keys and the current and previous signed pre-keys. The serialized
+//! account is encrypted using ChaCha20-Poly1305 and a key derived with
+//! [`DehydratedDeviceKey::from_secret()`].
+//!
+//! Dehydrated devices using an older version of the format can't be
+//! rehydrated, they need to be replaced with a new dehydrated device.
+//!
+//! [`AccountPickle`]: super::AccountPickle
+//! [dehydrated devices]: https://github.com/matrix-org/matrix-spec-proposals/pull/3814
+//! [MSC3814]: https://github.com/matrix-org/matrix-spec-proposals/pull/3814
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use rand::{thread_rng, RngCore};
+use sha2::Sha256;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use super::{Account, AccountPickle};
+use crate::utilities::{base64_decode, base64_encode};
+
+const PICKLE_VERSION: u32 = 2;
+const NONCE_LENGTH: usize = 12;
+const KEY_INFO: &[u8] = b"DEHYDRATED_DEVICE";
+
+/// Error type describing failures that can happen while dehydrating or
+/// rehydrating a device.
+#[derive(Debug, Error)]
+pub enum DehydratedDeviceError {
+    /// The ciphertext or the nonce weren't valid base64.
+    #[error("The dehydrated device wasn't valid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    /// The nonce doesn't have the expected length.
+    #[error("The nonce has an invalid length: expected {NONCE_LENGTH}, got {0}")]
+    InvalidNonceLength(usize),
+    /// The dehydrated device couldn't be decrypted, either because the wrong
+    /// key was used or because the ciphertext was tampered with.
+    #[error("The dehydrated device couldn't be decrypted")]
+    Decryption,
+    /// The decrypted payload is missing the pickle version.
+    #[error("The dehydrated device is missing a pickle version")]
+    MissingVersion,
+    /// The pickle version of the dehydrated device isn't supported.
+    #[error("The dehydrated device uses an unsupported version: expected {0}, got {1}")]
+    Version(u32, u32),
+    /// The account couldn't be serialized or the decrypted payload couldn't
+    /// be deserialized.
+    #[error("The dehydrated device couldn't be (de)serialized: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// The key that is used to encrypt and decrypt a dehydrated device.
+pub struct DehydratedDeviceKey(Box<[u8; 32]>);
+
+impl DehydratedDeviceKey {
+    /// Derive a dehydrated device key from the given secret.
+    ///
+    /// The secret is usually the one stored in the user's secret storage. The
+    /// key is derived using HKDF-SHA-256 with the ID of the dehydrated device
+    /// as the salt, so every dehydrated device uses a different key.
+    pub fn from_secret(secret: &[u8], device_id: &str) -> Self {
+        let hkdf: Hkdf<Sha256> = Hkdf::new(Some(device_id.as_bytes()), secret);
+        let mut key = Box::new([0u8; 32]);
+
+        hkdf.expand(KEY_INFO, key.as_mut_slice())
+            .expect("We should be able to expand the secret into a 32 byte key");
+
+        Self(key)
+    }
+
+    /// Create a dehydrated device key from raw bytes.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        Self(Box::new(*bytes))
+    }
+
+    /// View the key as a byte array.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Drop for DehydratedDeviceKey {
+    fn drop(&mut self) {
+        self.0.zeroize()
+    }
+}
+
+/// An encrypted [`Account`], ready to be uploaded as a dehydrated device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DehydratedDevice {
+    /// The base64 encoded, encrypted account.
+    pub ciphertext: String,
+    /// The base64 encoded nonce that was used to encrypt the account.
+    pub nonce: String,
+}
+
+pub(super) fn dehydrate(
+    account: &Account,
+    key: &DehydratedDeviceKey,
+) -> Result<DehydratedDevice, DehydratedDeviceError> {
+    let mut plaintext = PICKLE_VERSION.to_be_bytes().to_vec();
+    serde_json::to_writer(&mut plaintext, &account.pickle())?;
+
+    let mut nonce = [0u8; NONCE_LENGTH];
+    thread_rng().fill_bytes(&mut nonce);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_slice());
+
+    plaintext.zeroize();
+
+    // Encryption only fails if the plaintext is larger than what
+    // ChaCha20-Poly1305 supports, which is far beyond the size of an account.
+    let ciphertext = ciphertext.expect("We should be able to encrypt the account");
+
+    Ok(DehydratedDevice { ciphertext: base64_encode(ciphertext), nonce: base64_encode(nonce) })
+}
+
+pub(super) fn rehydrate(
+    ciphertext: &str,
+    nonce: &str,
+    key: &DehydratedDeviceKey,
+) -> Result<Account, DehydratedDeviceError> {
+    let ciphertext = base64_decode(ciphertext)?;
+    let nonce = base64_decode(nonce)?;
+
+    if nonce.len() != NONCE_LENGTH {
+        return Err(DehydratedDeviceError::InvalidNonceLength(nonce.len()));
+    }
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+    let mut plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| DehydratedDeviceError::Decryption)?;
+
+    let pickle = decode_pickle(&plaintext);
+    plaintext.zeroize();
+
+    Ok(Account::from_pickle(pickle?))
+}
+
+fn decode_pickle(plaintext: &[u8]) -> Result<AccountPickle, DehydratedDeviceError> {
+    let (version, pickle) = plaintext
+        .get(0..4)
+        .and_then(|v| v.try_into().ok())
+        .map(|v| (u32::from_be_bytes(v), &plaintext[4..]))
+        .ok_or(DehydratedDeviceError::MissingVersion)?;
+
+    if version != PICKLE_VERSION {
+        return Err(DehydratedDeviceError::Version(PICKLE_VERSION, version));
+    }
+
+    Ok(serde_json::from_slice(pickle)?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use anyhow::{bail, Result};
+    use assert_matches::assert_matches;
+    use chacha20poly1305::{
+        aead::{Aead, NewAead},
+        ChaCha20Poly1305, Key, Nonce,
+    };
+
+    use super::{DehydratedDeviceError, DehydratedDeviceKey, NONCE_LENGTH};
+    use crate::{
+        olm::{Account, OlmMessage, OneTimeKeyConfig, SessionConfig, SignedPreKeyPolicy},
+        utilities::base64_encode,
+    };
+
+    #[test]
+    fn key_derivation() {
+        let secret = b"It's a secret to everybody";
+
+        let first = DehydratedDeviceKey::from_secret(secret, "DEVICEID");
+        let second = DehydratedDeviceKey::from_secret(secret, "DEVICEID");
+        let other = DehydratedDeviceKey::from_secret(secret, "OTHERDEVICEID");
+
+        assert_eq!(first.as_bytes(), second.as_bytes());
+        assert_ne!(first.as_bytes(), other.as_bytes());
+    }
+
+    #[test]
+    fn dehydration_roundtrip() -> Result<()> {
+        let key = DehydratedDeviceKey::from_secret(b"It's a secret to everybody", "DEVICEID");
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let mut account = Account::new();
+        account.set_one_time_key_config(
+            OneTimeKeyConfig::default()
+                .with_max_published_one_time_keys(10)?
+                .with_max_one_time_keys(20)?
+                .with_consumed_key_cache_size(10)?,
+        );
+        account.set_max_previous_fallback_keys(3);
+        account.generate_one_time_keys(10);
+        account.generate_fallback_key_at(now);
+        account.mark_keys_as_published();
+
+        for _ in 0..3 {
+            account.generate_fallback_key_at(now);
+        }
+
+        account.generate_one_time_keys(5);
+
+        // Use up a one-time key, so the cache of consumed keys isn't empty.
+        let alice = Account::new();
+        let one_time_key = *account.one_time_keys().values().next().expect("Missing one-time key");
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            account.curve25519_key(),
+            one_time_key,
+        );
+
+        if let OlmMessage::PreKey(m) = alice_session.encrypt("It's a secret") {
+            account.create_inbound_session(alice.curve25519_key(), &m)?;
+        } else {
+            bail!("Expected a pre-key message");
+        }
+
+        let device = account.dehydrate(&key)?;
+        let rehydrated = Account::rehydrate(&device.ciphertext, &device.nonce, &key)?;
+
+        // The dehydrated device contains the full state of the account.
+        assert_eq!(
+            serde_json::to_value(account.pickle())?,
+            serde_json::to_value(rehydrated.pickle())?
+        );
+        assert_eq!(account.identity_keys(), rehydrated.identity_keys());
+        assert_eq!(account.one_time_keys(), rehydrated.one_time_keys());
+        assert_eq!(account.fallback_key(), rehydrated.fallback_key());
+        assert_eq!(rehydrated.one_time_key_config(), account.one_time_key_config());
+        assert_eq!(rehydrated.fallback_keys.previous_fallback_keys.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rehydrated_device_keeps_signed_pre_keys() -> Result<()> {
+        let key = DehydratedDeviceKey::from_bytes(&[1u8; 32]);
+        let policy = SignedPreKeyPolicy::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+
+        let previous = bob.generate_signed_pre_key_at(start);
+        let current = bob.generate_signed_pre_key_at(start + policy.rotation_period);
+
+        let device = bob.dehydrate(&key)?;
+        let mut bob = Account::rehydrate(&device.ciphertext, &device.nonce, &key)?;
+
+        assert_eq!(bob.signed_pre_key(), Some(current));
+        assert!(!bob.should_rotate_signed_pre_key(&policy, start + policy.rotation_period));
+
+        // The previous signed pre-key can still be used, until its retention
+        // period is over.
+        let mut alice_session = alice.create_outbound_session_from_signed_pre_key(
+            SessionConfig::version_2(),
+            &bob.identity_keys(),
+            &previous,
+        )?;
+
+        if let OlmMessage::PreKey(m) = alice_session.encrypt("It's a secret") {
+            let result = bob.create_inbound_session(alice.curve25519_key(), &m)?;
+            assert_eq!(result.plaintext, b"It's a secret");
+        } else {
+            bail!("Expected a pre-key message");
+        }
+
+        let expired_at = start + policy.rotation_period + policy.retention_period;
+        assert_eq!(bob.forget_expired_signed_pre_keys(&policy, expired_at), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_version() -> Result<()> {
+        let key = DehydratedDeviceKey::from_bytes(&[1u8; 32]);
+        let nonce = [0u8; NONCE_LENGTH];
+
+        let mut plaintext = 1u32.to_be_bytes().to_vec();
+        serde_json::to_writer(&mut plaintext, &Account::new().pickle())?;
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .expect("We should be able to encrypt the account");
+
+        assert_matches!(
+            Account::rehydrate(&base64_encode(ciphertext), &base64_encode(nonce), &key).err(),
+            Some(DehydratedDeviceError::Version(2, 1))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rehydrated_device_decrypts_messages() -> Result<()> {
+        let key = DehydratedDeviceKey::from_bytes(&[1u8; 32]);
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().expect("Missing one-time key");
+        bob.mark_keys_as_published();
+
+        let device = bob.dehydrate(&key)?;
+        let mut bob = Account::rehydrate(&device.ciphertext, &device.nonce, &key)?;
+
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let message = "It's a secret to everybody";
+
+        if let OlmMessage::PreKey(m) = alice_session.encrypt(message) {
+            let result = bob.create_inbound_session(alice.curve25519_key(), &m)?;
+            assert_eq!(message.as_bytes(), result.plaintext);
+        } else {
+            bail!("Expected a pre-key message");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn rehydration_with_invalid_input() -> Result<()> {
+        let key = DehydratedDeviceKey::from_bytes(&[1u8; 32]);
+        let wrong_key = DehydratedDeviceKey::from_bytes(&[2u8; 32]);
+
+        let device = Account::new().dehydrate(&key)?;
+
+        assert_matches!(
+            Account::rehydrate(&device.ciphertext, &device.nonce, &wrong_key).err(),
+            Some(DehydratedDeviceError::Decryption)
+        );
+        assert_matches!(
+            Account::rehydrate(&device.ciphertext, "AAAA", &key).err(),
+            Some(DehydratedDeviceError::InvalidNonceLength(3))
+        );
+        assert_matches!(
+            Account::rehydrate("!!!", &device.nonce, &key).err(),
+            Some(DehydratedDeviceError::Base64(_))
+        );
+
+        Ok(())
+    }
+}
@@ -12,25 +12,87 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use rand::{thread_rng, CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 
+use super::has_elapsed;
 use crate::{
     types::{Curve25519SecretKey, KeyId},
     Curve25519PublicKey,
 };
 
+/// The number of previous fallback keys an [`Account`] keeps around by
+/// default.
+///
+/// [`Account`]: super::Account
+const DEFAULT_MAX_PREVIOUS_FALLBACK_KEYS: usize = 1;
+
+/// The policy deciding when the fallback key of an [`Account`] should be
+/// rotated and when previous fallback keys can be forgotten.
+///
+/// All the decisions are based on the timestamps the [`Account`] records when
+/// a fallback key is created using [`Account::generate_fallback_key_at()`],
+/// or used for the first time using [`Account::create_inbound_session_at()`].
+///
+/// [`Account`]: super::Account
+/// [`Account::generate_fallback_key_at()`]: super::Account::generate_fallback_key_at
+/// [`Account::create_inbound_session_at()`]: super::Account::create_inbound_session_at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FallbackKeyPolicy {
+    /// The maximal age of a fallback key. Older fallback keys should be
+    /// rotated even if nobody used them.
+    pub max_age: Duration,
+    /// How long a fallback key should remain in use after somebody used it
+    /// for the first time.
+    pub max_age_after_first_use: Duration,
+    /// How long a previous fallback key should be kept around after it has
+    /// been replaced. Messages which used the key might still be in flight.
+    pub previous_key_lifetime: Duration,
+}
+
+impl Default for FallbackKeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            max_age_after_first_use: Duration::from_secs(60 * 60),
+            previous_key_lifetime: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub(super) struct FallbackKey {
     pub key_id: KeyId,
     pub key: Curve25519SecretKey,
     pub published: bool,
+    #[serde(default)]
+    pub created_at: Option<SystemTime>,
+    #[serde(default)]
+    pub first_used_at: Option<SystemTime>,
+    #[serde(default)]
+    pub replaced_at: Option<SystemTime>,
 }
 
 impl FallbackKey {
-    fn new(key_id: KeyId) -> Self {
-        let key = Curve25519SecretKey::new();
+    fn new(
+        key_id: KeyId,
+        created_at: Option<SystemTime>,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Self {
+        let key = Curve25519SecretKey::new_with_rng(rng);
+
+        Self { key_id, key, published: false, created_at, first_used_at: None, replaced_at: None }
+    }
 
-        Self { key_id, key, published: false }
+    /// Restore a fallback key from a format which doesn't store any
+    /// timestamps.
+    pub fn from_parts(key_id: KeyId, key: Curve25519SecretKey, published: bool) -> Self {
+        Self { key_id, key, published, created_at: None, first_used_at: None, replaced_at: None }
     }
 
     pub fn public_key(&self) -> Curve25519PublicKey {
@@ -52,18 +114,49 @@ impl FallbackKey {
     pub fn published(&self) -> bool {
         self.published
     }
+
+    /// Has this previous fallback key been replaced long enough ago to be
+    /// forgotten.
+    fn is_expired(&self, policy: &FallbackKeyPolicy, now: SystemTime) -> bool {
+        self.replaced_at.map_or(false, |replaced_at| {
+            has_elapsed(replaced_at, now, policy.previous_key_lifetime)
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "FallbackKeysPickle")]
 pub(super) struct FallbackKeys {
     pub key_id: u64,
     pub fallback_key: Option<FallbackKey>,
-    pub previous_fallback_key: Option<FallbackKey>,
+    /// The fallback keys that were replaced by a newer one, newest first.
+    pub previous_fallback_keys: VecDeque<FallbackKey>,
+    pub max_previous_fallback_keys: usize,
 }
 
 impl FallbackKeys {
     pub fn new() -> Self {
-        Self { key_id: 0, fallback_key: None, previous_fallback_key: None }
+        Self {
+            key_id: 0,
+            fallback_key: None,
+            previous_fallback_keys: VecDeque::new(),
+            max_previous_fallback_keys: DEFAULT_MAX_PREVIOUS_FALLBACK_KEYS,
+        }
+    }
+
+    /// Restore the fallback keys from a format which only supports a single
+    /// previous fallback key.
+    pub fn from_parts(
+        key_id: u64,
+        fallback_key: Option<FallbackKey>,
+        previous_fallback_key: Option<FallbackKey>,
+    ) -> Self {
+        Self {
+            key_id,
+            fallback_key,
+            previous_fallback_keys: previous_fallback_key.into_iter().collect(),
+            ..Self::new()
+        }
     }
 
     pub fn mark_as_published(&mut self) {
@@ -72,43 +165,154 @@ impl FallbackKeys {
         }
     }
 
-    pub fn generate_fallback_key(&mut self) {
+    pub fn generate_fallback_key(&mut self, now: Option<SystemTime>) {
+        self.generate_fallback_key_with_rng(now, &mut thread_rng())
+    }
+
+    pub fn generate_fallback_key_with_rng(
+        &mut self,
+        now: Option<SystemTime>,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) {
         let key_id = KeyId(self.key_id);
         self.key_id += 1;
 
-        self.previous_fallback_key = self.fallback_key.take();
-        self.fallback_key = Some(FallbackKey::new(key_id))
+        if let Some(mut fallback_key) = self.fallback_key.take() {
+            fallback_key.replaced_at = now;
+            self.previous_fallback_keys.push_front(fallback_key);
+            self.previous_fallback_keys.truncate(self.max_previous_fallback_keys);
+        }
+
+        self.fallback_key = Some(FallbackKey::new(key_id, now, rng))
+    }
+
+    pub fn set_max_previous_fallback_keys(&mut self, max_previous_fallback_keys: usize) {
+        self.max_previous_fallback_keys = max_previous_fallback_keys;
+        self.previous_fallback_keys.truncate(max_previous_fallback_keys);
+    }
+
+    /// The most recently replaced fallback key.
+    pub fn previous_fallback_key(&self) -> Option<&FallbackKey> {
+        self.previous_fallback_keys.front()
     }
 
     pub fn get_secret_key(&self, public_key: &Curve25519PublicKey) -> Option<&Curve25519SecretKey> {
         self.fallback_key
-            .as_ref()
-            .filter(|f| f.public_key() == *public_key)
-            .or_else(|| {
-                self.previous_fallback_key.as_ref().filter(|f| f.public_key() == *public_key)
-            })
+            .iter()
+            .chain(self.previous_fallback_keys.iter())
+            .find(|f| f.public_key() == *public_key)
             .map(|f| f.secret_key())
     }
 
-    pub fn forget_previous_fallback_key(&mut self) -> Option<FallbackKey> {
-        self.previous_fallback_key.take()
+    /// Get the key ID of the fallback key with the given public key, and
+    /// whether the key is a previous fallback key.
+    pub fn get_key_id(&self, public_key: &Curve25519PublicKey) -> Option<(KeyId, bool)> {
+        let matches = |f: &&FallbackKey| f.public_key() == *public_key;
+
+        self.fallback_key.as_ref().filter(matches).map(|f| (f.key_id(), false)).or_else(|| {
+            self.previous_fallback_keys.iter().find(matches).map(|f| (f.key_id(), true))
+        })
+    }
+
+    /// Remember when the fallback key with the given public key was used for
+    /// the first time.
+    pub fn mark_as_used(&mut self, public_key: &Curve25519PublicKey, now: SystemTime) {
+        if let Some(fallback_key) = self
+            .fallback_key
+            .iter_mut()
+            .chain(self.previous_fallback_keys.iter_mut())
+            .find(|f| f.public_key() == *public_key)
+        {
+            fallback_key.first_used_at.get_or_insert(now);
+        }
+    }
+
+    pub fn forget_previous_fallback_keys(&mut self) -> bool {
+        let forgot_keys = !self.previous_fallback_keys.is_empty();
+        self.previous_fallback_keys.clear();
+
+        forgot_keys
     }
 
     pub fn unpublished_fallback_key(&self) -> Option<&FallbackKey> {
         self.fallback_key.as_ref().filter(|f| !f.published())
     }
+
+    pub fn should_rotate(&self, policy: &FallbackKeyPolicy, now: SystemTime) -> bool {
+        if let Some(fallback_key) = &self.fallback_key {
+            let too_old = fallback_key
+                .created_at
+                .map_or(false, |created_at| has_elapsed(created_at, now, policy.max_age));
+            let used_for_too_long = fallback_key.first_used_at.map_or(false, |first_used_at| {
+                has_elapsed(first_used_at, now, policy.max_age_after_first_use)
+            });
+
+            too_old || used_for_too_long
+        } else {
+            true
+        }
+    }
+
+    pub fn can_forget_previous(&self, policy: &FallbackKeyPolicy, now: SystemTime) -> bool {
+        self.previous_fallback_keys.iter().any(|f| f.is_expired(policy, now))
+    }
+
+    pub fn forget_expired(&mut self, policy: &FallbackKeyPolicy, now: SystemTime) -> usize {
+        let count = self.previous_fallback_keys.len();
+
+        self.previous_fallback_keys.retain(|f| !f.is_expired(policy, now));
+
+        count - self.previous_fallback_keys.len()
+    }
+}
+
+fn default_max_previous_fallback_keys() -> usize {
+    DEFAULT_MAX_PREVIOUS_FALLBACK_KEYS
+}
+
+/// The serialized form of [`FallbackKeys`], older pickles only contained a
+/// single previous fallback key.
+#[derive(Deserialize)]
+struct FallbackKeysPickle {
+    key_id: u64,
+    fallback_key: Option<FallbackKey>,
+    #[serde(default)]
+    previous_fallback_key: Option<FallbackKey>,
+    #[serde(default)]
+    previous_fallback_keys: VecDeque<FallbackKey>,
+    #[serde(default = "default_max_previous_fallback_keys")]
+    max_previous_fallback_keys: usize,
+}
+
+impl From<FallbackKeysPickle> for FallbackKeys {
+    fn from(pickle: FallbackKeysPickle) -> Self {
+        let mut previous_fallback_keys = pickle.previous_fallback_keys;
+
+        if let Some(previous_fallback_key) = pickle.previous_fallback_key {
+            previous_fallback_keys.push_front(previous_fallback_key);
+        }
+
+        Self {
+            key_id: pickle.key_id,
+            fallback_key: pickle.fallback_key,
+            previous_fallback_keys,
+            max_previous_fallback_keys: pickle.max_previous_fallback_keys,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::FallbackKeys;
+    use std::time::{Duration, SystemTime};
+
+    use super::{FallbackKeyPolicy, FallbackKeys};
 
     #[test]
     fn fallback_key_fetching() {
         let err = "Missing fallback key";
         let mut fallback_keys = FallbackKeys::new();
 
-        fallback_keys.generate_fallback_key();
+        fallback_keys.generate_fallback_key(None);
 
         let public_key = fallback_keys.fallback_key.as_ref().expect(err).public_key();
         let secret_bytes = fallback_keys.fallback_key.as_ref().expect(err).key.to_bytes();
@@ -117,7 +321,7 @@ mod test {
 
         assert_eq!(secret_bytes, fetched_key.to_bytes());
 
-        fallback_keys.generate_fallback_key();
+        fallback_keys.generate_fallback_key(None);
 
         let fetched_key = fallback_keys.get_secret_key(&public_key).expect(err);
         assert_eq!(secret_bytes, fetched_key.to_bytes());
@@ -129,4 +333,142 @@ mod test {
 
         assert_eq!(secret_bytes, fetched_key.to_bytes());
     }
+
+    #[test]
+    fn rotation_policy() {
+        let policy = FallbackKeyPolicy::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut fallback_keys = FallbackKeys::new();
+
+        // We always want a fallback key.
+        assert!(fallback_keys.should_rotate(&policy, start));
+
+        fallback_keys.generate_fallback_key(Some(start));
+        assert!(!fallback_keys.should_rotate(&policy, start));
+        assert!(!fallback_keys.should_rotate(&policy, start + policy.max_age / 2));
+        assert!(fallback_keys.should_rotate(&policy, start + policy.max_age));
+
+        // Once the key gets used, it should be rotated sooner.
+        let public_key =
+            fallback_keys.fallback_key.as_ref().expect("Missing fallback key").public_key();
+        let used_at = start + Duration::from_secs(10);
+        fallback_keys.mark_as_used(&public_key, used_at);
+        fallback_keys.mark_as_used(&public_key, used_at + Duration::from_secs(10));
+
+        assert!(!fallback_keys.should_rotate(&policy, used_at));
+        assert!(fallback_keys.should_rotate(&policy, used_at + policy.max_age_after_first_use));
+
+        // Keys without a creation time are never too old.
+        let mut fallback_keys = FallbackKeys::new();
+        fallback_keys.generate_fallback_key(None);
+        assert!(!fallback_keys.should_rotate(&policy, start + policy.max_age));
+    }
+
+    #[test]
+    fn previous_key_expiration() {
+        let policy = FallbackKeyPolicy::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut fallback_keys = FallbackKeys::new();
+        fallback_keys.set_max_previous_fallback_keys(3);
+
+        fallback_keys.generate_fallback_key(Some(start));
+        assert!(!fallback_keys.can_forget_previous(&policy, start + policy.previous_key_lifetime));
+
+        let second = start + Duration::from_secs(60);
+        let third = second + Duration::from_secs(60);
+        fallback_keys.generate_fallback_key(Some(second));
+        fallback_keys.generate_fallback_key(Some(third));
+        assert_eq!(fallback_keys.previous_fallback_keys.len(), 2);
+
+        assert!(!fallback_keys.can_forget_previous(&policy, second));
+
+        // The oldest key was replaced by the second key, so it expires first.
+        let now = second + policy.previous_key_lifetime;
+        assert!(fallback_keys.can_forget_previous(&policy, now));
+        assert_eq!(fallback_keys.forget_expired(&policy, now), 1);
+        assert_eq!(fallback_keys.previous_fallback_keys.len(), 1);
+        assert!(!fallback_keys.can_forget_previous(&policy, now));
+
+        let now = third + policy.previous_key_lifetime;
+        assert_eq!(fallback_keys.forget_expired(&policy, now), 1);
+        assert!(fallback_keys.previous_fallback_key().is_none());
+        assert!(fallback_keys.fallback_key.is_some());
+    }
+
+    #[test]
+    fn previous_key_expiration_without_timestamps() {
+        let policy = FallbackKeyPolicy::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut fallback_keys = FallbackKeys::new();
+        fallback_keys.set_max_previous_fallback_keys(3);
+
+        // The oldest key gets replaced without a timestamp, so it never
+        // expires, while the key that replaced it does.
+        fallback_keys.generate_fallback_key(Some(start));
+        fallback_keys.generate_fallback_key(None);
+        fallback_keys.generate_fallback_key(Some(start));
+
+        let now = start + policy.previous_key_lifetime;
+        assert!(fallback_keys.can_forget_previous(&policy, now));
+        assert_eq!(fallback_keys.forget_expired(&policy, now), 1);
+
+        let key_ids: Vec<_> =
+            fallback_keys.previous_fallback_keys.iter().map(|f| f.key_id().0).collect();
+        assert_eq!(key_ids, [0]);
+        assert!(!fallback_keys.can_forget_previous(&policy, now));
+    }
+
+    #[test]
+    fn max_previous_fallback_keys() {
+        let mut fallback_keys = FallbackKeys::new();
+
+        for _ in 0..5 {
+            fallback_keys.generate_fallback_key(None);
+        }
+
+        assert_eq!(fallback_keys.previous_fallback_keys.len(), 1);
+
+        fallback_keys.set_max_previous_fallback_keys(3);
+
+        for _ in 0..5 {
+            fallback_keys.generate_fallback_key(None);
+        }
+
+        assert_eq!(fallback_keys.previous_fallback_keys.len(), 3);
+
+        let key_ids: Vec<_> =
+            fallback_keys.previous_fallback_keys.iter().map(|f| f.key_id().0).collect();
+        assert_eq!(key_ids, [8, 7, 6]);
+
+        fallback_keys.set_max_previous_fallback_keys(2);
+        assert_eq!(fallback_keys.previous_fallback_keys.len(), 2);
+        assert_eq!(fallback_keys.previous_fallback_key().map(|f| f.key_id().0), Some(8));
+
+        assert!(fallback_keys.forget_previous_fallback_keys());
+        assert!(!fallback_keys.forget_previous_fallback_keys());
+    }
+
+    #[test]
+    fn legacy_pickle() -> anyhow::Result<()> {
+        let mut fallback_keys = FallbackKeys::new();
+        fallback_keys.generate_fallback_key(None);
+        fallback_keys.generate_fallback_key(None);
+
+        // Older pickles only contained a single previous fallback key.
+        let mut pickle = serde_json::to_value(&fallback_keys)?;
+        let object = pickle.as_object_mut().expect("The pickle should be an object");
+        let previous_keys = object.remove("previous_fallback_keys").expect("Missing previous keys");
+        object.remove("max_previous_fallback_keys");
+        object.insert("previous_fallback_key".to_owned(), previous_keys[0].clone());
+
+        let unpickled: FallbackKeys = serde_json::from_value(pickle)?;
+
+        assert_eq!(unpickled.max_previous_fallback_keys, 1);
+        assert_eq!(
+            unpickled.previous_fallback_key().map(|f| f.public_key()),
+            fallback_keys.previous_fallback_key().map(|f| f.public_key())
+        );
+
+        Ok(())
+    }
 }
@@ -12,39 +12,71 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod dehydrated_device;
 mod fallback_keys;
 mod one_time_keys;
+mod signed_keys;
+mod signed_pre_keys;
 
-use std::collections::HashMap;
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
 
-use rand::thread_rng;
+use hkdf::Hkdf;
+use rand::{thread_rng, CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use thiserror::Error;
 use x25519_dalek::ReusableSecret;
-
-pub use self::one_time_keys::OneTimeKeyGenerationResult;
+use zeroize::Zeroize;
+
+pub use self::{
+    dehydrated_device::{DehydratedDevice, DehydratedDeviceError, DehydratedDeviceKey},
+    fallback_keys::FallbackKeyPolicy,
+    one_time_keys::{
+        OneTimeKeyConfig, OneTimeKeyConfigError, OneTimeKeyEvictionStrategy,
+        OneTimeKeyGenerationResult,
+    },
+    signed_keys::{SignedKey, SignedKeys},
+    signed_pre_keys::{PublicSignedPreKey, SignedPreKeyPolicy},
+};
 use self::{
     fallback_keys::FallbackKeys,
     one_time_keys::{OneTimeKeys, OneTimeKeysPickle},
+    signed_pre_keys::SignedPreKeys,
 };
 use super::{
     messages::PreKeyMessage,
     session::{DecryptionError, Session},
-    session_keys::SessionKeys,
+    session_keys::{OneTimeKeyKind, SessionKeys},
     shared_secret::{RemoteShared3DHSecret, Shared3DHSecret},
-    SessionConfig,
+    SessionConfig, SessionConfigError,
 };
 use crate::{
+    canonical_json::{self, CanonicalJsonError, JsonSignatureError},
     types::{
         Curve25519Keypair, Curve25519KeypairPickle, Curve25519PublicKey, Curve25519SecretKey,
-        Ed25519Keypair, Ed25519KeypairPickle, Ed25519PublicKey, KeyId,
+        Ed25519Keypair, Ed25519KeypairPickle, Ed25519PublicKey, KeyError, KeyId,
     },
     utilities::{pickle, unpickle},
-    Ed25519Signature, PickleError,
+    Ed25519Signature, PickleError, SignatureError,
 };
 
 const PUBLIC_MAX_ONE_TIME_KEYS: usize = 50;
 
+/// The HKDF info used to derive the Ed25519 identity key from an account seed.
+const ED25519_SEED_INFO: &[u8] = b"VODOZEMAC_ACCOUNT_SEED_ED25519";
+/// The HKDF info used to derive the Curve25519 identity key from an account
+/// seed.
+const CURVE25519_SEED_INFO: &[u8] = b"VODOZEMAC_ACCOUNT_SEED_CURVE25519";
+
+/// Has at least `duration` elapsed between `since` and `now`.
+fn has_elapsed(since: SystemTime, now: SystemTime, duration: Duration) -> bool {
+    matches!(now.duration_since(since), Ok(elapsed) if elapsed >= duration)
+}
+
 /// Error describing failure modes when creating a Olm Session from an incoming
 /// Olm message.
 #[derive(Error, Debug)]
@@ -61,6 +93,19 @@ pub enum SessionCreationError {
         expected {0}, got {1}"
     )]
     MismatchedIdentityKey(Curve25519PublicKey, Curve25519PublicKey),
+    /// The pre-key message has already been used to create a Session. The
+    /// message should be decrypted using the existing Session with the given
+    /// session ID.
+    ///
+    /// This error is only returned as long as the key the pre-key message used
+    /// is still remembered, see
+    /// [`OneTimeKeyConfig::consumed_key_cache_size()`].
+    #[error("The pre-key message belongs to the existing Session {0}")]
+    DuplicatePreKeyMessage(String),
+    /// The given [`SessionConfig`] uses a different Olm version than the
+    /// pre-key message.
+    #[error("The session config doesn't match the pre-key message: {0}")]
+    SessionConfig(#[from] SessionConfigError),
     /// The pre-key message that was used to establish the Session couldn't be
     /// decrypted. The message needs to be decryptable, otherwise we will have
     /// created a Session that wasn't used to encrypt the pre-key message.
@@ -68,6 +113,34 @@ pub enum SessionCreationError {
     Decryption(#[from] DecryptionError),
 }
 
+/// Error describing failure modes when creating an outbound Olm Session from a
+/// one-time key that was claimed from the server.
+#[derive(Error, Debug)]
+pub enum ClaimedKeyError {
+    /// The claimed key isn't signed by the device it supposedly belongs to.
+    #[error("The claimed one-time key isn't signed by the device")]
+    MissingSignature,
+    /// The claimed key contains a signature from the device, but the signature
+    /// couldn't be verified.
+    #[error("The signature of the claimed one-time key is invalid: {0}")]
+    InvalidSignature(JsonSignatureError),
+    /// The claimed key object doesn't have the format of a signed key.
+    #[error("The claimed one-time key object is malformed: {0}")]
+    MalformedKey(#[from] serde_json::Error),
+    /// The claimed key isn't a valid Curve25519 key.
+    #[error("The claimed one-time key isn't a valid Curve25519 key: {0}")]
+    InvalidKey(#[from] KeyError),
+}
+
+impl From<JsonSignatureError> for ClaimedKeyError {
+    fn from(e: JsonSignatureError) -> Self {
+        match e {
+            JsonSignatureError::MissingSignature { .. } => Self::MissingSignature,
+            e => Self::InvalidSignature(e),
+        }
+    }
+}
+
 /// Struct holding the two public identity keys of an [`Account`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IdentityKeys {
@@ -84,6 +157,87 @@ pub struct InboundCreationResult {
     pub session: Session,
     /// The plaintext of the pre-key message.
     pub plaintext: Vec<u8>,
+    /// The one-time or fallback key of ours that was used to create the
+    /// [`Session`].
+    pub consumed_key: ConsumedKey,
+}
+
+/// The key of an [`Account`] that was used by the other side to create a
+/// [`Session`] with us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsumedKey {
+    /// A one-time key was used, the key has been removed from the [`Account`].
+    OneTimeKey {
+        /// The ID of the one-time key.
+        key_id: KeyId,
+        /// The public part of the one-time key.
+        public_key: Curve25519PublicKey,
+    },
+    /// A fallback key was used. This usually means that we ran out of
+    /// published one-time keys, new ones should be uploaded and the fallback
+    /// key should be rotated.
+    FallbackKey {
+        /// The ID of the fallback key.
+        key_id: KeyId,
+        /// The public part of the fallback key.
+        public_key: Curve25519PublicKey,
+        /// Was the key the previous fallback key, i.e. the other side used a
+        /// fallback key we already replaced with a new one.
+        previous: bool,
+    },
+    /// A signed pre-key was used. Signed pre-keys aren't removed when they get
+    /// used, they are replaced according to a [`SignedPreKeyPolicy`].
+    SignedPreKey {
+        /// The ID of the signed pre-key.
+        key_id: KeyId,
+        /// The public part of the signed pre-key.
+        public_key: Curve25519PublicKey,
+    },
+}
+
+impl ConsumedKey {
+    /// The ID of the key that was used.
+    pub fn key_id(&self) -> KeyId {
+        match self {
+            ConsumedKey::OneTimeKey { key_id, .. }
+            | ConsumedKey::FallbackKey { key_id, .. }
+            | ConsumedKey::SignedPreKey { key_id, .. } => *key_id,
+        }
+    }
+
+    /// The public part of the key that was used.
+    pub fn public_key(&self) -> Curve25519PublicKey {
+        match self {
+            ConsumedKey::OneTimeKey { public_key, .. }
+            | ConsumedKey::FallbackKey { public_key, .. }
+            | ConsumedKey::SignedPreKey { public_key, .. } => *public_key,
+        }
+    }
+
+    /// The kind of the key that was used.
+    pub fn kind(&self) -> OneTimeKeyKind {
+        match self {
+            ConsumedKey::OneTimeKey { .. } => OneTimeKeyKind::OneTimeKey,
+            ConsumedKey::FallbackKey { .. } => OneTimeKeyKind::FallbackKey,
+            ConsumedKey::SignedPreKey { .. } => OneTimeKeyKind::SignedPreKey,
+        }
+    }
+
+    /// Was a fallback key used.
+    pub fn is_fallback_key(&self) -> bool {
+        matches!(self, ConsumedKey::FallbackKey { .. })
+    }
+}
+
+/// Return type for the creation of outbound [`Session`] objects from claimed
+/// one-time keys.
+#[derive(Debug)]
+pub struct OutboundCreationResult {
+    /// The [`Session`] that was created using the claimed key.
+    pub session: Session,
+    /// Was the claimed key a fallback key. This means that the other side ran
+    /// out of one-time keys and should upload new ones.
+    pub used_fallback_key: bool,
 }
 
 /// An Olm account manages all cryptographic keys used on a device.
@@ -100,19 +254,75 @@ pub struct Account {
     /// the 3DH, in case we run out of those. We keep track of both the current
     /// and the previous fallback key in any given moment.
     fallback_keys: FallbackKeys,
+    /// The medium-term Curve25519 keys, signed by our Ed25519 key, used in lieu
+    /// of a one-time key as part of the 3DH. Previous signed pre-keys are kept
+    /// around for a while after they have been replaced.
+    signed_pre_keys: SignedPreKeys,
 }
 
 impl Account {
     /// Create a new Account with new random identity keys.
     pub fn new() -> Self {
+        Self::new_with_rng(&mut thread_rng())
+    }
+
+    /// Create a new Account, using the given random number generator to
+    /// create the identity keys.
+    ///
+    /// Other methods which create keys, like
+    /// [`Account::generate_one_time_keys()`], keep on using a random number
+    /// generator of their own, use their `_with_rng()` variants to create
+    /// fully deterministic test vectors.
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng)) -> Self {
         Self {
-            signing_key: Ed25519Keypair::new(),
-            diffie_hellman_key: Curve25519Keypair::new(),
+            signing_key: Ed25519Keypair::new_with_rng(rng),
+            diffie_hellman_key: Curve25519Keypair::new_with_rng(rng),
             one_time_keys: OneTimeKeys::new(),
             fallback_keys: FallbackKeys::new(),
+            signed_pre_keys: SignedPreKeys::new(),
         }
     }
 
+    /// Create a new Account, deriving the identity keys from the given seed.
+    ///
+    /// The Ed25519 and Curve25519 identity keys are derived from the seed using
+    /// HKDF-SHA256 with separate info strings, the same seed will always result
+    /// in the same identity keys. This allows the identity of a device to be
+    /// restored from a backed-up seed instead of a full [`AccountPickle`].
+    ///
+    /// Only the identity keys are derived from the seed, one-time and fallback
+    /// keys are still random. An Account restored from the seed won't be able
+    /// to create sessions from pre-key messages which used one-time keys of the
+    /// original Account.
+    ///
+    /// **Warning**: The seed needs to be generated using a cryptographically
+    /// secure random number generator and it needs to be kept secret, anybody
+    /// who knows the seed can impersonate the device.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let hkdf: Hkdf<Sha256> = Hkdf::new(None, seed);
+
+        let mut ed25519_key = Box::new([0u8; 32]);
+        let mut curve25519_key = Box::new([0u8; 32]);
+
+        hkdf.expand(ED25519_SEED_INFO, ed25519_key.as_mut_slice())
+            .expect("We should be able to expand the seed into a 32 byte key");
+        hkdf.expand(CURVE25519_SEED_INFO, curve25519_key.as_mut_slice())
+            .expect("We should be able to expand the seed into a 32 byte key");
+
+        let account = Self {
+            signing_key: Ed25519Keypair::from_secret_key(&ed25519_key),
+            diffie_hellman_key: Curve25519Keypair::from_secret_key(&curve25519_key),
+            one_time_keys: OneTimeKeys::new(),
+            fallback_keys: FallbackKeys::new(),
+            signed_pre_keys: SignedPreKeys::new(),
+        };
+
+        ed25519_key.zeroize();
+        curve25519_key.zeroize();
+
+        account
+    }
+
     /// Get the IdentityKeys of this Account
     pub fn identity_keys(&self) -> IdentityKeys {
         IdentityKeys { ed25519: self.ed25519_key(), curve25519: self.curve25519_key() }
@@ -133,6 +343,25 @@ impl Account {
         self.signing_key.sign(message.as_bytes())
     }
 
+    /// Sign the given JSON object using our Ed25519 fingerprint key.
+    ///
+    /// The `signatures` and `unsigned` fields are removed from the object, the
+    /// rest is encoded as [canonical JSON] and signed. The signature is then
+    /// inserted into the `signatures.{user_id}.{ed25519:device_id}` field of
+    /// the object, any existing signatures are kept.
+    ///
+    /// [canonical JSON]: https://spec.matrix.org/v1.2/appendices/#canonical-json
+    pub fn sign_json(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        value: &mut serde_json::Value,
+    ) -> Result<(), CanonicalJsonError> {
+        canonical_json::sign_json(user_id, device_id, value, |message| {
+            self.signing_key.sign(message)
+        })
+    }
+
     /// Get the maximum number of one-time keys the client should keep on the
     /// server.
     ///
@@ -149,7 +378,26 @@ impl Account {
         // private one-time keys, since we're generating new ones, while we
         // didn't yet receive the pre-key messages that used those one-time
         // keys.
-        PUBLIC_MAX_ONE_TIME_KEYS
+        self.one_time_keys.config.max_published_one_time_keys()
+    }
+
+    /// Get the configuration of the one-time key pool of this [`Account`].
+    pub fn one_time_key_config(&self) -> OneTimeKeyConfig {
+        self.one_time_keys.config
+    }
+
+    /// Change the configuration of the one-time key pool of this [`Account`].
+    ///
+    /// If the pool now holds more keys than the new
+    /// [`OneTimeKeyConfig::max_one_time_keys()`] allows, keys are removed
+    /// according to the configured [`OneTimeKeyEvictionStrategy`]. Returns the
+    /// removed keys which were already published, the server might still hand
+    /// those out but we won't be able to create sessions using them.
+    pub fn set_one_time_key_config(
+        &mut self,
+        config: OneTimeKeyConfig,
+    ) -> HashMap<KeyId, Curve25519PublicKey> {
+        self.one_time_keys.set_config(config)
     }
 
     /// Create a `Session` with the given identity key and one-time key.
@@ -159,9 +407,45 @@ impl Account {
         identity_key: Curve25519PublicKey,
         one_time_key: Curve25519PublicKey,
     ) -> Session {
-        let rng = thread_rng();
+        self.create_outbound_session_with_rng(
+            session_config,
+            identity_key,
+            one_time_key,
+            &mut thread_rng(),
+        )
+    }
+
+    /// Create a `Session` with the given identity key and one-time key, using
+    /// the given random number generator to create the base key and the first
+    /// ratchet key of the `Session`.
+    ///
+    /// This behaves exactly like [`Account::create_outbound_session()`] and
+    /// is only useful to create deterministic test vectors.
+    pub fn create_outbound_session_with_rng(
+        &self,
+        session_config: SessionConfig,
+        identity_key: Curve25519PublicKey,
+        one_time_key: Curve25519PublicKey,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Session {
+        self.create_outbound_session_helper(
+            session_config,
+            identity_key,
+            one_time_key,
+            OneTimeKeyKind::Unknown,
+            rng,
+        )
+    }
 
-        let base_key = ReusableSecret::new(rng);
+    fn create_outbound_session_helper(
+        &self,
+        session_config: SessionConfig,
+        identity_key: Curve25519PublicKey,
+        one_time_key: Curve25519PublicKey,
+        one_time_key_kind: OneTimeKeyKind,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Session {
+        let base_key = ReusableSecret::new(&mut *rng);
         let public_base_key = Curve25519PublicKey::from(&base_key);
 
         let shared_secret = Shared3DHSecret::new(
@@ -175,15 +459,120 @@ impl Account {
             identity_key: self.curve25519_key(),
             base_key: public_base_key,
             one_time_key,
+            one_time_key_kind,
         };
 
-        Session::new(session_config, shared_secret, session_keys)
+        Session::new(session_config, shared_secret, session_keys, rng)
     }
 
-    fn find_one_time_key(&self, public_key: &Curve25519PublicKey) -> Option<&Curve25519SecretKey> {
-        self.one_time_keys
-            .get_secret_key(public_key)
-            .or_else(|| self.fallback_keys.get_secret_key(public_key))
+    /// Create a `Session` using a one-time key that was claimed from the server
+    /// using the `/keys/claim` endpoint.
+    ///
+    /// Unlike [`Account::create_outbound_session`], this checks that the
+    /// claimed key was signed by the Ed25519 key of the device with the given
+    /// user and device ID, before the key gets used. The `identity_keys`
+    /// should come from the device keys of the device, which need to be
+    /// verified separately.
+    ///
+    /// The `claimed_key` needs to be the key object exactly as it was received
+    /// from the server, the signature covers all of its fields, including
+    /// the ones a [`SignedKey`] doesn't contain.
+    pub fn create_outbound_session_from_claimed_key(
+        &self,
+        session_config: SessionConfig,
+        user_id: &str,
+        device_id: &str,
+        identity_keys: &IdentityKeys,
+        claimed_key: &serde_json::Value,
+    ) -> Result<OutboundCreationResult, ClaimedKeyError> {
+        identity_keys.ed25519.verify_json(user_id, device_id, claimed_key)?;
+
+        let claimed_key = SignedKey::deserialize(claimed_key)?;
+        let one_time_key = claimed_key.curve25519_key()?;
+
+        let one_time_key_kind = if claimed_key.fallback {
+            OneTimeKeyKind::FallbackKey
+        } else {
+            OneTimeKeyKind::OneTimeKey
+        };
+
+        let session = self.create_outbound_session_helper(
+            session_config,
+            identity_keys.curve25519,
+            one_time_key,
+            one_time_key_kind,
+            &mut thread_rng(),
+        );
+
+        Ok(OutboundCreationResult { session, used_fallback_key: claimed_key.fallback })
+    }
+
+    /// Create a `Session` using a signed pre-key of another device.
+    ///
+    /// The signature of the signed pre-key is checked against the Ed25519 key
+    /// in the given `identity_keys` before the key gets used. The
+    /// `identity_keys` should come from the device keys of the device, which
+    /// need to be verified separately.
+    pub fn create_outbound_session_from_signed_pre_key(
+        &self,
+        session_config: SessionConfig,
+        identity_keys: &IdentityKeys,
+        signed_pre_key: &PublicSignedPreKey,
+    ) -> Result<Session, SignatureError> {
+        signed_pre_key.verify(&identity_keys.ed25519)?;
+
+        Ok(self.create_outbound_session_helper(
+            session_config,
+            identity_keys.curve25519,
+            signed_pre_key.public_key,
+            OneTimeKeyKind::SignedPreKey,
+            &mut thread_rng(),
+        ))
+    }
+
+    /// Find the session a pre-key message belongs to, among the given
+    /// sessions.
+    ///
+    /// Pre-key messages get resent until the other side receives a reply, so
+    /// the same pre-key message might arrive multiple times. Incoming pre-key
+    /// messages should therefore be checked against the existing sessions
+    /// with the sender before [`Account::create_inbound_session()`] gets
+    /// called. [`Account::create_inbound_session()`] only recognizes a
+    /// duplicate message as long as the [`Account`] remembers the key it used,
+    /// see [`OneTimeKeyConfig::consumed_key_cache_size()`].
+    ///
+    /// The sessions can be given by reference or by mutable reference, the
+    /// matching one gets returned so it can be used to decrypt the message.
+    ///
+    /// See [`Session::matches_pre_key_message()`] for the check that is
+    /// performed for every session.
+    pub fn find_matching_session<S: Borrow<Session>>(
+        pre_key_message: &PreKeyMessage,
+        sessions: impl IntoIterator<Item = S>,
+    ) -> Option<S> {
+        sessions.into_iter().find(|s| s.borrow().matches_pre_key_message(pre_key_message))
+    }
+
+    fn find_one_time_key(
+        &self,
+        public_key: &Curve25519PublicKey,
+    ) -> Option<(&Curve25519SecretKey, ConsumedKey)> {
+        if let Some(secret_key) = self.one_time_keys.get_secret_key(public_key) {
+            let key_id = self.one_time_keys.get_key_id(public_key)?;
+
+            Some((secret_key, ConsumedKey::OneTimeKey { key_id, public_key: *public_key }))
+        } else if let Some(secret_key) = self.fallback_keys.get_secret_key(public_key) {
+            let (key_id, previous) = self.fallback_keys.get_key_id(public_key)?;
+
+            Some((
+                secret_key,
+                ConsumedKey::FallbackKey { key_id, public_key: *public_key, previous },
+            ))
+        } else {
+            let (secret_key, key_id) = self.signed_pre_keys.get_secret_key(public_key)?;
+
+            Some((secret_key, ConsumedKey::SignedPreKey { key_id, public_key: *public_key }))
+        }
     }
 
     /// Remove a one-time key that has previously been published but not yet
@@ -213,6 +602,88 @@ impl Account {
         &mut self,
         their_identity_key: Curve25519PublicKey,
         pre_key_message: &PreKeyMessage,
+    ) -> Result<InboundCreationResult, SessionCreationError> {
+        self.create_inbound_session_helper(their_identity_key, pre_key_message, None, None, None)
+    }
+
+    /// Create a [`Session`] from the given pre-key message and identity key,
+    /// using the given [`SessionConfig`].
+    ///
+    /// The Olm version of the session is decided by the pre-key message, the
+    /// `session_config` needs to use the same version, otherwise
+    /// [`SessionCreationError::SessionConfig`] is returned. The limits of the
+    /// config, and the [`PaddingScheme`] our replies use, are taken over by the
+    /// new session. [`Account::create_inbound_session()`] uses the default
+    /// limits and padding scheme instead.
+    ///
+    /// [`PaddingScheme`]: crate::olm::PaddingScheme
+    pub fn create_inbound_session_with_config(
+        &mut self,
+        session_config: SessionConfig,
+        their_identity_key: Curve25519PublicKey,
+        pre_key_message: &PreKeyMessage,
+    ) -> Result<InboundCreationResult, SessionCreationError> {
+        self.create_inbound_session_helper(
+            their_identity_key,
+            pre_key_message,
+            Some(session_config),
+            None,
+            None,
+        )
+    }
+
+    /// Create a [`Session`] from the given pre-key message and identity key,
+    /// `now` being the current time.
+    ///
+    /// This behaves exactly like [`Account::create_inbound_session`], but if
+    /// the session was created using one of our fallback keys, the time the
+    /// fallback key was used for the first time gets recorded. The
+    /// [`FallbackKeyPolicy`] uses this to decide when the fallback key should
+    /// be rotated.
+    pub fn create_inbound_session_at(
+        &mut self,
+        their_identity_key: Curve25519PublicKey,
+        pre_key_message: &PreKeyMessage,
+        now: SystemTime,
+    ) -> Result<InboundCreationResult, SessionCreationError> {
+        self.create_inbound_session_helper(
+            their_identity_key,
+            pre_key_message,
+            None,
+            None,
+            Some(now),
+        )
+    }
+
+    /// Create a [`Session`] from the given pre-key message and identity key,
+    /// the MAC of the pre-key message covering the given `associated_data`.
+    ///
+    /// This behaves exactly like [`Account::create_inbound_session`], but the
+    /// pre-key message gets decrypted using [`Session::decrypt_with_ad()`].
+    /// This is only supported if the pre-key message was encrypted by a
+    /// session using [`SessionConfig::version_3()`] or a later version.
+    pub fn create_inbound_session_with_ad(
+        &mut self,
+        their_identity_key: Curve25519PublicKey,
+        pre_key_message: &PreKeyMessage,
+        associated_data: impl AsRef<[u8]>,
+    ) -> Result<InboundCreationResult, SessionCreationError> {
+        self.create_inbound_session_helper(
+            their_identity_key,
+            pre_key_message,
+            None,
+            Some(associated_data.as_ref()),
+            None,
+        )
+    }
+
+    fn create_inbound_session_helper(
+        &mut self,
+        their_identity_key: Curve25519PublicKey,
+        pre_key_message: &PreKeyMessage,
+        session_config: Option<SessionConfig>,
+        associated_data: Option<&[u8]>,
+        now: Option<SystemTime>,
     ) -> Result<InboundCreationResult, SessionCreationError> {
         if their_identity_key != pre_key_message.identity_key() {
             Err(SessionCreationError::MismatchedIdentityKey(
@@ -220,10 +691,21 @@ impl Account {
                 pre_key_message.identity_key(),
             ))
         } else {
+            let public_otk = pre_key_message.one_time_key();
+            let session_id = pre_key_message.session_id();
+
+            // A retransmitted pre-key message can be recognized if we still
+            // remember the key it used, this is checked first so a duplicate
+            // never uses up a key or creates a twin of the existing session.
+            // The session ID covers all the keys of the pre-key message, so a
+            // different message reusing a fallback key won't match.
+            if self.one_time_keys.is_consumed_by(&public_otk, &session_id) {
+                return Err(SessionCreationError::DuplicatePreKeyMessage(session_id));
+            }
+
             // Find the matching private part of the OTK that the message claims
             // was used to create the session that encrypted it.
-            let public_otk = pre_key_message.one_time_key();
-            let private_otk = self
+            let (private_otk, consumed_key) = self
                 .find_one_time_key(&public_otk)
                 .ok_or(SessionCreationError::MissingOneTimeKey(public_otk))?;
 
@@ -240,14 +722,31 @@ impl Account {
                 identity_key: pre_key_message.identity_key(),
                 base_key: pre_key_message.base_key(),
                 one_time_key: pre_key_message.one_time_key(),
+                one_time_key_kind: consumed_key.kind(),
             };
 
             let config = if pre_key_message.message.mac_truncated() {
                 SessionConfig::version_1()
+            } else if pre_key_message.message.is_padded() {
+                SessionConfig::version_5(Default::default())
+            } else if pre_key_message.message.uses_aead() {
+                SessionConfig::version_4()
+            } else if pre_key_message.message.mac_covers_associated_data() {
+                SessionConfig::version_3()
             } else {
                 SessionConfig::version_2()
             };
 
+            let config = match session_config {
+                Some(c) if c.version() != config.version() => {
+                    return Err(
+                        SessionConfigError::VersionMismatch(config.version(), c.version()).into()
+                    );
+                }
+                Some(c) => c,
+                None => config,
+            };
+
             // Create a Session, AKA a double ratchet, this one will have an
             // inactive sending chain until we decide to encrypt a message.
             let mut session = Session::new_remote(
@@ -258,7 +757,11 @@ impl Account {
             );
 
             // Decrypt the message to check if the Session is actually valid.
-            let plaintext = session.decrypt_decoded(&pre_key_message.message)?;
+            let plaintext = if let Some(associated_data) = associated_data {
+                session.decrypt_decoded_with_ad(&pre_key_message.message, associated_data)?
+            } else {
+                session.decrypt_decoded(&pre_key_message.message)?
+            };
 
             // We only drop the one-time key now, this is why we can't use a
             // one-time key type that takes `self`. If we didn't do this,
@@ -268,8 +771,13 @@ impl Account {
             // us. This is strictly worse than the one-time key exhaustion
             // scenario.
             self.remove_one_time_key_helper(pre_key_message.one_time_key());
+            self.one_time_keys.remember_consumed_key(public_otk, session_id);
+
+            if let (ConsumedKey::FallbackKey { public_key, .. }, Some(now)) = (consumed_key, now) {
+                self.fallback_keys.mark_as_used(&public_key, now);
+            }
 
-            Ok(InboundCreationResult { session, plaintext })
+            Ok(InboundCreationResult { session, plaintext, consumed_key })
         }
     }
 
@@ -279,12 +787,26 @@ impl Account {
     ///
     /// Our one-time key store inside the [`Account`] has a limited amount of
     /// places for one-time keys, If we try to generate new ones while the store
-    /// is completely populated, the oldest one-time keys will get discarded
-    /// to make place for new ones.
+    /// is completely populated, existing one-time keys will get discarded to
+    /// make place for new ones. Which keys get discarded, if any, is decided by
+    /// the [`OneTimeKeyEvictionStrategy`] of the [`OneTimeKeyConfig`]. If no
+    /// keys may be discarded, fewer keys than requested will be created.
     pub fn generate_one_time_keys(&mut self, count: usize) -> OneTimeKeyGenerationResult {
         self.one_time_keys.generate(count)
     }
 
+    /// Generate new one-time keys using the given random number generator.
+    ///
+    /// This behaves exactly like [`Account::generate_one_time_keys()`] and is
+    /// only useful to create deterministic test vectors.
+    pub fn generate_one_time_keys_with_rng(
+        &mut self,
+        count: usize,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> OneTimeKeyGenerationResult {
+        self.one_time_keys.generate_with_rng(count, rng)
+    }
+
     pub fn stored_one_time_key_count(&self) -> usize {
         self.one_time_keys.private_keys.len()
     }
@@ -306,7 +828,25 @@ impl Account {
     /// The fallback key will be used by other users to establish a `Session` if
     /// all the one-time keys on the server have been used up.
     pub fn generate_fallback_key(&mut self) {
-        self.fallback_keys.generate_fallback_key()
+        self.fallback_keys.generate_fallback_key(None)
+    }
+
+    /// Generate a single new fallback key using the given random number
+    /// generator.
+    ///
+    /// This behaves exactly like [`Account::generate_fallback_key()`] and is
+    /// only useful to create deterministic test vectors.
+    pub fn generate_fallback_key_with_rng(&mut self, rng: &mut (impl RngCore + CryptoRng)) {
+        self.fallback_keys.generate_fallback_key_with_rng(None, rng)
+    }
+
+    /// Generate a single new fallback key, `now` being the current time.
+    ///
+    /// This behaves exactly like [`Account::generate_fallback_key`], but the
+    /// creation time of the fallback key gets recorded, which lets the
+    /// [`FallbackKeyPolicy`] decide when the key should be rotated.
+    pub fn generate_fallback_key_at(&mut self, now: SystemTime) {
+        self.fallback_keys.generate_fallback_key(Some(now))
     }
 
     /// Get the currently unpublished fallback key.
@@ -324,16 +864,176 @@ impl Account {
         }
     }
 
-    /// The `Account` stores at most two private parts of the fallback key. This
-    /// method lets us forget the previously used fallback key.
+    /// The `Account` stores the private part of the current fallback key and
+    /// of a limited number of previous fallback keys. This method lets us
+    /// forget all the previously used fallback keys.
     pub fn forget_fallback_key(&mut self) -> bool {
-        self.fallback_keys.forget_previous_fallback_key().is_some()
+        self.fallback_keys.forget_previous_fallback_keys()
+    }
+
+    /// Get the maximal number of previous fallback keys the `Account` keeps
+    /// around, defaults to 1.
+    pub fn max_previous_fallback_keys(&self) -> usize {
+        self.fallback_keys.max_previous_fallback_keys
+    }
+
+    /// Set the maximal number of previous fallback keys the `Account` keeps
+    /// around. If the `Account` currently holds more previous fallback keys,
+    /// the oldest ones are forgotten.
+    pub fn set_max_previous_fallback_keys(&mut self, max_previous_fallback_keys: usize) {
+        self.fallback_keys.set_max_previous_fallback_keys(max_previous_fallback_keys)
+    }
+
+    /// Should the fallback key be rotated at the time `now`, according to the
+    /// given [`FallbackKeyPolicy`].
+    ///
+    /// This is the case if we don't have a fallback key at all, if the
+    /// fallback key is older than the maximal allowed age, or if it was first
+    /// used long enough ago. Only the timestamps recorded by
+    /// [`Account::generate_fallback_key_at`] and
+    /// [`Account::create_inbound_session_at`] are taken into account.
+    pub fn should_rotate_fallback_key(&self, policy: &FallbackKeyPolicy, now: SystemTime) -> bool {
+        self.fallback_keys.should_rotate(policy, now)
+    }
+
+    /// Can any of the previous fallback keys be forgotten at the time `now`,
+    /// according to the given [`FallbackKeyPolicy`].
+    ///
+    /// A previous fallback key can be forgotten once the fallback key that
+    /// replaced it is older than the lifetime of previous fallback keys. Keys
+    /// which were replaced using [`Account::generate_fallback_key`] don't know
+    /// when they were replaced and are never considered to be expired.
+    pub fn can_forget_previous_fallback_key(
+        &self,
+        policy: &FallbackKeyPolicy,
+        now: SystemTime,
+    ) -> bool {
+        self.fallback_keys.can_forget_previous(policy, now)
+    }
+
+    /// Forget the previous fallback keys which can be forgotten at the time
+    /// `now`, according to the given [`FallbackKeyPolicy`].
+    ///
+    /// Returns the number of forgotten fallback keys.
+    pub fn forget_expired_fallback_keys(
+        &mut self,
+        policy: &FallbackKeyPolicy,
+        now: SystemTime,
+    ) -> usize {
+        self.fallback_keys.forget_expired(policy, now)
+    }
+
+    /// Generate a new signed pre-key, replacing the current one.
+    ///
+    /// The previous signed pre-key is kept around, since pre-key messages
+    /// which used it might still be in flight. Use
+    /// [`Account::forget_expired_signed_pre_keys()`] or
+    /// [`Account::forget_previous_signed_pre_keys()`] to remove it. At most
+    /// [`Account::max_previous_signed_pre_keys()`] previous signed pre-keys
+    /// are kept, the oldest ones are forgotten first.
+    pub fn generate_signed_pre_key(&mut self) -> PublicSignedPreKey {
+        self.signed_pre_keys.generate(&self.signing_key, None)
+    }
+
+    /// Generate a new signed pre-key using the given random number generator.
+    ///
+    /// This behaves exactly like [`Account::generate_signed_pre_key()`] and is
+    /// only useful to create deterministic test vectors.
+    pub fn generate_signed_pre_key_with_rng(
+        &mut self,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> PublicSignedPreKey {
+        self.signed_pre_keys.generate_with_rng(&self.signing_key, None, rng)
+    }
+
+    /// Generate a new signed pre-key, `now` being the current time.
+    ///
+    /// This behaves exactly like [`Account::generate_signed_pre_key()`], but
+    /// the creation time of the key gets recorded, which lets the
+    /// [`SignedPreKeyPolicy`] decide when the key should be rotated and when
+    /// it can be forgotten after it has been replaced.
+    pub fn generate_signed_pre_key_at(&mut self, now: SystemTime) -> PublicSignedPreKey {
+        self.signed_pre_keys.generate(&self.signing_key, Some(now))
+    }
+
+    /// Get the current signed pre-key, if we have one.
+    pub fn signed_pre_key(&self) -> Option<PublicSignedPreKey> {
+        self.signed_pre_keys.public_key(&self.signing_key)
+    }
+
+    /// Should the signed pre-key be replaced with a new one at the time `now`,
+    /// according to the given [`SignedPreKeyPolicy`].
+    ///
+    /// This is always the case if there is no signed pre-key. Keys which were
+    /// created without a timestamp are never considered to be too old.
+    pub fn should_rotate_signed_pre_key(
+        &self,
+        policy: &SignedPreKeyPolicy,
+        now: SystemTime,
+    ) -> bool {
+        self.signed_pre_keys.should_rotate(policy, now)
+    }
+
+    /// Forget the previous signed pre-keys whose retention period, according
+    /// to the given [`SignedPreKeyPolicy`], is over at the time `now`.
+    ///
+    /// Returns the number of signed pre-keys that were forgotten.
+    pub fn forget_expired_signed_pre_keys(
+        &mut self,
+        policy: &SignedPreKeyPolicy,
+        now: SystemTime,
+    ) -> usize {
+        self.signed_pre_keys.forget_expired(policy, now)
     }
 
-    /// Mark all currently unpublished one-time and fallback keys as published.
+    /// Forget all previous signed pre-keys, regardless of their age.
+    ///
+    /// Returns true if any keys were forgotten.
+    pub fn forget_previous_signed_pre_keys(&mut self) -> bool {
+        self.signed_pre_keys.forget_previous()
+    }
+
+    /// Get the maximal number of previous signed pre-keys the `Account` keeps
+    /// around, defaults to 5.
+    pub fn max_previous_signed_pre_keys(&self) -> usize {
+        self.signed_pre_keys.max_previous_signed_pre_keys()
+    }
+
+    /// Set the maximal number of previous signed pre-keys the `Account` keeps
+    /// around. If the `Account` currently holds more previous signed pre-keys,
+    /// the oldest ones are forgotten.
+    pub fn set_max_previous_signed_pre_keys(&mut self, max_previous_signed_pre_keys: usize) {
+        self.signed_pre_keys.set_max_previous_signed_pre_keys(max_previous_signed_pre_keys)
+    }
+
+    /// Get the currently unpublished one-time, fallback and signed pre-keys,
+    /// signed with our Ed25519 fingerprint key, ready to be uploaded using the
+    /// `/keys/upload` endpoint.
+    ///
+    /// After the keys have been successfully uploaded, they need to be marked
+    /// as published using the `mark_keys_as_published()` method.
+    pub fn signed_keys(&self, user_id: &str, device_id: &str) -> SignedKeys {
+        let fallback_key = self
+            .fallback_keys
+            .unpublished_fallback_key()
+            .map(|key| (key.key_id(), key.public_key()));
+
+        signed_keys::sign_keys(
+            &self.signing_key,
+            user_id,
+            device_id,
+            self.one_time_keys.unpublished_public_keys.iter(),
+            fallback_key,
+            self.signed_pre_keys.unpublished_public_key(&self.signing_key),
+        )
+    }
+
+    /// Mark all currently unpublished one-time, fallback and signed pre-keys as
+    /// published.
     pub fn mark_keys_as_published(&mut self) {
         self.one_time_keys.mark_as_published();
         self.fallback_keys.mark_as_published();
+        self.signed_pre_keys.mark_as_published();
     }
 
     /// Convert the account into a struct which implements [`serde::Serialize`]
@@ -344,6 +1044,7 @@ impl Account {
             diffie_hellman_key: self.diffie_hellman_key.clone().into(),
             one_time_keys: self.one_time_keys.clone().into(),
             fallback_keys: self.fallback_keys.clone(),
+            signed_pre_keys: self.signed_pre_keys.clone(),
         }
     }
 
@@ -352,21 +1053,81 @@ impl Account {
         pickle.into()
     }
 
+    /// Encrypt the account so it can be uploaded to the server as a
+    /// [dehydrated device].
+    ///
+    /// The returned [`DehydratedDevice`] contains the encrypted account and
+    /// the nonce that was used to encrypt it, both are needed to restore the
+    /// account using [`Account::rehydrate`].
+    ///
+    /// The dehydrated device contains the full state of the account, but the
+    /// format is specific to vodozemac, other implementations of
+    /// [dehydrated device]s can't rehydrate it.
+    ///
+    /// [dehydrated device]: https://github.com/matrix-org/matrix-spec-proposals/pull/3814
+    pub fn dehydrate(
+        &self,
+        key: &DehydratedDeviceKey,
+    ) -> Result<DehydratedDevice, DehydratedDeviceError> {
+        dehydrated_device::dehydrate(self, key)
+    }
+
+    /// Restore an [`Account`] from a dehydrated device that was created using
+    /// [`Account::dehydrate`].
+    pub fn rehydrate(
+        ciphertext: &str,
+        nonce: &str,
+        key: &DehydratedDeviceKey,
+    ) -> Result<Self, DehydratedDeviceError> {
+        dehydrated_device::rehydrate(ciphertext, nonce, key)
+    }
+
     /// Create an [`Account`] object by unpickling an account pickle in libolm
     /// legacy pickle format.
     ///
     /// Such pickles are encrypted and need to first be decrypted using
     /// `pickle_key`.
+    ///
+    /// Pickles using version 2, 3 or 4 of the libolm account pickle format are
+    /// supported. Version 1 pickles are rejected with
+    /// [`LibolmPickleError::LegacyAccountPickle`], just like libolm does.
+    ///
+    /// [`LibolmPickleError::LegacyAccountPickle`]: crate::LibolmPickleError::LegacyAccountPickle
     #[cfg(feature = "libolm-compat")]
     pub fn from_libolm_pickle(
         pickle: &str,
         pickle_key: &[u8],
     ) -> Result<Self, crate::LibolmPickleError> {
-        use self::libolm::Pickle;
+        use self::libolm::{Pickle, PICKLE_VERSIONS};
         use crate::utilities::unpickle_libolm;
 
-        const PICKLE_VERSION: u32 = 4;
-        unpickle_libolm::<Pickle, _>(pickle, pickle_key, PICKLE_VERSION)
+        unpickle_libolm::<Pickle, _>(pickle, pickle_key, PICKLE_VERSIONS)
+    }
+
+    /// Pickle an [`Account`] into a libolm pickle format.
+    ///
+    /// This pickle can be restored using the [`Account::from_libolm_pickle`]
+    /// method, or can be used in the [`libolm`] C library.
+    ///
+    /// The pickle will be encrypted using the pickle key.
+    ///
+    /// *Note*: This method might be lossy, the vodozemac [`Account`] has the
+    /// ability to hold more one-time keys compared to the [`libolm`]
+    /// variant.
+    ///
+    /// ⚠️  ***Security Warning***: The pickle key will get expanded into both
+    /// an AES key and an IV in a deterministic manner. If the same pickle
+    /// key is reused, this will lead to IV reuse. To prevent this, users
+    /// have to ensure that they always use a globally (probabilistically)
+    /// unique pickle key.
+    ///
+    /// [`libolm`]: https://gitlab.matrix.org/matrix-org/olm/
+    #[cfg(feature = "libolm-compat")]
+    pub fn to_libolm_pickle(&self, pickle_key: &[u8]) -> Result<String, crate::LibolmPickleError> {
+        use self::libolm::Pickle;
+        use crate::utilities::pickle_libolm;
+
+        pickle_libolm::<Pickle>(self.into(), pickle_key)
     }
 
     #[cfg(all(any(fuzzing, test), feature = "libolm-compat"))]
@@ -398,6 +1159,8 @@ pub struct AccountPickle {
     diffie_hellman_key: Curve25519KeypairPickle,
     one_time_keys: OneTimeKeysPickle,
     fallback_keys: FallbackKeys,
+    #[serde(default)]
+    signed_pre_keys: SignedPreKeys,
 }
 
 /// A format suitable for serialization which implements [`serde::Serialize`]
@@ -426,27 +1189,38 @@ impl From<AccountPickle> for Account {
             diffie_hellman_key: pickle.diffie_hellman_key.into(),
             one_time_keys: pickle.one_time_keys.into(),
             fallback_keys: pickle.fallback_keys,
+            signed_pre_keys: pickle.signed_pre_keys,
         }
     }
 }
 
 #[cfg(feature = "libolm-compat")]
 mod libolm {
-    use matrix_pickle::{Decode, DecodeError};
+    use matrix_pickle::{Decode, DecodeError, Encode, EncodeError};
     use zeroize::Zeroize;
 
     use super::{
         fallback_keys::{FallbackKey, FallbackKeys},
         one_time_keys::OneTimeKeys,
+        signed_pre_keys::SignedPreKeys,
         Account,
     };
     use crate::{
         types::{Curve25519Keypair, Curve25519SecretKey},
         utilities::LibolmEd25519Keypair,
-        Ed25519Keypair, KeyId,
+        Curve25519PublicKey, Ed25519Keypair, KeyId,
     };
 
-    #[derive(Debug, Zeroize, Decode)]
+    pub(super) const PICKLE_VERSION: u32 = 4;
+
+    /// The versions of the account pickle we know how to decode, version 1 is
+    /// decoded only to be able to reject it with a proper error.
+    pub(super) const PICKLE_VERSIONS: &[u32] = &[PICKLE_VERSION, 3, 2, 1];
+
+    /// The maximum number of one-time keys a libolm account can hold.
+    const MAX_ONE_TIME_KEYS: usize = 100;
+
+    #[derive(Debug, Zeroize, Encode, Decode)]
     #[zeroize(drop)]
     struct OneTimeKey {
         key_id: u32,
@@ -457,10 +1231,21 @@ mod libolm {
 
     impl From<&OneTimeKey> for FallbackKey {
         fn from(key: &OneTimeKey) -> Self {
-            FallbackKey {
-                key_id: KeyId(key.key_id.into()),
-                key: Curve25519SecretKey::from_slice(&key.private_key),
-                published: key.published,
+            FallbackKey::from_parts(
+                KeyId(key.key_id.into()),
+                Curve25519SecretKey::from_slice(&key.private_key),
+                key.published,
+            )
+        }
+    }
+
+    impl From<&FallbackKey> for OneTimeKey {
+        fn from(key: &FallbackKey) -> Self {
+            OneTimeKey {
+                key_id: key.key_id.0 as u32,
+                published: key.published(),
+                public_key: key.public_key().to_bytes(),
+                private_key: Box::new(key.secret_key().to_bytes()),
             }
         }
     }
@@ -491,7 +1276,54 @@ mod libolm {
         }
     }
 
-    #[derive(Zeroize, Decode)]
+    impl FallbackKeysArray {
+        /// Decode the fallback keys of a version 3 pickle.
+        ///
+        /// Version 3 pickles always contain two fallback keys, the `published`
+        /// flag of the keys tells us which of them are actually present.
+        fn decode_v3(reader: &mut impl std::io::Read) -> Result<Self, DecodeError> {
+            let fallback_key = OneTimeKey::decode(reader)?;
+            let previous_fallback_key = OneTimeKey::decode(reader)?;
+
+            let (fallback_key, previous_fallback_key) =
+                match (fallback_key.published, previous_fallback_key.published) {
+                    (true, true) => (Some(fallback_key), Some(previous_fallback_key)),
+                    (true, false) => (Some(fallback_key), None),
+                    (false, _) => (None, None),
+                };
+
+            Ok(Self { fallback_key, previous_fallback_key })
+        }
+    }
+
+    impl Encode for FallbackKeysArray {
+        fn encode(&self, writer: &mut impl std::io::Write) -> Result<usize, EncodeError> {
+            let ret = match (&self.fallback_key, &self.previous_fallback_key) {
+                // libolm can't store a previous fallback key without a current
+                // one, the single key of the array is always read as the
+                // current fallback key. Leave the previous key out instead of
+                // turning it into the current one.
+                (None, _) => 0u8.encode(writer)?,
+                (Some(key), None) => {
+                    let mut ret = 1u8.encode(writer)?;
+                    ret += key.encode(writer)?;
+
+                    ret
+                }
+                (Some(key), Some(previous_key)) => {
+                    let mut ret = 2u8.encode(writer)?;
+                    ret += key.encode(writer)?;
+                    ret += previous_key.encode(writer)?;
+
+                    ret
+                }
+            };
+
+            Ok(ret)
+        }
+    }
+
+    #[derive(Zeroize, Encode)]
     #[zeroize(drop)]
     pub(super) struct Pickle {
         version: u32,
@@ -503,34 +1335,109 @@ mod libolm {
         next_key_id: u32,
     }
 
-    impl TryFrom<Pickle> for Account {
-        type Error = crate::LibolmPickleError;
+    impl Decode for Pickle {
+        fn decode(reader: &mut impl std::io::Read) -> Result<Self, DecodeError> {
+            let version = u32::decode(reader)?;
+            let ed25519_keypair = LibolmEd25519Keypair::decode(reader)?;
+            let public_curve25519_key = <[u8; 32]>::decode(reader)?;
+            let private_curve25519_key = <Box<[u8; 32]>>::decode(reader)?;
+            let one_time_keys = Vec::<OneTimeKey>::decode(reader)?;
+
+            let fallback_keys = match version {
+                // Fallback keys were introduced in version 3 of the pickle.
+                1 | 2 => FallbackKeysArray { fallback_key: None, previous_fallback_key: None },
+                3 => FallbackKeysArray::decode_v3(reader)?,
+                _ => FallbackKeysArray::decode(reader)?,
+            };
 
-        fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
-            let mut one_time_keys = OneTimeKeys::new();
+            let next_key_id = u32::decode(reader)?;
 
-            for key in &pickle.one_time_keys {
-                let secret_key = Curve25519SecretKey::from_slice(&key.private_key);
-                let key_id = KeyId(key.key_id.into());
-                one_time_keys.insert_secret_key(key_id, secret_key, key.published);
-            }
+            Ok(Self {
+                version,
+                ed25519_keypair,
+                public_curve25519_key,
+                private_curve25519_key,
+                one_time_keys,
+                fallback_keys,
+                next_key_id,
+            })
+        }
+    }
+
+    impl From<&Account> for Pickle {
+        fn from(account: &Account) -> Self {
+            let one_time_keys: Vec<_> = account
+                .one_time_keys
+                .private_keys
+                .iter()
+                // libolm can only hold a limited amount of one-time keys, keep
+                // the newest ones.
+                .rev()
+                .take(MAX_ONE_TIME_KEYS)
+                .rev()
+                .map(|(key_id, secret_key)| OneTimeKey {
+                    key_id: key_id.0 as u32,
+                    published: !account.one_time_keys.unpublished_public_keys.contains_key(key_id),
+                    public_key: Curve25519PublicKey::from(secret_key).to_bytes(),
+                    private_key: Box::new(secret_key.to_bytes()),
+                })
+                .collect();
+
+            let fallback_keys = FallbackKeysArray {
+                fallback_key: account.fallback_keys.fallback_key.as_ref().map(|f| f.into()),
+                previous_fallback_key: account
+                    .fallback_keys
+                    .previous_fallback_key()
+                    .map(|f| f.into()),
+            };
+
+            Self {
+                version: PICKLE_VERSION,
+                ed25519_keypair: LibolmEd25519Keypair {
+                    public_key: account.signing_key.public_key().as_bytes().to_owned(),
+                    private_key: account.signing_key.expanded_secret_key(),
+                },
+                public_curve25519_key: account.diffie_hellman_key.public_key().to_bytes(),
+                private_curve25519_key: Box::new(
+                    account.diffie_hellman_key.secret_key().to_bytes(),
+                ),
+                one_time_keys,
+                fallback_keys,
+                next_key_id: account.one_time_keys.next_key_id as u32,
+            }
+        }
+    }
+
+    impl TryFrom<Pickle> for Account {
+        type Error = crate::LibolmPickleError;
+
+        fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
+            // Version 1 pickles stored a truncated Ed25519 key, the identity of
+            // such accounts can't be restored.
+            if pickle.version == 1 {
+                return Err(crate::LibolmPickleError::LegacyAccountPickle);
+            }
+
+            let mut one_time_keys = OneTimeKeys::new();
+
+            for key in &pickle.one_time_keys {
+                let secret_key = Curve25519SecretKey::from_slice(&key.private_key);
+                let key_id = KeyId(key.key_id.into());
+                one_time_keys.insert_secret_key(key_id, secret_key, key.published);
+            }
 
             one_time_keys.next_key_id = pickle.next_key_id.into();
 
-            let fallback_keys = FallbackKeys {
-                key_id: pickle
+            let fallback_keys = FallbackKeys::from_parts(
+                pickle
                     .fallback_keys
                     .fallback_key
                     .as_ref()
                     .map(|k| k.key_id.wrapping_add(1))
                     .unwrap_or(0) as u64,
-                fallback_key: pickle.fallback_keys.fallback_key.as_ref().map(|k| k.into()),
-                previous_fallback_key: pickle
-                    .fallback_keys
-                    .previous_fallback_key
-                    .as_ref()
-                    .map(|k| k.into()),
-            };
+                pickle.fallback_keys.fallback_key.as_ref().map(|k| k.into()),
+                pickle.fallback_keys.previous_fallback_key.as_ref().map(|k| k.into()),
+            );
 
             Ok(Self {
                 signing_key: Ed25519Keypair::from_expanded_key(
@@ -541,6 +1448,7 @@ mod libolm {
                 ),
                 one_time_keys,
                 fallback_keys,
+                signed_pre_keys: SignedPreKeys::new(),
             })
         }
     }
@@ -551,7 +1459,9 @@ mod test {
     use anyhow::{bail, Context, Result};
     use olm_rs::{account::OlmAccount, session::OlmMessage as LibolmOlmMessage};
 
-    use super::{Account, InboundCreationResult, SessionConfig, SessionCreationError};
+    use super::{
+        Account, IdentityKeys, InboundCreationResult, SessionConfig, SessionCreationError,
+    };
     use crate::{
         cipher::Mac,
         olm::{
@@ -628,6 +1538,570 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn outbound_session_from_claimed_key() -> Result<()> {
+        use assert_matches::assert_matches;
+        use serde_json::json;
+
+        use super::{ClaimedKeyError, OutboundCreationResult};
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        let bob_identity_keys = bob.identity_keys();
+
+        bob.generate_one_time_keys(1);
+        bob.generate_fallback_key();
+
+        let keys = bob.signed_keys("@bob:example.org", "BOBDEVICE");
+        let one_time_key = serde_json::to_value(
+            keys.one_time_keys.values().next().context("Missing signed one-time key")?,
+        )?;
+        let fallback_key = serde_json::to_value(
+            keys.fallback_keys.values().next().context("Missing fallback key")?,
+        )?;
+
+        let OutboundCreationResult { mut session, used_fallback_key } = alice
+            .create_outbound_session_from_claimed_key(
+                SessionConfig::version_2(),
+                "@bob:example.org",
+                "BOBDEVICE",
+                &bob_identity_keys,
+                &one_time_key,
+            )?;
+        assert!(!used_fallback_key);
+
+        let message = "It's a secret to everybody";
+        let message = if let OlmMessage::PreKey(m) = session.encrypt(message) {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+        let InboundCreationResult { plaintext, .. } =
+            bob.create_inbound_session(alice.curve25519_key(), &message)?;
+        assert_eq!(plaintext, b"It's a secret to everybody");
+
+        let result = alice.create_outbound_session_from_claimed_key(
+            SessionConfig::version_2(),
+            "@bob:example.org",
+            "BOBDEVICE",
+            &bob_identity_keys,
+            &fallback_key,
+        )?;
+        assert!(result.used_fallback_key);
+
+        // Fields the key object doesn't know about, or an explicit fallback
+        // flag, are covered by the signature and need to be kept for the
+        // verification.
+        let mut extended_key = json!({
+            "key": one_time_key["key"],
+            "fallback": false,
+            "custom_field": "value",
+        });
+        bob.sign_json("@bob:example.org", "BOBDEVICE", &mut extended_key)?;
+        let result = alice.create_outbound_session_from_claimed_key(
+            SessionConfig::version_2(),
+            "@bob:example.org",
+            "BOBDEVICE",
+            &bob_identity_keys,
+            &extended_key,
+        )?;
+        assert!(!result.used_fallback_key);
+
+        // The key needs to be signed by the given device.
+        assert_matches!(
+            alice
+                .create_outbound_session_from_claimed_key(
+                    SessionConfig::version_2(),
+                    "@bob:example.org",
+                    "OTHERDEVICE",
+                    &bob_identity_keys,
+                    &one_time_key,
+                )
+                .err(),
+            Some(ClaimedKeyError::MissingSignature)
+        );
+
+        // The key needs to be signed by the Ed25519 key of the device.
+        let mallory_identity_keys = IdentityKeys {
+            ed25519: Account::new().ed25519_key(),
+            curve25519: bob_identity_keys.curve25519,
+        };
+        assert_matches!(
+            alice
+                .create_outbound_session_from_claimed_key(
+                    SessionConfig::version_2(),
+                    "@bob:example.org",
+                    "BOBDEVICE",
+                    &mallory_identity_keys,
+                    &one_time_key,
+                )
+                .err(),
+            Some(ClaimedKeyError::InvalidSignature(_))
+        );
+
+        // Unsetting the fallback flag invalidates the signature.
+        let mut tampered_key = fallback_key.clone();
+        tampered_key["fallback"] = false.into();
+        assert_matches!(
+            alice
+                .create_outbound_session_from_claimed_key(
+                    SessionConfig::version_2(),
+                    "@bob:example.org",
+                    "BOBDEVICE",
+                    &bob_identity_keys,
+                    &tampered_key,
+                )
+                .err(),
+            Some(ClaimedKeyError::InvalidSignature(_))
+        );
+
+        // Replacing the key invalidates the signature as well.
+        let mut tampered_key = one_time_key.clone();
+        tampered_key["key"] = Account::new().curve25519_key().to_base64().into();
+        assert_matches!(
+            alice
+                .create_outbound_session_from_claimed_key(
+                    SessionConfig::version_2(),
+                    "@bob:example.org",
+                    "BOBDEVICE",
+                    &bob_identity_keys,
+                    &tampered_key,
+                )
+                .err(),
+            Some(ClaimedKeyError::InvalidSignature(_))
+        );
+
+        // The key object needs to contain a signed key.
+        let mut malformed_key = json!({ "key": 1 });
+        bob.sign_json("@bob:example.org", "BOBDEVICE", &mut malformed_key)?;
+        assert_matches!(
+            alice
+                .create_outbound_session_from_claimed_key(
+                    SessionConfig::version_2(),
+                    "@bob:example.org",
+                    "BOBDEVICE",
+                    &bob_identity_keys,
+                    &malformed_key,
+                )
+                .err(),
+            Some(ClaimedKeyError::MalformedKey(_))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn consumed_key_reporting() -> Result<()> {
+        use super::ConsumedKey;
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+
+        bob.generate_one_time_keys(1);
+        bob.generate_fallback_key();
+
+        let (otk_id, one_time_key) =
+            bob.one_time_keys().into_iter().next().context("Missing one-time key")?;
+        let (fallback_id, fallback_key) =
+            bob.fallback_key().into_iter().next().context("Missing fallback key")?;
+        bob.mark_keys_as_published();
+
+        let create_session = |bob: &mut Account, one_time_key| -> Result<ConsumedKey> {
+            let mut session = alice.create_outbound_session(
+                SessionConfig::version_2(),
+                bob.curve25519_key(),
+                one_time_key,
+            );
+
+            if let OlmMessage::PreKey(m) = session.encrypt("It's a secret to everybody") {
+                Ok(bob.create_inbound_session(alice.curve25519_key(), &m)?.consumed_key)
+            } else {
+                bail!("Invalid message type")
+            }
+        };
+
+        let consumed_key = create_session(&mut bob, one_time_key)?;
+        assert_eq!(
+            consumed_key,
+            ConsumedKey::OneTimeKey { key_id: otk_id, public_key: one_time_key }
+        );
+        assert!(!consumed_key.is_fallback_key());
+        assert_eq!(consumed_key.key_id(), otk_id);
+        assert_eq!(consumed_key.public_key(), one_time_key);
+
+        let consumed_key = create_session(&mut bob, fallback_key)?;
+        assert_eq!(
+            consumed_key,
+            ConsumedKey::FallbackKey {
+                key_id: fallback_id,
+                public_key: fallback_key,
+                previous: false
+            }
+        );
+        assert!(consumed_key.is_fallback_key());
+
+        bob.generate_fallback_key();
+
+        let consumed_key = create_session(&mut bob, fallback_key)?;
+        assert_eq!(
+            consumed_key,
+            ConsumedKey::FallbackKey {
+                key_id: fallback_id,
+                public_key: fallback_key,
+                previous: true
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn deterministic_account() -> Result<()> {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        use crate::types::KeyId;
+
+        let create_account = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut account = Account::new_with_rng(&mut rng);
+            account.generate_one_time_keys_with_rng(2, &mut rng);
+            account.generate_fallback_key_with_rng(&mut rng);
+            account.generate_signed_pre_key_with_rng(&mut rng);
+
+            account
+        };
+
+        let alice = create_account(1);
+        let bob = create_account(2);
+        let other_bob = create_account(2);
+
+        assert_eq!(bob.identity_keys(), other_bob.identity_keys());
+        assert_eq!(bob.one_time_keys(), other_bob.one_time_keys());
+        assert_eq!(bob.fallback_key(), other_bob.fallback_key());
+        assert_eq!(bob.signed_pre_key(), other_bob.signed_pre_key());
+        assert_ne!(alice.identity_keys(), bob.identity_keys());
+
+        let one_time_key = bob.one_time_keys()[&KeyId(0)];
+
+        let create_session = || {
+            alice.create_outbound_session_with_rng(
+                SessionConfig::version_2(),
+                bob.curve25519_key(),
+                one_time_key,
+                &mut StdRng::seed_from_u64(3),
+            )
+        };
+
+        let mut session = create_session();
+        let mut other_session = create_session();
+        assert_eq!(session.session_id(), other_session.session_id());
+
+        let mut rng = StdRng::seed_from_u64(4);
+        let message = session.encrypt_with_rng("It's a secret to everybody", &mut rng);
+        let mut rng = StdRng::seed_from_u64(4);
+        let other_message = other_session.encrypt_with_rng("It's a secret to everybody", &mut rng);
+        assert_eq!(message, other_message);
+
+        Ok(())
+    }
+
+    #[test]
+    fn account_from_seed() -> Result<()> {
+        let seed = [7u8; 32];
+
+        let mut account = Account::from_seed(&seed);
+        let mut other_account = Account::from_seed(&seed);
+
+        assert_eq!(account.identity_keys(), other_account.identity_keys());
+        assert_ne!(account.identity_keys(), Account::from_seed(&[8u8; 32]).identity_keys());
+
+        // Make sure the derivation doesn't change.
+        assert_eq!(
+            account.ed25519_key().to_base64(),
+            "rzbd75cHf/Mwd4bNO19LAPhqprXSBtoNkVEw3fNW1N4"
+        );
+        assert_eq!(
+            account.curve25519_key().to_base64(),
+            "Ky74hUk0pPdqf3yJ7/91u4t7h7p4veXGF3+wOUPk1g4"
+        );
+
+        // The signing key works as expected.
+        let signature = account.sign("It's a secret to everybody");
+        other_account.ed25519_key().verify(b"It's a secret to everybody", &signature)?;
+
+        // One-time and fallback keys are not derived from the seed.
+        account.generate_one_time_keys(1);
+        other_account.generate_one_time_keys(1);
+        assert_ne!(account.one_time_keys(), other_account.one_time_keys());
+
+        account.generate_fallback_key();
+        other_account.generate_fallback_key();
+        assert_ne!(account.fallback_key(), other_account.fallback_key());
+
+        Ok(())
+    }
+
+    #[test]
+    fn signed_pre_key_sessions() -> Result<()> {
+        use super::ConsumedKey;
+        use crate::olm::OneTimeKeyKind;
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+
+        assert!(bob.signed_pre_key().is_none());
+        let signed_pre_key = bob.generate_signed_pre_key();
+        assert_eq!(bob.signed_pre_key(), Some(signed_pre_key));
+
+        // A signed pre-key which wasn't signed by Bob's identity key is rejected.
+        let malory_keys = IdentityKeys { ed25519: alice.ed25519_key(), ..bob.identity_keys() };
+        alice
+            .create_outbound_session_from_signed_pre_key(
+                SessionConfig::version_2(),
+                &malory_keys,
+                &signed_pre_key,
+            )
+            .expect_err("The signed pre-key shouldn't be valid for another Ed25519 key");
+
+        // The signed pre-key can be used multiple times.
+        for _ in 0..2 {
+            let mut alice_session = alice.create_outbound_session_from_signed_pre_key(
+                SessionConfig::version_2(),
+                &bob.identity_keys(),
+                &signed_pre_key,
+            )?;
+            assert_eq!(
+                alice_session.session_keys().one_time_key_kind(),
+                OneTimeKeyKind::SignedPreKey
+            );
+
+            let message = if let OlmMessage::PreKey(m) = alice_session.encrypt("It's a secret") {
+                m
+            } else {
+                bail!("Invalid message type");
+            };
+
+            let InboundCreationResult { session, plaintext, consumed_key } =
+                bob.create_inbound_session(alice.curve25519_key(), &message)?;
+
+            assert_eq!(plaintext, b"It's a secret");
+            assert_eq!(
+                consumed_key,
+                ConsumedKey::SignedPreKey {
+                    key_id: signed_pre_key.key_id,
+                    public_key: signed_pre_key.public_key
+                }
+            );
+            assert_eq!(session.session_keys().one_time_key_kind(), OneTimeKeyKind::SignedPreKey);
+            assert_eq!(session.session_keys(), alice_session.session_keys());
+        }
+
+        // Signed pre-keys survive a pickle roundtrip.
+        let bob = Account::from_pickle(bob.pickle());
+        assert_eq!(bob.signed_pre_key(), Some(signed_pre_key));
+
+        Ok(())
+    }
+
+    #[test]
+    fn pickle_without_signed_pre_keys() -> Result<()> {
+        let mut account = Account::new();
+        account.generate_one_time_keys(1);
+
+        // Turn the pickle into one created before signed pre-keys, the one-time
+        // key config and multiple previous fallback keys existed.
+        let mut pickle = serde_json::to_value(account.pickle())?;
+        let object = pickle.as_object_mut().context("The pickle should be an object")?;
+        object.remove("signed_pre_keys");
+        object.insert(
+            "fallback_keys".to_owned(),
+            serde_json::json!({ "key_id": 0, "fallback_key": null, "previous_fallback_key": null }),
+        );
+
+        let one_time_keys = object
+            .get_mut("one_time_keys")
+            .and_then(serde_json::Value::as_object_mut)
+            .context("The one-time keys should be an object")?;
+        one_time_keys.remove("config");
+        one_time_keys.remove("consumed_keys");
+
+        let unpickled = Account::from_pickle(serde_json::from_value(pickle)?);
+
+        assert_eq!(unpickled.one_time_keys(), account.one_time_keys());
+        assert!(unpickled.signed_pre_key().is_none());
+        assert_eq!(unpickled.max_previous_signed_pre_keys(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_pre_key_messages() -> Result<()> {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_fallback_key();
+
+        let fallback_key = *bob.fallback_key().values().next().context("Missing fallback key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            fallback_key,
+        );
+
+        let message = if let OlmMessage::PreKey(m) = alice_session.encrypt("It's a secret") {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+
+        let mut sessions = Vec::new();
+
+        assert!(Account::find_matching_session(&message, &sessions).is_none());
+        sessions.push(bob.create_inbound_session(alice.curve25519_key(), &message)?.session);
+
+        // Even without checking the existing sessions, the duplicate message
+        // doesn't create a twin using the fallback key.
+        assert!(matches!(
+            bob.create_inbound_session(alice.curve25519_key(), &message),
+            Err(SessionCreationError::DuplicatePreKeyMessage(session_id))
+                if session_id == message.session_id()
+        ));
+
+        // The duplicate message is matched to the existing session instead of
+        // creating a twin using the fallback key.
+        let session = Account::find_matching_session(&message, &mut sessions)
+            .context("The pre-key message should match the existing session")?;
+        assert_eq!(session.session_id(), message.session_id());
+
+        // Further pre-key messages of the same session match as well.
+        let message = if let OlmMessage::PreKey(m) = alice_session.encrypt("Another secret") {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+
+        let session = Account::find_matching_session(&message, &mut sessions)
+            .context("The pre-key message should match the existing session")?;
+        assert_eq!(session.decrypt(&OlmMessage::PreKey(message))?, b"Another secret");
+
+        Ok(())
+    }
+
+    #[test]
+    fn inbound_session_with_config() -> Result<()> {
+        use crate::olm::{DecryptionError, PaddingScheme, SessionConfigError};
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key =
+            *bob.one_time_keys().values().next().context("Failed getting bob's OTK")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_5(PaddingScheme::PowerOfTwo),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        // The pre-key message skips more messages than the default limits
+        // allow.
+        for _ in 0..2500 {
+            alice_session.encrypt("Skipped");
+        }
+
+        let message = if let OlmMessage::PreKey(m) = alice_session.encrypt("It's a secret") {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+
+        assert!(matches!(
+            bob.create_inbound_session(alice.curve25519_key(), &message),
+            Err(SessionCreationError::Decryption(DecryptionError::TooBigMessageGap(2500, 2000)))
+        ));
+        assert!(matches!(
+            bob.create_inbound_session_with_config(
+                SessionConfig::version_2(),
+                alice.curve25519_key(),
+                &message
+            ),
+            Err(SessionCreationError::SessionConfig(SessionConfigError::VersionMismatch(5, 2)))
+        ));
+
+        let config =
+            SessionConfig::version_5(PaddingScheme::PowerOfTwo).with_max_message_gap(5000)?;
+        let InboundCreationResult { mut session, plaintext, .. } =
+            bob.create_inbound_session_with_config(config, alice.curve25519_key(), &message)?;
+
+        assert_eq!(plaintext, b"It's a secret");
+        assert_eq!(session.session_config(), config);
+
+        // Our replies use the padding scheme we picked, not the default one.
+        if let OlmMessage::Normal(m) = session.encrypt("A".repeat(100)) {
+            assert_eq!(m.ciphertext().len(), 128);
+        } else {
+            bail!("Invalid message type");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn retransmitted_pre_key_message() -> Result<()> {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(2);
+
+        let one_time_key =
+            *bob.one_time_keys().values().next().context("Failed getting bob's OTK")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let message = if let OlmMessage::PreKey(m) = alice_session.encrypt("It's a secret") {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+
+        let InboundCreationResult { session, .. } =
+            bob.create_inbound_session(alice.curve25519_key(), &message)?;
+        assert_eq!(bob.stored_one_time_key_count(), 1);
+
+        // The cache survives a pickle roundtrip.
+        let mut bob =
+            Account::from_pickle(serde_json::from_str(&serde_json::to_string(&bob.pickle())?)?);
+
+        assert!(matches!(
+            bob.create_inbound_session(alice.curve25519_key(), &message),
+            Err(SessionCreationError::DuplicatePreKeyMessage(session_id))
+                if session_id == session.session_id()
+        ));
+        // The duplicate didn't use up any of the remaining keys.
+        assert_eq!(bob.stored_one_time_key_count(), 1);
+
+        // Another session using the same one-time key isn't mistaken for the
+        // existing one.
+        let mut other_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+        let message = if let OlmMessage::PreKey(m) = other_session.encrypt("It's a secret") {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+
+        assert!(matches!(
+            bob.create_inbound_session(alice.curve25519_key(), &message),
+            Err(SessionCreationError::MissingOneTimeKey(key)) if key == one_time_key
+        ));
+
+        Ok(())
+    }
+
     #[test]
     fn vodozemac_vodozemac_communication() -> Result<()> {
         // Both of these are vodozemac accounts.
@@ -654,7 +2128,7 @@ mod test {
         if let OlmMessage::PreKey(m) = olm_message {
             assert_eq!(m.session_keys(), alice_session.session_keys());
 
-            let InboundCreationResult { session: mut bob_session, plaintext } =
+            let InboundCreationResult { session: mut bob_session, plaintext, .. } =
                 bob.create_inbound_session(alice.curve25519_key(), &m)?;
             assert_eq!(alice_session.session_id(), bob_session.session_id());
             assert_eq!(m.session_keys(), bob_session.session_keys());
@@ -708,11 +2182,12 @@ mod test {
 
         let identity_key = PublicKey::from_base64(alice.parsed_identity_keys().curve25519())?;
 
-        let InboundCreationResult { session, plaintext } = if let OlmMessage::PreKey(m) = &message {
-            bob.create_inbound_session(identity_key, m)?
-        } else {
-            bail!("Got invalid message type from olm_rs {:?}", message);
-        };
+        let InboundCreationResult { session, plaintext, .. } =
+            if let OlmMessage::PreKey(m) = &message {
+                bob.create_inbound_session(identity_key, m)?
+            } else {
+                bail!("Got invalid message type from olm_rs {:?}", message);
+            };
 
         assert_eq!(alice_session.session_id(), session.session_id());
         assert!(bob.one_time_keys.private_keys.is_empty());
@@ -744,7 +2219,7 @@ mod test {
         let identity_key = PublicKey::from_base64(alice.parsed_identity_keys().curve25519())?;
 
         if let OlmMessage::PreKey(m) = &message {
-            let InboundCreationResult { session, plaintext } =
+            let InboundCreationResult { session, plaintext, .. } =
                 bob.create_inbound_session(identity_key, m)?;
 
             assert_eq!(m.session_keys(), session.session_keys());
@@ -759,6 +2234,66 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn fallback_key_rotation() -> Result<()> {
+        use std::time::{Duration, SystemTime};
+
+        use super::FallbackKeyPolicy;
+
+        let policy = FallbackKeyPolicy::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+
+        assert!(bob.should_rotate_fallback_key(&policy, start));
+
+        bob.generate_fallback_key_at(start);
+        let fallback_key = *bob.fallback_key().values().next().context("Missing fallback key")?;
+        bob.mark_keys_as_published();
+
+        assert!(!bob.should_rotate_fallback_key(&policy, start));
+
+        let mut session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            fallback_key,
+        );
+
+        let used_at = start + Duration::from_secs(60);
+
+        if let OlmMessage::PreKey(m) = session.encrypt("It's a secret to everybody") {
+            bob.create_inbound_session_at(alice.curve25519_key(), &m, used_at)?;
+        } else {
+            bail!("Invalid message type");
+        }
+
+        // Using the fallback key shortens its lifetime.
+        assert!(!bob.should_rotate_fallback_key(&policy, used_at));
+        assert!(bob.should_rotate_fallback_key(&policy, used_at + policy.max_age_after_first_use));
+
+        let rotated_at = used_at + policy.max_age_after_first_use;
+        bob.generate_fallback_key_at(rotated_at);
+
+        assert!(!bob.can_forget_previous_fallback_key(&policy, rotated_at));
+        assert_eq!(bob.forget_expired_fallback_keys(&policy, rotated_at), 0);
+
+        let expired_at = rotated_at + policy.previous_key_lifetime;
+        assert!(bob.can_forget_previous_fallback_key(&policy, expired_at));
+        assert_eq!(bob.forget_expired_fallback_keys(&policy, expired_at), 1);
+        assert!(bob.fallback_keys.previous_fallback_key().is_none());
+
+        // The timestamps survive a pickle roundtrip.
+        bob.set_max_previous_fallback_keys(2);
+        let unpickled = Account::from_pickle(bob.pickle());
+
+        assert_eq!(unpickled.max_previous_fallback_keys(), 2);
+        assert!(!unpickled.should_rotate_fallback_key(&policy, rotated_at));
+        assert!(unpickled.should_rotate_fallback_key(&policy, rotated_at + policy.max_age));
+
+        Ok(())
+    }
+
     #[test]
     fn account_pickling_roundtrip_is_identity() -> Result<()> {
         let mut account = Account::new();
@@ -786,6 +2321,42 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn one_time_key_config() -> Result<()> {
+        use super::{OneTimeKeyConfig, OneTimeKeyEvictionStrategy};
+
+        let mut account = Account::new();
+        assert_eq!(account.one_time_key_config(), OneTimeKeyConfig::default());
+        assert_eq!(account.max_number_of_one_time_keys(), 50);
+
+        let config = OneTimeKeyConfig::default()
+            .with_max_published_one_time_keys(10)?
+            .with_max_one_time_keys(20)?
+            .with_eviction_strategy(OneTimeKeyEvictionStrategy::Never);
+
+        assert!(account.set_one_time_key_config(config).is_empty());
+        assert_eq!(account.max_number_of_one_time_keys(), 10);
+
+        account.generate_one_time_keys(30);
+        assert_eq!(account.stored_one_time_key_count(), 20);
+
+        let pickle = serde_json::to_string(&account.pickle())?;
+        let unpickled = Account::from_pickle(serde_json::from_str(&pickle)?);
+        assert_eq!(unpickled.one_time_key_config(), config);
+        assert_eq!(unpickled.max_number_of_one_time_keys(), 10);
+
+        // Pickles without a config use the default one.
+        let mut pickle: serde_json::Value = serde_json::from_str(&pickle)?;
+        pickle["one_time_keys"]
+            .as_object_mut()
+            .context("The one-time keys should be an object")?
+            .remove("config");
+        let unpickled = Account::from_pickle(serde_json::from_value(pickle)?);
+        assert_eq!(unpickled.one_time_key_config(), OneTimeKeyConfig::default());
+
+        Ok(())
+    }
+
     #[test]
     #[cfg(feature = "libolm-compat")]
     fn libolm_unpickling() -> Result<()> {
@@ -829,6 +2400,177 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_pickling() -> Result<()> {
+        let mut account = Account::new();
+        account.generate_one_time_keys(10);
+        account.generate_fallback_key();
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let pickle = account.to_libolm_pickle(key)?;
+
+        let olm =
+            OlmAccount::unpickle(pickle, olm_rs::PicklingMode::Encrypted { key: key.to_vec() })?;
+
+        assert_eq!(olm.parsed_identity_keys().ed25519(), account.ed25519_key().to_base64());
+        assert_eq!(olm.parsed_identity_keys().curve25519(), account.curve25519_key().to_base64());
+
+        let mut olm_one_time_keys: Vec<_> =
+            olm.parsed_one_time_keys().curve25519().values().map(|k| k.to_owned()).collect();
+        let mut one_time_keys: Vec<_> =
+            account.one_time_keys().values().map(|k| k.to_base64()).collect();
+
+        olm_one_time_keys.sort();
+        one_time_keys.sort();
+        assert_eq!(olm_one_time_keys, one_time_keys);
+
+        let olm_fallback_key =
+            olm.parsed_fallback_key().expect("libolm should have a fallback key");
+        assert_eq!(
+            olm_fallback_key.curve25519(),
+            account
+                .fallback_key()
+                .values()
+                .next()
+                .expect("We should have a fallback key")
+                .to_base64()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_pickling_roundtrip() -> Result<()> {
+        let mut account = Account::new();
+        account.generate_one_time_keys(10);
+        account.generate_fallback_key();
+        account.mark_keys_as_published();
+        account.generate_fallback_key();
+        account.generate_one_time_keys(5);
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let pickle = account.to_libolm_pickle(key)?;
+        let unpickled = Account::from_libolm_pickle(&pickle, key)?;
+
+        assert_eq!(account.identity_keys(), unpickled.identity_keys());
+        assert_eq!(account.one_time_keys(), unpickled.one_time_keys());
+        assert_eq!(account.fallback_key(), unpickled.fallback_key());
+        assert_eq!(account.stored_one_time_key_count(), unpickled.stored_one_time_key_count());
+        assert_eq!(account.one_time_keys.next_key_id, unpickled.one_time_keys.next_key_id);
+        assert!(unpickled.fallback_keys.previous_fallback_key().is_some());
+
+        let message = "It's a secret to everybody";
+        assert_eq!(account.sign(message), unpickled.sign(message));
+
+        // The current and the previous fallback key keep their roles.
+        let public_keys = |account: &Account| {
+            (
+                account.fallback_keys.fallback_key.as_ref().map(|f| f.public_key()),
+                account.fallback_keys.previous_fallback_key().map(|f| f.public_key()),
+            )
+        };
+        assert_eq!(public_keys(&unpickled), public_keys(&account));
+
+        // A previous fallback key without a current one can't be stored in
+        // a libolm pickle, it must not become the current fallback key.
+        account.fallback_keys.fallback_key = None;
+        let pickle = account.to_libolm_pickle(key)?;
+        let unpickled = Account::from_libolm_pickle(&pickle, key)?;
+
+        assert!(public_keys(&account).1.is_some());
+        assert_eq!(public_keys(&unpickled), (None, None));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_legacy_pickle_versions() -> Result<()> {
+        use assert_matches::assert_matches;
+        use matrix_pickle::Encode;
+
+        use super::libolm::Pickle;
+        use crate::{utilities::encrypt_libolm_pickle, LibolmPickleError};
+
+        const ONE_TIME_KEY_LENGTH: usize = 4 + 1 + 32 + 32;
+
+        let mut account = Account::new();
+        account.generate_one_time_keys(5);
+        account.generate_fallback_key();
+        account.mark_keys_as_published();
+        account.generate_fallback_key();
+        account.mark_keys_as_published();
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let encoded = Pickle::from(&account).encode_to_vec()?;
+
+        // Split the pickle into the part that all versions share, the fallback
+        // keys, which changed between versions, and the next key ID.
+        let fallback_keys_start = 4 + 32 + 64 + 32 + 32 + 4 + 5 * ONE_TIME_KEY_LENGTH;
+        let (head, rest) = encoded.split_at(fallback_keys_start);
+        let (fallback_keys, next_key_id) = rest.split_at(rest.len() - 4);
+
+        let pickle = |version: u32, fallback_keys: &[u8]| {
+            let mut bytes = version.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&head[4..]);
+            bytes.extend_from_slice(fallback_keys);
+            bytes.extend_from_slice(next_key_id);
+
+            encrypt_libolm_pickle(&bytes, key)
+        };
+
+        let fallback_key =
+            |account: &Account| account.fallback_keys.fallback_key.as_ref().map(|k| k.public_key());
+        let previous_fallback_key = |account: &Account| {
+            account.fallback_keys.previous_fallback_key().map(|k| k.public_key())
+        };
+
+        // Version 2 pickles don't contain any fallback keys.
+        let unpickled = Account::from_libolm_pickle(&pickle(2, &[]), key)?;
+        assert_eq!(account.identity_keys(), unpickled.identity_keys());
+        assert_eq!(account.stored_one_time_key_count(), unpickled.stored_one_time_key_count());
+        assert_eq!(account.one_time_keys.next_key_id, unpickled.one_time_keys.next_key_id);
+        assert!(fallback_key(&unpickled).is_none());
+        assert!(previous_fallback_key(&unpickled).is_none());
+
+        // Version 3 pickles always contain two fallback keys, without the
+        // count in front of them.
+        let mut v3_fallback_keys = fallback_keys[1..].to_vec();
+        assert_eq!(v3_fallback_keys.len(), 2 * ONE_TIME_KEY_LENGTH);
+
+        let unpickled = Account::from_libolm_pickle(&pickle(3, &v3_fallback_keys), key)?;
+        assert_eq!(account.identity_keys(), unpickled.identity_keys());
+        assert_eq!(fallback_key(&account), fallback_key(&unpickled));
+        assert_eq!(previous_fallback_key(&account), previous_fallback_key(&unpickled));
+
+        // An unpublished previous fallback key means that there's no previous
+        // fallback key.
+        v3_fallback_keys[ONE_TIME_KEY_LENGTH + 4] = 0;
+        let unpickled = Account::from_libolm_pickle(&pickle(3, &v3_fallback_keys), key)?;
+        assert_eq!(fallback_key(&account), fallback_key(&unpickled));
+        assert!(previous_fallback_key(&unpickled).is_none());
+
+        // An unpublished current fallback key means that there are no fallback
+        // keys at all.
+        v3_fallback_keys[4] = 0;
+        let unpickled = Account::from_libolm_pickle(&pickle(3, &v3_fallback_keys), key)?;
+        assert!(fallback_key(&unpickled).is_none());
+        assert!(previous_fallback_key(&unpickled).is_none());
+
+        assert_matches!(
+            Account::from_libolm_pickle(&pickle(1, &[]), key).err(),
+            Some(LibolmPickleError::LegacyAccountPickle)
+        );
+        assert_matches!(
+            Account::from_libolm_pickle(&pickle(5, fallback_keys), key).err(),
+            Some(LibolmPickleError::Version(4, 5))
+        );
+
+        Ok(())
+    }
+
     #[test]
     #[cfg(feature = "libolm-compat")]
     fn signing_with_expanded_key() -> Result<()> {
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
+use rand::{thread_rng, CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use super::PUBLIC_MAX_ONE_TIME_KEYS;
 use crate::{
@@ -22,6 +24,207 @@ use crate::{
     Curve25519PublicKey,
 };
 
+/// The strategy an [`Account`] uses to make room for new one-time keys once
+/// its one-time key pool is full.
+///
+/// [`Account`]: super::Account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OneTimeKeyEvictionStrategy {
+    /// Remove the oldest one-time keys, regardless of whether they were
+    /// published or not. This is what libolm does.
+    OldestFirst,
+    /// Remove the oldest unpublished one-time keys first. The server doesn't
+    /// know about unpublished keys, so removing them can't break any session
+    /// setup. Published keys are only removed once no unpublished keys remain.
+    UnpublishedFirst,
+    /// Never remove one-time keys. Once the pool is full, no new one-time keys
+    /// will be generated until some of the existing ones get used up.
+    Never,
+}
+
+/// The default number of used up one-time keys an [`Account`] remembers, see
+/// [`OneTimeKeyConfig::consumed_key_cache_size()`].
+///
+/// [`Account`]: super::Account
+const DEFAULT_CONSUMED_KEY_CACHE_SIZE: usize = 100;
+
+/// Error type describing an invalid [`OneTimeKeyConfig`].
+#[derive(Debug, Error)]
+pub enum OneTimeKeyConfigError {
+    /// The number of one-time keys to keep on the server is zero or bigger
+    /// than the number of one-time keys the account stores.
+    #[error(
+        "The number of published one-time keys needs to be between 1 and the number of stored \
+        one-time keys {1}, got {0}"
+    )]
+    InvalidPublishedKeyCount(usize, usize),
+    /// The consumed key cache can't be disabled.
+    #[error("The consumed one-time key cache needs to hold at least one key")]
+    EmptyConsumedKeyCache,
+}
+
+/// Configuration for the one-time key pool of an [`Account`].
+///
+/// The configuration is persisted in the [`AccountPickle`].
+///
+/// [`Account`]: super::Account
+/// [`AccountPickle`]: super::AccountPickle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "OneTimeKeyConfigPickle")]
+pub struct OneTimeKeyConfig {
+    max_one_time_keys: usize,
+    max_published_one_time_keys: usize,
+    eviction_strategy: OneTimeKeyEvictionStrategy,
+    consumed_key_cache_size: usize,
+}
+
+fn default_consumed_key_cache_size() -> usize {
+    DEFAULT_CONSUMED_KEY_CACHE_SIZE
+}
+
+impl OneTimeKeyConfig {
+    /// The maximum number of private one-time keys the [`Account`] stores.
+    ///
+    /// [`Account`]: super::Account
+    pub fn max_one_time_keys(&self) -> usize {
+        self.max_one_time_keys
+    }
+
+    /// The maximum number of one-time keys the client should keep on the
+    /// server, as returned by [`Account::max_number_of_one_time_keys()`].
+    ///
+    /// [`Account::max_number_of_one_time_keys()`]: super::Account::max_number_of_one_time_keys
+    pub fn max_published_one_time_keys(&self) -> usize {
+        self.max_published_one_time_keys
+    }
+
+    /// The strategy used to make room for new one-time keys once
+    /// [`OneTimeKeyConfig::max_one_time_keys()`] has been reached.
+    pub fn eviction_strategy(&self) -> OneTimeKeyEvictionStrategy {
+        self.eviction_strategy
+    }
+
+    /// The number of used one-time keys, fallback keys and signed pre-keys the
+    /// [`Account`] remembers, together with the ID of the [`Session`] they
+    /// were used to create.
+    ///
+    /// A sender might retransmit a pre-key message which we already used to
+    /// create a [`Session`]. If the key of such a message is still remembered,
+    /// [`Account::create_inbound_session()`] fails with
+    /// [`SessionCreationError::DuplicatePreKeyMessage`], pointing to the
+    /// existing [`Session`] that should be used to decrypt the message,
+    /// instead of failing with [`SessionCreationError::MissingOneTimeKey`] or
+    /// creating a twin of the existing [`Session`].
+    ///
+    /// Only the public part of the used keys is remembered. Defaults to 100.
+    ///
+    /// [`Account`]: super::Account
+    /// [`Session`]: crate::olm::Session
+    /// [`Account::create_inbound_session()`]: super::Account::create_inbound_session
+    /// [`SessionCreationError::DuplicatePreKeyMessage`]: super::SessionCreationError::DuplicatePreKeyMessage
+    /// [`SessionCreationError::MissingOneTimeKey`]: super::SessionCreationError::MissingOneTimeKey
+    pub fn consumed_key_cache_size(&self) -> usize {
+        self.consumed_key_cache_size
+    }
+
+    /// Set the maximum number of private one-time keys the [`Account`] stores,
+    /// it can't be smaller than
+    /// [`OneTimeKeyConfig::max_published_one_time_keys()`].
+    ///
+    /// [`Account`]: super::Account
+    pub fn with_max_one_time_keys(
+        mut self,
+        max_one_time_keys: usize,
+    ) -> Result<Self, OneTimeKeyConfigError> {
+        self.max_one_time_keys = max_one_time_keys;
+        self.validate()
+    }
+
+    /// Set the maximum number of one-time keys the client should keep on the
+    /// server, it needs to be between 1 and
+    /// [`OneTimeKeyConfig::max_one_time_keys()`].
+    ///
+    /// This should be considerably smaller than the number of stored one-time
+    /// keys, otherwise keys might get removed while pre-key messages which use
+    /// them are still in flight.
+    pub fn with_max_published_one_time_keys(
+        mut self,
+        max_published_one_time_keys: usize,
+    ) -> Result<Self, OneTimeKeyConfigError> {
+        self.max_published_one_time_keys = max_published_one_time_keys;
+        self.validate()
+    }
+
+    /// Set the strategy used to make room for new one-time keys.
+    pub fn with_eviction_strategy(mut self, eviction_strategy: OneTimeKeyEvictionStrategy) -> Self {
+        self.eviction_strategy = eviction_strategy;
+        self
+    }
+
+    /// Set the number of used keys the [`Account`] remembers, it needs to be at
+    /// least 1.
+    ///
+    /// [`Account`]: super::Account
+    pub fn with_consumed_key_cache_size(
+        mut self,
+        consumed_key_cache_size: usize,
+    ) -> Result<Self, OneTimeKeyConfigError> {
+        self.consumed_key_cache_size = consumed_key_cache_size;
+        self.validate()
+    }
+
+    fn validate(self) -> Result<Self, OneTimeKeyConfigError> {
+        if self.max_published_one_time_keys == 0
+            || self.max_published_one_time_keys > self.max_one_time_keys
+        {
+            Err(OneTimeKeyConfigError::InvalidPublishedKeyCount(
+                self.max_published_one_time_keys,
+                self.max_one_time_keys,
+            ))
+        } else if self.consumed_key_cache_size == 0 {
+            Err(OneTimeKeyConfigError::EmptyConsumedKeyCache)
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+impl Default for OneTimeKeyConfig {
+    fn default() -> Self {
+        Self {
+            max_one_time_keys: OneTimeKeys::MAX_ONE_TIME_KEYS,
+            max_published_one_time_keys: PUBLIC_MAX_ONE_TIME_KEYS,
+            eviction_strategy: OneTimeKeyEvictionStrategy::OldestFirst,
+            consumed_key_cache_size: DEFAULT_CONSUMED_KEY_CACHE_SIZE,
+        }
+    }
+}
+
+/// The serialized form of [`OneTimeKeyConfig`], checked the same way the
+/// `OneTimeKeyConfig::with_*()` methods check the config.
+#[derive(Deserialize)]
+struct OneTimeKeyConfigPickle {
+    max_one_time_keys: usize,
+    max_published_one_time_keys: usize,
+    eviction_strategy: OneTimeKeyEvictionStrategy,
+    #[serde(default = "default_consumed_key_cache_size")]
+    consumed_key_cache_size: usize,
+}
+
+impl TryFrom<OneTimeKeyConfigPickle> for OneTimeKeyConfig {
+    type Error = OneTimeKeyConfigError;
+
+    fn try_from(pickle: OneTimeKeyConfigPickle) -> Result<Self, Self::Error> {
+        OneTimeKeyConfig {
+            max_one_time_keys: pickle.max_one_time_keys,
+            max_published_one_time_keys: pickle.max_published_one_time_keys,
+            eviction_strategy: pickle.eviction_strategy,
+            consumed_key_cache_size: pickle.consumed_key_cache_size,
+        }
+        .validate()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(from = "OneTimeKeysPickle")]
 #[serde(into = "OneTimeKeysPickle")]
@@ -30,6 +233,16 @@ pub(super) struct OneTimeKeys {
     pub unpublished_public_keys: BTreeMap<KeyId, Curve25519PublicKey>,
     pub private_keys: BTreeMap<KeyId, Curve25519SecretKey>,
     pub key_ids_by_key: HashMap<Curve25519PublicKey, KeyId>,
+    pub config: OneTimeKeyConfig,
+    pub consumed_keys: VecDeque<ConsumedOneTimeKey>,
+}
+
+/// A one-time key that was used up, and the ID of the session it was used to
+/// create.
+#[derive(Serialize, Deserialize, Clone)]
+pub(super) struct ConsumedOneTimeKey {
+    public_key: Curve25519PublicKey,
+    session_id: String,
 }
 
 /// The result type for the one-time key generation operation.
@@ -39,17 +252,34 @@ pub struct OneTimeKeyGenerationResult {
     /// The public part of the one-time keys that had to be removed to make
     /// space for the new ones.
     pub removed: Vec<Curve25519PublicKey>,
+    /// The subset of the removed one-time keys which were already published.
+    ///
+    /// The server might still hand those keys out, but pre-key messages using
+    /// them can't be decrypted anymore.
+    pub removed_published: HashMap<KeyId, Curve25519PublicKey>,
+}
+
+struct EvictedKey {
+    key_id: KeyId,
+    public_key: Curve25519PublicKey,
+    published: bool,
 }
 
 impl OneTimeKeys {
     const MAX_ONE_TIME_KEYS: usize = 100 * PUBLIC_MAX_ONE_TIME_KEYS;
 
     pub fn new() -> Self {
+        Self::with_config(OneTimeKeyConfig::default())
+    }
+
+    pub fn with_config(config: OneTimeKeyConfig) -> Self {
         Self {
             next_key_id: 0,
             unpublished_public_keys: Default::default(),
             private_keys: Default::default(),
             key_ids_by_key: Default::default(),
+            config,
+            consumed_keys: Default::default(),
         }
     }
 
@@ -61,6 +291,10 @@ impl OneTimeKeys {
         self.key_ids_by_key.get(public_key).and_then(|key_id| self.private_keys.get(key_id))
     }
 
+    pub fn get_key_id(&self, public_key: &Curve25519PublicKey) -> Option<KeyId> {
+        self.key_ids_by_key.get(public_key).copied()
+    }
+
     pub fn remove_secret_key(
         &mut self,
         public_key: &Curve25519PublicKey,
@@ -71,34 +305,78 @@ impl OneTimeKeys {
         })
     }
 
+    /// Remember that the given key, be it a one-time key, a fallback key or a
+    /// signed pre-key, was used to create the session with the given ID.
+    pub fn remember_consumed_key(&mut self, public_key: Curve25519PublicKey, session_id: String) {
+        self.consumed_keys.push_back(ConsumedOneTimeKey { public_key, session_id });
+        self.shrink_consumed_keys();
+    }
+
+    /// Do we remember that the given key was used to create the session with
+    /// the given ID.
+    pub fn is_consumed_by(&self, public_key: &Curve25519PublicKey, session_id: &str) -> bool {
+        self.consumed_keys.iter().any(|k| k.public_key == *public_key && k.session_id == session_id)
+    }
+
+    fn shrink_consumed_keys(&mut self) {
+        let excess = self.consumed_keys.len().saturating_sub(self.config.consumed_key_cache_size);
+        self.consumed_keys.drain(..excess);
+    }
+
+    /// Replace the configuration of the pool, removing keys if the pool is
+    /// now over its limit and the eviction strategy allows it. Returns the
+    /// removed keys which were already published.
+    pub fn set_config(&mut self, config: OneTimeKeyConfig) -> HashMap<KeyId, Curve25519PublicKey> {
+        self.config = config;
+        self.shrink_consumed_keys();
+
+        let excess = self.private_keys.len().saturating_sub(config.max_one_time_keys);
+
+        self.evict(excess)
+            .into_iter()
+            .filter(|k| k.published)
+            .map(|k| (k.key_id, k.public_key))
+            .collect()
+    }
+
+    fn evict_one(&mut self) -> Option<EvictedKey> {
+        let key_id = match self.config.eviction_strategy {
+            OneTimeKeyEvictionStrategy::OldestFirst => self.private_keys.keys().next(),
+            OneTimeKeyEvictionStrategy::UnpublishedFirst => self
+                .unpublished_public_keys
+                .keys()
+                .next()
+                .or_else(|| self.private_keys.keys().next()),
+            OneTimeKeyEvictionStrategy::Never => None,
+        }
+        .copied()?;
+
+        let private_key = self.private_keys.remove(&key_id)?;
+        let public_key = Curve25519PublicKey::from(&private_key);
+        self.key_ids_by_key.remove(&public_key);
+        let published = self.unpublished_public_keys.remove(&key_id).is_none();
+
+        Some(EvictedKey { key_id, public_key, published })
+    }
+
+    fn evict(&mut self, count: usize) -> Vec<EvictedKey> {
+        (0..count).map_while(|_| self.evict_one()).collect()
+    }
+
+    /// Insert the given one-time key into the pool, returns `None` if the pool
+    /// is full and the eviction strategy doesn't allow us to make room for
+    /// the key.
     pub(super) fn insert_secret_key(
         &mut self,
         key_id: KeyId,
         key: Curve25519SecretKey,
         published: bool,
-    ) -> (Curve25519PublicKey, Option<Curve25519PublicKey>) {
+    ) -> Option<Curve25519PublicKey> {
         // If we hit the max number of one-time keys we'd like to keep, first remove one
-        // before we create a new one.
-        let removed = if self.private_keys.len() >= Self::MAX_ONE_TIME_KEYS {
-            if let Some(key_id) = self.private_keys.keys().next().copied() {
-                let public_key = if let Some(private_key) = self.private_keys.remove(&key_id) {
-                    let public_key = Curve25519PublicKey::from(&private_key);
-                    self.key_ids_by_key.remove(&public_key);
-
-                    Some(public_key)
-                } else {
-                    None
-                };
-
-                self.unpublished_public_keys.remove(&key_id);
-
-                public_key
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+        // before we insert a new one.
+        if self.private_keys.len() >= self.config.max_one_time_keys {
+            self.evict_one()?;
+        }
 
         let public_key = Curve25519PublicKey::from(&key);
 
@@ -109,31 +387,51 @@ impl OneTimeKeys {
             self.unpublished_public_keys.insert(key_id, public_key);
         }
 
-        (public_key, removed)
+        Some(public_key)
     }
 
-    fn generate_one_time_key(&mut self) -> (Curve25519PublicKey, Option<Curve25519PublicKey>) {
+    fn generate_one_time_key(
+        &mut self,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Option<Curve25519PublicKey> {
         let key_id = KeyId(self.next_key_id);
-        let key = Curve25519SecretKey::new();
+        let key = Curve25519SecretKey::new_with_rng(rng);
         self.insert_secret_key(key_id, key, false)
     }
 
     pub fn generate(&mut self, count: usize) -> OneTimeKeyGenerationResult {
-        let mut removed_keys = Vec::new();
-        let mut created_keys = Vec::new();
+        self.generate_with_rng(count, &mut thread_rng())
+    }
 
-        for _ in 0..count {
-            let (created, removed) = self.generate_one_time_key();
+    pub fn generate_with_rng(
+        &mut self,
+        count: usize,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> OneTimeKeyGenerationResult {
+        let max_one_time_keys = self.config.max_one_time_keys;
+
+        // Make room for all the new keys up front, this way the eviction
+        // strategy never picks one of the keys we're about to create.
+        let count = count.min(max_one_time_keys);
+        let excess = (self.private_keys.len() + count).saturating_sub(max_one_time_keys);
+        let evicted = self.evict(excess);
 
-            created_keys.push(created);
-            if let Some(removed) = removed {
-                removed_keys.push(removed);
-            }
+        // If the strategy didn't let us remove enough keys, only fill up the
+        // free space.
+        let count = count.min(max_one_time_keys.saturating_sub(self.private_keys.len()));
 
+        let mut created_keys = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            created_keys.extend(self.generate_one_time_key(rng));
             self.next_key_id = self.next_key_id.wrapping_add(1);
         }
 
-        OneTimeKeyGenerationResult { created: created_keys, removed: removed_keys }
+        let removed_published =
+            evicted.iter().filter(|k| k.published).map(|k| (k.key_id, k.public_key)).collect();
+        let removed = evicted.into_iter().map(|k| k.public_key).collect();
+
+        OneTimeKeyGenerationResult { created: created_keys, removed, removed_published }
     }
 }
 
@@ -143,6 +441,10 @@ pub(super) struct OneTimeKeysPickle {
     next_key_id: u64,
     public_keys: BTreeMap<KeyId, Curve25519PublicKey>,
     private_keys: BTreeMap<KeyId, Curve25519SecretKey>,
+    #[serde(default)]
+    config: OneTimeKeyConfig,
+    #[serde(default)]
+    consumed_keys: VecDeque<ConsumedOneTimeKey>,
 }
 
 impl From<OneTimeKeysPickle> for OneTimeKeys {
@@ -158,6 +460,8 @@ impl From<OneTimeKeysPickle> for OneTimeKeys {
             unpublished_public_keys: pickle.public_keys.iter().map(|(&k, &v)| (k, v)).collect(),
             private_keys: pickle.private_keys,
             key_ids_by_key,
+            config: pickle.config,
+            consumed_keys: pickle.consumed_keys,
         }
     }
 }
@@ -168,14 +472,24 @@ impl From<OneTimeKeys> for OneTimeKeysPickle {
             next_key_id: keys.next_key_id,
             public_keys: keys.unpublished_public_keys.iter().map(|(&k, &v)| (k, v)).collect(),
             private_keys: keys.private_keys,
+            config: keys.config,
+            consumed_keys: keys.consumed_keys,
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::OneTimeKeys;
-    use crate::types::KeyId;
+    use super::{OneTimeKeyConfig, OneTimeKeyConfigError, OneTimeKeyEvictionStrategy, OneTimeKeys};
+    use crate::types::{Curve25519SecretKey, KeyId};
+
+    fn config(strategy: OneTimeKeyEvictionStrategy) -> OneTimeKeyConfig {
+        OneTimeKeyConfig::default()
+            .with_max_published_one_time_keys(5)
+            .and_then(|c| c.with_max_one_time_keys(10))
+            .expect("The config should be valid")
+            .with_eviction_strategy(strategy)
+    }
 
     #[test]
     fn store_limit() {
@@ -207,4 +521,139 @@ mod test {
 
         assert_eq!(oldest_key_id, KeyId(10));
     }
+
+    #[test]
+    fn oldest_first_eviction() {
+        let mut store = OneTimeKeys::with_config(config(OneTimeKeyEvictionStrategy::OldestFirst));
+
+        store.generate(8);
+        store.mark_as_published();
+
+        let result = store.generate(5);
+        assert_eq!(result.created.len(), 5);
+        assert_eq!(result.removed.len(), 3);
+        assert_eq!(result.removed_published.len(), 3);
+        assert!(result.removed_published.contains_key(&KeyId(0)));
+        assert!(result.removed_published.contains_key(&KeyId(2)));
+        assert_eq!(store.private_keys.len(), 10);
+        assert_eq!(store.unpublished_public_keys.len(), 5);
+    }
+
+    #[test]
+    fn unpublished_first_eviction() {
+        let mut store =
+            OneTimeKeys::with_config(config(OneTimeKeyEvictionStrategy::UnpublishedFirst));
+
+        store.generate(6);
+        store.mark_as_published();
+        store.generate(4);
+
+        // The unpublished keys get removed first, none of the keys we create
+        // are removed again.
+        let result = store.generate(6);
+        assert_eq!(result.created.len(), 6);
+        assert_eq!(result.removed.len(), 6);
+        assert_eq!(result.removed_published.len(), 2);
+        assert!(result.removed_published.contains_key(&KeyId(0)));
+        assert!(result.removed_published.contains_key(&KeyId(1)));
+        assert_eq!(store.private_keys.len(), 10);
+        assert_eq!(store.unpublished_public_keys.len(), 6);
+        assert!(result.created.iter().all(|k| store.get_secret_key(k).is_some()));
+    }
+
+    #[test]
+    fn no_eviction() {
+        let mut store = OneTimeKeys::with_config(config(OneTimeKeyEvictionStrategy::Never));
+
+        let result = store.generate(8);
+        assert_eq!(result.created.len(), 8);
+
+        let result = store.generate(5);
+        assert_eq!(result.created.len(), 2);
+        assert!(result.removed.is_empty());
+        assert_eq!(store.private_keys.len(), 10);
+
+        let result = store.generate(1);
+        assert!(result.created.is_empty());
+        assert_eq!(store.next_key_id, 10);
+
+        // Keys that get inserted directly don't make the pool grow either.
+        assert!(store.insert_secret_key(KeyId(10), Curve25519SecretKey::new(), true).is_none());
+        assert_eq!(store.private_keys.len(), 10);
+    }
+
+    #[test]
+    fn config_validation() -> Result<(), OneTimeKeyConfigError> {
+        let config = OneTimeKeyConfig::default();
+        assert_eq!(config.max_published_one_time_keys(), 50);
+        assert_eq!(config.consumed_key_cache_size(), 100);
+
+        let config = config.with_max_published_one_time_keys(5)?.with_max_one_time_keys(10)?;
+        assert_eq!(config.max_one_time_keys(), 10);
+        assert_eq!(config.max_published_one_time_keys(), 5);
+
+        assert!(matches!(
+            config.with_max_one_time_keys(4),
+            Err(OneTimeKeyConfigError::InvalidPublishedKeyCount(5, 4))
+        ));
+        assert!(matches!(
+            config.with_max_one_time_keys(0),
+            Err(OneTimeKeyConfigError::InvalidPublishedKeyCount(5, 0))
+        ));
+        assert!(matches!(
+            config.with_max_published_one_time_keys(0),
+            Err(OneTimeKeyConfigError::InvalidPublishedKeyCount(0, 10))
+        ));
+        assert!(matches!(
+            config.with_consumed_key_cache_size(0),
+            Err(OneTimeKeyConfigError::EmptyConsumedKeyCache)
+        ));
+
+        // The same checks are done when a config gets deserialized.
+        let mut value = serde_json::to_value(config).expect("The config should serialize");
+        value["max_published_one_time_keys"] = 20.into();
+        serde_json::from_value::<OneTimeKeyConfig>(value)
+            .expect_err("An invalid config shouldn't deserialize");
+
+        Ok(())
+    }
+
+    #[test]
+    fn shrinking_the_pool() {
+        let mut store = OneTimeKeys::new();
+
+        store.generate(12);
+        store.mark_as_published();
+
+        let removed = store.set_config(config(OneTimeKeyEvictionStrategy::OldestFirst));
+        assert_eq!(removed.len(), 2);
+        assert!(removed.contains_key(&KeyId(0)));
+        assert!(removed.contains_key(&KeyId(1)));
+        assert_eq!(store.private_keys.len(), 10);
+        assert_eq!(store.key_ids_by_key.len(), 10);
+    }
+
+    #[test]
+    fn consumed_key_cache() {
+        let mut store = OneTimeKeys::new();
+        let keys = store.generate(3).created;
+
+        let config = config(OneTimeKeyEvictionStrategy::OldestFirst);
+        store.set_config(config.with_consumed_key_cache_size(2).expect("Valid cache size"));
+
+        store.remember_consumed_key(keys[0], "first".to_owned());
+        store.remember_consumed_key(keys[1], "second".to_owned());
+        assert!(store.is_consumed_by(&keys[0], "first"));
+        assert!(!store.is_consumed_by(&keys[0], "second"));
+
+        // The oldest key is forgotten once the cache is full.
+        store.remember_consumed_key(keys[2], "third".to_owned());
+        assert!(!store.is_consumed_by(&keys[0], "first"));
+        assert!(store.is_consumed_by(&keys[1], "second"));
+        assert!(store.is_consumed_by(&keys[2], "third"));
+
+        store.set_config(config.with_consumed_key_cache_size(1).expect("Valid cache size"));
+        assert!(!store.is_consumed_by(&keys[1], "second"));
+        assert!(store.is_consumed_by(&keys[2], "third"));
+    }
 }
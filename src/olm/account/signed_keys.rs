@@ -0,0 +1,287 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::PublicSignedPreKey;
+use crate::{canonical_json, types::KeyId, Curve25519PublicKey, Ed25519Keypair, KeyError};
+
+/// The algorithm name of signed Curve25519 keys, used as the prefix of the
+/// key IDs in the `/keys/upload` request.
+const SIGNED_CURVE25519: &str = "signed_curve25519";
+
+/// A signed Curve25519 one-time, fallback or signed pre-key, in the format the
+/// `/keys/upload` endpoint expects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedKey {
+    /// The unpadded base64 encoded Curve25519 public key.
+    pub key: String,
+    /// Is this key a fallback key.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+    /// Is this key a signed pre-key.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub signed_pre_key: bool,
+    /// The signatures of the key, a map from the user ID to a map from the
+    /// key ID of the signing key to the signature.
+    pub signatures: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl SignedKey {
+    fn new(
+        signing_key: &Ed25519Keypair,
+        user_id: &str,
+        device_id: &str,
+        key: Curve25519PublicKey,
+        fallback: bool,
+    ) -> Self {
+        let key = key.to_base64();
+        let signature = signing_key.sign(Self::signable_json(&key, fallback, false).as_bytes());
+
+        Self {
+            key,
+            fallback,
+            signed_pre_key: false,
+            signatures: signatures(user_id, device_id, signature.to_base64()),
+        }
+    }
+
+    fn from_signed_pre_key(
+        signed_pre_key: &PublicSignedPreKey,
+        user_id: &str,
+        device_id: &str,
+    ) -> Self {
+        Self {
+            key: signed_pre_key.public_key.to_base64(),
+            fallback: false,
+            signed_pre_key: true,
+            signatures: signatures(user_id, device_id, signed_pre_key.signature.to_base64()),
+        }
+    }
+
+    /// The canonical JSON of the key object without its signatures, this is
+    /// the message the signature of the key covers.
+    pub(super) fn signable_json(key: &str, fallback: bool, signed_pre_key: bool) -> String {
+        let mut signable = json!({ "key": key });
+
+        if let Value::Object(object) = &mut signable {
+            if fallback {
+                object.insert("fallback".to_owned(), Value::Bool(true));
+            }
+            if signed_pre_key {
+                object.insert("signed_pre_key".to_owned(), Value::Bool(true));
+            }
+        }
+
+        canonical_json::to_signable_json(&signable)
+            .expect("A key object should always be encodable as canonical JSON")
+    }
+
+    /// Get the Curve25519 public key this object contains.
+    pub fn curve25519_key(&self) -> Result<Curve25519PublicKey, KeyError> {
+        Curve25519PublicKey::from_base64(&self.key)
+    }
+}
+
+fn signatures(
+    user_id: &str,
+    device_id: &str,
+    signature: String,
+) -> BTreeMap<String, BTreeMap<String, String>> {
+    BTreeMap::from([(
+        user_id.to_owned(),
+        BTreeMap::from([(canonical_json::key_id(device_id), signature)]),
+    )])
+}
+
+/// The signed one-time, fallback and signed pre-keys of an [`Account`] that
+/// still need to be published.
+///
+/// The struct serializes into the `one_time_keys` and `fallback_keys` fields
+/// of a `/keys/upload` request, signed pre-keys go into the additional
+/// `signed_pre_keys` field. Once the upload succeeded, the keys need to be
+/// marked as published using [`Account::mark_keys_as_published()`].
+///
+/// [`Account`]: super::Account
+/// [`Account::mark_keys_as_published()`]: super::Account::mark_keys_as_published
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedKeys {
+    /// The signed one-time keys, keyed by `signed_curve25519:<key_id>`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub one_time_keys: BTreeMap<String, SignedKey>,
+    /// The signed fallback key, keyed by `signed_curve25519:<key_id>`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fallback_keys: BTreeMap<String, SignedKey>,
+    /// The signed pre-key, keyed by `signed_curve25519:<key_id>`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub signed_pre_keys: BTreeMap<String, SignedKey>,
+}
+
+impl SignedKeys {
+    /// Are there any keys that need to be published.
+    pub fn is_empty(&self) -> bool {
+        self.one_time_keys.is_empty()
+            && self.fallback_keys.is_empty()
+            && self.signed_pre_keys.is_empty()
+    }
+}
+
+/// The key IDs use the same encoding as [`KeyId::to_base64()`], so a signed key
+/// can be matched up with the key of the same ID returned by
+/// [`Account::one_time_keys()`], [`Account::fallback_key()`] or
+/// [`Account::signed_pre_key()`].
+///
+/// [`Account::one_time_keys()`]: super::Account::one_time_keys
+/// [`Account::fallback_key()`]: super::Account::fallback_key
+/// [`Account::signed_pre_key()`]: super::Account::signed_pre_key
+fn key_id(key_id: KeyId) -> String {
+    format!("{SIGNED_CURVE25519}:{}", key_id.to_base64())
+}
+
+pub(super) fn sign_keys<'a>(
+    signing_key: &Ed25519Keypair,
+    user_id: &str,
+    device_id: &str,
+    one_time_keys: impl Iterator<Item = (&'a KeyId, &'a Curve25519PublicKey)>,
+    fallback_key: Option<(KeyId, Curve25519PublicKey)>,
+    signed_pre_key: Option<PublicSignedPreKey>,
+) -> SignedKeys {
+    let one_time_keys = one_time_keys
+        .map(|(id, key)| {
+            (key_id(*id), SignedKey::new(signing_key, user_id, device_id, *key, false))
+        })
+        .collect();
+
+    let fallback_keys = fallback_key
+        .map(|(id, key)| (key_id(id), SignedKey::new(signing_key, user_id, device_id, key, true)))
+        .into_iter()
+        .collect();
+
+    let signed_pre_keys = signed_pre_key
+        .map(|k| (key_id(k.key_id), SignedKey::from_signed_pre_key(&k, user_id, device_id)))
+        .into_iter()
+        .collect();
+
+    SignedKeys { one_time_keys, fallback_keys, signed_pre_keys }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use serde_json::json;
+
+    use super::SignedKeys;
+    use crate::olm::Account;
+
+    const USER_ID: &str = "@alice:example.org";
+    const DEVICE_ID: &str = "DEVICEID";
+
+    #[test]
+    fn signed_keys() -> Result<()> {
+        let mut account = Account::new();
+        assert!(account.signed_keys(USER_ID, DEVICE_ID).is_empty());
+
+        account.generate_one_time_keys(2);
+        account.generate_fallback_key();
+        let signed_pre_key = account.generate_signed_pre_key();
+
+        let keys = account.signed_keys(USER_ID, DEVICE_ID);
+
+        assert_eq!(keys.one_time_keys.len(), 2);
+        assert_eq!(keys.fallback_keys.len(), 1);
+        assert_eq!(keys.signed_pre_keys.len(), 1);
+        assert!(keys.one_time_keys.contains_key("signed_curve25519:AAAAAAAAAAA"));
+        assert!(keys.one_time_keys.contains_key("signed_curve25519:AAAAAAAAAAE"));
+
+        // The key IDs are the same ones the other methods of the account use.
+        for (key_id, key) in account.one_time_keys() {
+            let signed_key =
+                &keys.one_time_keys[&format!("signed_curve25519:{}", key_id.to_base64())];
+            assert_eq!(signed_key.curve25519_key()?, key);
+        }
+        for (key_id, key) in account.fallback_key() {
+            let signed_key =
+                &keys.fallback_keys[&format!("signed_curve25519:{}", key_id.to_base64())];
+            assert_eq!(signed_key.curve25519_key()?, key);
+        }
+
+        let (_, fallback_key) =
+            keys.fallback_keys.iter().next().expect("The fallback key should be included");
+        assert!(fallback_key.fallback);
+
+        // The signed pre-key object carries the signature of the signed
+        // pre-key itself.
+        let signed_key = &keys.signed_pre_keys
+            [&format!("signed_curve25519:{}", signed_pre_key.key_id.to_base64())];
+        assert!(signed_key.signed_pre_key);
+        assert!(!signed_key.fallback);
+        assert_eq!(signed_key.curve25519_key()?, signed_pre_key.public_key);
+        assert_eq!(
+            signed_key.signatures[USER_ID]["ed25519:DEVICEID"],
+            signed_pre_key.signature.to_base64()
+        );
+
+        for key in keys
+            .one_time_keys
+            .values()
+            .chain(keys.fallback_keys.values())
+            .chain(keys.signed_pre_keys.values())
+        {
+            account.ed25519_key().verify_json(USER_ID, DEVICE_ID, &serde_json::to_value(key)?)?;
+        }
+
+        account.mark_keys_as_published();
+        assert!(account.signed_keys(USER_ID, DEVICE_ID).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn serialization() -> Result<()> {
+        let mut account = Account::new();
+        account.generate_one_time_keys(1);
+
+        let keys = account.signed_keys(USER_ID, DEVICE_ID);
+        let key = &keys.one_time_keys["signed_curve25519:AAAAAAAAAAA"];
+        let signature = &key.signatures[USER_ID]["ed25519:DEVICEID"];
+
+        let value = serde_json::to_value(&keys)?;
+
+        // One-time keys don't contain the fallback marker and empty key maps
+        // are left out.
+        assert_eq!(
+            value,
+            json!({
+                "one_time_keys": {
+                    "signed_curve25519:AAAAAAAAAAA": {
+                        "key": key.key,
+                        "signatures": {
+                            USER_ID: {
+                                "ed25519:DEVICEID": signature,
+                            }
+                        }
+                    }
+                }
+            })
+        );
+
+        let deserialized: SignedKeys = serde_json::from_value(value)?;
+        assert_eq!(keys, deserialized);
+
+        Ok(())
+    }
+}
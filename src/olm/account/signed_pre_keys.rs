@@ -0,0 +1,410 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use rand::{thread_rng, CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::{has_elapsed, SignedKey};
+use crate::{
+    types::{Curve25519SecretKey, KeyId},
+    Curve25519PublicKey, Ed25519Keypair, Ed25519PublicKey, Ed25519Signature, KeyError,
+    SignatureError,
+};
+
+/// The number of previous signed pre-keys an [`Account`] keeps around by
+/// default, enough to cover the default retention period if the keys get
+/// rotated according to the default [`SignedPreKeyPolicy`].
+///
+/// [`Account`]: super::Account
+const DEFAULT_MAX_PREVIOUS_SIGNED_PRE_KEYS: usize = 5;
+
+/// The policy deciding when the signed pre-key of an [`Account`] should be
+/// rotated and when previous signed pre-keys can be forgotten.
+///
+/// [`Account`]: super::Account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedPreKeyPolicy {
+    /// How long a signed pre-key should be used before it gets replaced with a
+    /// new one.
+    pub rotation_period: Duration,
+    /// How long a signed pre-key should be kept around after it has been
+    /// replaced. Pre-key messages which used the key might still be in flight.
+    pub retention_period: Duration,
+}
+
+impl Default for SignedPreKeyPolicy {
+    fn default() -> Self {
+        Self {
+            rotation_period: Duration::from_secs(7 * 24 * 60 * 60),
+            retention_period: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// The message the signature of a signed pre-key covers, the canonical JSON of
+/// the `{"key": <key>, "signed_pre_key": true}` key object. The marker keeps
+/// the signature of a one-time or fallback key from passing as the signature
+/// of a signed pre-key.
+fn signature_message(public_key: &Curve25519PublicKey) -> String {
+    SignedKey::signable_json(&public_key.to_base64(), false, true)
+}
+
+/// The public part of a signed pre-key, signed by the Ed25519 identity key of
+/// the [`Account`] it belongs to.
+///
+/// Unlike fallback keys, signed pre-keys can be authenticated by the other
+/// side before they get used, without any additional signing of the key. The
+/// signature is the same one the key object for the `/keys/upload` endpoint,
+/// returned by [`Account::signed_keys()`], carries.
+///
+/// [`Account`]: super::Account
+/// [`Account::signed_keys()`]: super::Account::signed_keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "PublicSignedPreKeyObject", try_from = "PublicSignedPreKeyObject")]
+pub struct PublicSignedPreKey {
+    /// The ID of the signed pre-key.
+    pub key_id: KeyId,
+    /// The public Curve25519 part of the signed pre-key.
+    pub public_key: Curve25519PublicKey,
+    /// The signature of the public key, created by the Ed25519 identity key
+    /// of the [`Account`].
+    ///
+    /// [`Account`]: super::Account
+    pub signature: Ed25519Signature,
+}
+
+impl PublicSignedPreKey {
+    /// Verify that the signed pre-key was signed by the given Ed25519 identity
+    /// key.
+    pub fn verify(&self, signing_key: &Ed25519PublicKey) -> Result<(), SignatureError> {
+        signing_key.verify(signature_message(&self.public_key).as_bytes(), &self.signature)
+    }
+}
+
+/// The serialized form of [`PublicSignedPreKey`], the key and the signature
+/// are encoded as unpadded base64.
+#[derive(Serialize, Deserialize)]
+struct PublicSignedPreKeyObject {
+    key_id: KeyId,
+    key: String,
+    signature: String,
+}
+
+impl From<PublicSignedPreKey> for PublicSignedPreKeyObject {
+    fn from(key: PublicSignedPreKey) -> Self {
+        Self {
+            key_id: key.key_id,
+            key: key.public_key.to_base64(),
+            signature: key.signature.to_base64(),
+        }
+    }
+}
+
+impl TryFrom<PublicSignedPreKeyObject> for PublicSignedPreKey {
+    type Error = KeyError;
+
+    fn try_from(object: PublicSignedPreKeyObject) -> Result<Self, Self::Error> {
+        Ok(Self {
+            key_id: object.key_id,
+            public_key: Curve25519PublicKey::from_base64(&object.key)?,
+            signature: Ed25519Signature::from_base64(&object.signature)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(super) struct SignedPreKey {
+    key_id: KeyId,
+    key: Curve25519SecretKey,
+    created_at: Option<SystemTime>,
+    replaced_at: Option<SystemTime>,
+    #[serde(default)]
+    published: bool,
+}
+
+impl SignedPreKey {
+    fn public_key(&self) -> Curve25519PublicKey {
+        Curve25519PublicKey::from(&self.key)
+    }
+
+    fn to_public(&self, signing_key: &Ed25519Keypair) -> PublicSignedPreKey {
+        let public_key = self.public_key();
+        let signature = signing_key.sign(signature_message(&public_key).as_bytes());
+
+        PublicSignedPreKey { key_id: self.key_id, public_key, signature }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(super) struct SignedPreKeys {
+    next_key_id: u64,
+    signed_pre_key: Option<SignedPreKey>,
+    /// The signed pre-keys that were replaced by a newer one, newest first.
+    previous_signed_pre_keys: VecDeque<SignedPreKey>,
+    max_previous_signed_pre_keys: usize,
+}
+
+impl Default for SignedPreKeys {
+    fn default() -> Self {
+        Self {
+            next_key_id: 0,
+            signed_pre_key: None,
+            previous_signed_pre_keys: VecDeque::new(),
+            max_previous_signed_pre_keys: DEFAULT_MAX_PREVIOUS_SIGNED_PRE_KEYS,
+        }
+    }
+}
+
+impl SignedPreKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn generate(
+        &mut self,
+        signing_key: &Ed25519Keypair,
+        now: Option<SystemTime>,
+    ) -> PublicSignedPreKey {
+        self.generate_with_rng(signing_key, now, &mut thread_rng())
+    }
+
+    pub fn generate_with_rng(
+        &mut self,
+        signing_key: &Ed25519Keypair,
+        now: Option<SystemTime>,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> PublicSignedPreKey {
+        let key_id = KeyId(self.next_key_id);
+        self.next_key_id = self.next_key_id.wrapping_add(1);
+
+        if let Some(mut signed_pre_key) = self.signed_pre_key.take() {
+            signed_pre_key.replaced_at = now;
+            self.previous_signed_pre_keys.push_front(signed_pre_key);
+            self.previous_signed_pre_keys.truncate(self.max_previous_signed_pre_keys);
+        }
+
+        let signed_pre_key = SignedPreKey {
+            key_id,
+            key: Curve25519SecretKey::new_with_rng(rng),
+            created_at: now,
+            replaced_at: None,
+            published: false,
+        };
+        let public = signed_pre_key.to_public(signing_key);
+
+        self.signed_pre_key = Some(signed_pre_key);
+
+        public
+    }
+
+    pub fn public_key(&self, signing_key: &Ed25519Keypair) -> Option<PublicSignedPreKey> {
+        self.signed_pre_key.as_ref().map(|k| k.to_public(signing_key))
+    }
+
+    pub fn unpublished_public_key(
+        &self,
+        signing_key: &Ed25519Keypair,
+    ) -> Option<PublicSignedPreKey> {
+        self.signed_pre_key.as_ref().filter(|k| !k.published).map(|k| k.to_public(signing_key))
+    }
+
+    pub fn mark_as_published(&mut self) {
+        if let Some(signed_pre_key) = &mut self.signed_pre_key {
+            signed_pre_key.published = true;
+        }
+    }
+
+    fn find(&self, public_key: &Curve25519PublicKey) -> Option<&SignedPreKey> {
+        self.signed_pre_key
+            .iter()
+            .chain(self.previous_signed_pre_keys.iter())
+            .find(|k| k.public_key() == *public_key)
+    }
+
+    pub fn get_secret_key(
+        &self,
+        public_key: &Curve25519PublicKey,
+    ) -> Option<(&Curve25519SecretKey, KeyId)> {
+        self.find(public_key).map(|k| (&k.key, k.key_id))
+    }
+
+    pub fn should_rotate(&self, policy: &SignedPreKeyPolicy, now: SystemTime) -> bool {
+        match &self.signed_pre_key {
+            None => true,
+            Some(key) => key
+                .created_at
+                .map_or(false, |created_at| has_elapsed(created_at, now, policy.rotation_period)),
+        }
+    }
+
+    pub fn forget_expired(&mut self, policy: &SignedPreKeyPolicy, now: SystemTime) -> usize {
+        let count = self.previous_signed_pre_keys.len();
+
+        self.previous_signed_pre_keys.retain(|k| {
+            !k.replaced_at
+                .map_or(false, |replaced_at| has_elapsed(replaced_at, now, policy.retention_period))
+        });
+
+        count - self.previous_signed_pre_keys.len()
+    }
+
+    pub fn forget_previous(&mut self) -> bool {
+        let had_keys = !self.previous_signed_pre_keys.is_empty();
+        self.previous_signed_pre_keys.clear();
+
+        had_keys
+    }
+
+    pub fn max_previous_signed_pre_keys(&self) -> usize {
+        self.max_previous_signed_pre_keys
+    }
+
+    pub fn set_max_previous_signed_pre_keys(&mut self, max_previous_signed_pre_keys: usize) {
+        self.max_previous_signed_pre_keys = max_previous_signed_pre_keys;
+        self.previous_signed_pre_keys.truncate(max_previous_signed_pre_keys);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use serde_json::json;
+
+    use super::{PublicSignedPreKey, SignedKey, SignedPreKeyPolicy, SignedPreKeys};
+    use crate::{types::KeyId, Ed25519Keypair};
+
+    #[test]
+    fn signed_pre_key_signature() {
+        let signing_key = Ed25519Keypair::new();
+        let mut signed_pre_keys = SignedPreKeys::new();
+
+        assert!(signed_pre_keys.public_key(&signing_key).is_none());
+
+        let public = signed_pre_keys.generate(&signing_key, None);
+        assert_eq!(public.key_id, KeyId(0));
+        assert_eq!(signed_pre_keys.public_key(&signing_key), Some(public));
+
+        public.verify(&signing_key.public_key()).expect("The signed pre-key should be valid");
+        public
+            .verify(&Ed25519Keypair::new().public_key())
+            .expect_err("The signed pre-key shouldn't be valid for another signing key");
+
+        // The signature covers the public key.
+        let mut tampered = public;
+        tampered.public_key = signed_pre_keys.generate(&signing_key, None).public_key;
+        tampered
+            .verify(&signing_key.public_key())
+            .expect_err("The tampered key shouldn't be valid");
+
+        // Neither the signature of the bare public key nor the signature of a
+        // one-time key object with the same key are accepted.
+        let mut tampered = public;
+        tampered.signature = signing_key.sign(public.public_key.as_bytes());
+        tampered
+            .verify(&signing_key.public_key())
+            .expect_err("The tampered key shouldn't be valid");
+
+        let one_time_key = SignedKey::signable_json(&public.public_key.to_base64(), false, false);
+        tampered.signature = signing_key.sign(one_time_key.as_bytes());
+        tampered
+            .verify(&signing_key.public_key())
+            .expect_err("The tampered key shouldn't be valid");
+    }
+
+    #[test]
+    fn public_signed_pre_key_serialization() -> anyhow::Result<()> {
+        let signing_key = Ed25519Keypair::new();
+        let public = SignedPreKeys::new().generate(&signing_key, None);
+
+        let value = serde_json::to_value(public)?;
+        assert_eq!(
+            value,
+            json!({
+                "key_id": 0,
+                "key": public.public_key.to_base64(),
+                "signature": public.signature.to_base64(),
+            })
+        );
+
+        let deserialized: PublicSignedPreKey = serde_json::from_value(value)?;
+        assert_eq!(deserialized, public);
+        deserialized.verify(&signing_key.public_key())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotation_and_retention() {
+        let policy = SignedPreKeyPolicy::default();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let signing_key = Ed25519Keypair::new();
+        let mut signed_pre_keys = SignedPreKeys::new();
+
+        assert!(signed_pre_keys.should_rotate(&policy, start));
+
+        let first = signed_pre_keys.generate(&signing_key, Some(start));
+        assert!(!signed_pre_keys.should_rotate(&policy, start));
+        assert!(signed_pre_keys.should_rotate(&policy, start + policy.rotation_period));
+
+        let rotated_at = start + policy.rotation_period;
+        let second = signed_pre_keys.generate(&signing_key, Some(rotated_at));
+        assert_eq!(second.key_id, KeyId(1));
+
+        // The previous key can still be used until the retention period is
+        // over.
+        assert!(signed_pre_keys.get_secret_key(&first.public_key).is_some());
+        assert_eq!(signed_pre_keys.forget_expired(&policy, rotated_at), 0);
+
+        let expired_at = rotated_at + policy.retention_period;
+        assert_eq!(signed_pre_keys.forget_expired(&policy, expired_at), 1);
+        assert!(signed_pre_keys.get_secret_key(&first.public_key).is_none());
+        assert_eq!(
+            signed_pre_keys.get_secret_key(&second.public_key).map(|(_, key_id)| key_id),
+            Some(KeyId(1))
+        );
+        assert!(!signed_pre_keys.forget_previous());
+    }
+
+    #[test]
+    fn max_previous_signed_pre_keys() {
+        let signing_key = Ed25519Keypair::new();
+        let mut signed_pre_keys = SignedPreKeys::new();
+
+        // Keys replaced without a timestamp never expire, but the number of
+        // previous keys is still limited.
+        for _ in 0..10 {
+            signed_pre_keys.generate(&signing_key, None);
+        }
+
+        assert_eq!(signed_pre_keys.previous_signed_pre_keys.len(), 5);
+
+        let key_ids: Vec<_> =
+            signed_pre_keys.previous_signed_pre_keys.iter().map(|k| k.key_id.0).collect();
+        assert_eq!(key_ids, [8, 7, 6, 5, 4]);
+
+        signed_pre_keys.set_max_previous_signed_pre_keys(2);
+        assert_eq!(signed_pre_keys.previous_signed_pre_keys.len(), 2);
+
+        signed_pre_keys.generate(&signing_key, None);
+        let key_ids: Vec<_> =
+            signed_pre_keys.previous_signed_pre_keys.iter().map(|k| k.key_id.0).collect();
+        assert_eq!(key_ids, [9, 8]);
+    }
+}
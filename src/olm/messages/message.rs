@@ -16,13 +16,16 @@ use prost::Message as ProstMessage;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    cipher::{Mac, MessageMac},
-    utilities::{base64_decode, base64_encode, extract_mac, VarInt},
+    cipher::{AeadCipher, Mac, MessageMac},
+    utilities::{base64_decode, base64_encode, extract_mac, extract_tag, VarInt},
     Curve25519PublicKey, DecodeError,
 };
 
 const MAC_TRUNCATED_VERSION: u8 = 3;
 const VERSION: u8 = 4;
+const ASSOCIATED_DATA_VERSION: u8 = 5;
+const AEAD_VERSION: u8 = 6;
+const PADDED_VERSION: u8 = 7;
 
 /// An encrypted Olm message.
 ///
@@ -66,6 +69,26 @@ impl Message {
         self.version == MAC_TRUNCATED_VERSION
     }
 
+    /// Does the MAC of this Olm message cover associated data, i.e. does the
+    /// message need to be decrypted using [`Session::decrypt_with_ad()`].
+    ///
+    /// [`Session::decrypt_with_ad()`]: crate::olm::Session::decrypt_with_ad
+    pub fn mac_covers_associated_data(&self) -> bool {
+        matches!(self.version, ASSOCIATED_DATA_VERSION | AEAD_VERSION | PADDED_VERSION)
+    }
+
+    /// Has this Olm message been encrypted using ChaCha20-Poly1305, i.e. does
+    /// it carry an authentication tag instead of a MAC.
+    pub fn uses_aead(&self) -> bool {
+        matches!(self.version, AEAD_VERSION | PADDED_VERSION)
+    }
+
+    /// Has the plaintext of this Olm message been padded before it was
+    /// encrypted, hiding its exact length.
+    pub fn is_padded(&self) -> bool {
+        self.version == PADDED_VERSION
+    }
+
     /// Try to decode the given byte slice as a Olm [`Message`].
     ///
     /// The expected format of the byte array is described in the
@@ -85,6 +108,9 @@ impl Message {
     /// +--------------+------------------------------------+-----------+
     /// ```
     ///
+    /// Messages encrypted using ChaCha20-Poly1305, padded or not, carry a 16
+    /// byte authentication tag in place of the MAC.
+    ///
     /// The payload uses a format based on the Protocol Buffers encoding. It
     /// consists of the following key-value pairs:
     ///
@@ -144,6 +170,34 @@ impl Message {
         }
     }
 
+    pub(crate) fn new_with_ad(
+        ratchet_key: Curve25519PublicKey,
+        chain_index: u64,
+        ciphertext: Vec<u8>,
+    ) -> Self {
+        Self {
+            version: ASSOCIATED_DATA_VERSION,
+            ratchet_key,
+            chain_index,
+            ciphertext,
+            mac: Mac([0u8; Mac::LENGTH]).into(),
+        }
+    }
+
+    pub(crate) fn new_aead(ratchet_key: Curve25519PublicKey, chain_index: u64) -> Self {
+        Self {
+            version: AEAD_VERSION,
+            ratchet_key,
+            chain_index,
+            ciphertext: Vec::new(),
+            mac: [0u8; AeadCipher::TAG_LENGTH].into(),
+        }
+    }
+
+    pub(crate) fn new_padded(ratchet_key: Curve25519PublicKey, chain_index: u64) -> Self {
+        Self { version: PADDED_VERSION, ..Self::new_aead(ratchet_key, chain_index) }
+    }
+
     fn encode(&self) -> Vec<u8> {
         ProtoBufMessage {
             ratchet_key: self.ratchet_key.to_bytes().to_vec(),
@@ -157,10 +211,39 @@ impl Message {
         self.encode()
     }
 
+    /// The bytes the MAC of the message gets calculated over, followed by the
+    /// length prefixed associated data.
+    pub(crate) fn to_mac_bytes_with_ad(&self, associated_data: &[u8]) -> Vec<u8> {
+        let mut bytes = self.encode();
+        bytes.extend(associated_data.len().to_var_int());
+        bytes.extend(associated_data);
+
+        bytes
+    }
+
+    /// The associated data the AEAD authenticates, the encoded message without
+    /// the ciphertext, followed by the length prefixed associated data.
+    pub(crate) fn to_aead_associated_data(&self, associated_data: &[u8]) -> Vec<u8> {
+        let mut bytes = ProtoBufMessage {
+            ratchet_key: self.ratchet_key.to_bytes().to_vec(),
+            chain_index: self.chain_index,
+            ciphertext: Vec::new(),
+        }
+        .encode_header(self.version);
+
+        bytes.extend(associated_data.len().to_var_int());
+        bytes.extend(associated_data);
+
+        bytes
+    }
+
     pub(crate) fn set_mac(&mut self, mac: Mac) {
         match self.mac {
             MessageMac::Truncated(_) => self.mac = mac.truncate().into(),
             MessageMac::Full(_) => self.mac = mac.into(),
+            MessageMac::Tag(_) => {
+                unreachable!("Messages using an authentication tag don't get a MAC set")
+            }
         }
     }
 }
@@ -207,8 +290,9 @@ impl TryFrom<&[u8]> for Message {
         let version = *value.first().ok_or(DecodeError::MissingVersion)?;
 
         let mac_length = match version {
-            VERSION => Mac::LENGTH,
+            VERSION | ASSOCIATED_DATA_VERSION => Mac::LENGTH,
             MAC_TRUNCATED_VERSION => Mac::TRUNCATED_LEN,
+            AEAD_VERSION | PADDED_VERSION => AeadCipher::TAG_LENGTH,
             _ => return Err(DecodeError::InvalidVersion(VERSION, version)),
         };
 
@@ -226,7 +310,11 @@ impl TryFrom<&[u8]> for Message {
             if mac_slice.len() != mac_length {
                 Err(DecodeError::InvalidMacLength(mac_length, mac_slice.len()))
             } else {
-                let mac = extract_mac(mac_slice, version == MAC_TRUNCATED_VERSION);
+                let mac = if matches!(version, AEAD_VERSION | PADDED_VERSION) {
+                    extract_tag(mac_slice)
+                } else {
+                    extract_mac(mac_slice, version == MAC_TRUNCATED_VERSION)
+                };
 
                 let chain_index = inner.chain_index;
                 let ciphertext = inner.ciphertext;
@@ -256,9 +344,21 @@ impl ProtoBufMessage {
     const CIPHER_TAG: &'static [u8; 1] = b"\x22";
 
     fn encode_manual(&self, version: u8) -> Vec<u8> {
+        let ciphertext_len = self.ciphertext.len().to_var_int();
+
+        [
+            self.encode_header(version).as_ref(),
+            Self::CIPHER_TAG.as_ref(),
+            &ciphertext_len,
+            &self.ciphertext,
+        ]
+        .concat()
+    }
+
+    /// Encode the message without the ciphertext.
+    fn encode_header(&self, version: u8) -> Vec<u8> {
         let index = self.chain_index.to_var_int();
         let ratchet_len = self.ratchet_key.len().to_var_int();
-        let ciphertext_len = self.ciphertext.len().to_var_int();
 
         [
             [version].as_ref(),
@@ -267,9 +367,6 @@ impl ProtoBufMessage {
             &self.ratchet_key,
             Self::INDEX_TAG.as_ref(),
             &index,
-            Self::CIPHER_TAG.as_ref(),
-            &ciphertext_len,
-            &self.ciphertext,
         ]
         .concat()
     }
@@ -295,4 +392,43 @@ mod test {
         assert_eq!(encoded.to_mac_bytes(), message.as_ref());
         assert_eq!(encoded.to_bytes(), message_mac.as_ref());
     }
+
+    #[test]
+    fn encode_with_associated_data() {
+        let message = b"\x05\n\x20ratchetkeyhereprettyplease123456\x10\x01\"\nciphertext";
+        let mac_bytes =
+            b"\x05\n\x20ratchetkeyhereprettyplease123456\x10\x01\"\nciphertext\x07headers";
+
+        let ratchet_key = Curve25519PublicKey::from(*b"ratchetkeyhereprettyplease123456");
+        let ciphertext = b"ciphertext";
+
+        let encoded = Message::new_with_ad(ratchet_key, 1, ciphertext.to_vec());
+
+        assert!(encoded.mac_covers_associated_data());
+        assert_eq!(encoded.to_mac_bytes(), message.as_ref());
+        assert_eq!(encoded.to_mac_bytes_with_ad(b"headers"), mac_bytes.as_ref());
+
+        let decoded = Message::from_bytes(&encoded.to_bytes()).expect("Can't decode the message");
+        assert_eq!(decoded, encoded);
+    }
+
+    #[test]
+    fn encode_aead() {
+        let aead_data = b"\x06\n\x20ratchetkeyhereprettyplease123456\x10\x01\x07headers";
+        let message_tag =
+            b"\x06\n\x20ratchetkeyhereprettyplease123456\x10\x01\"\nciphertextTAGHERETAGHERE!!";
+
+        let ratchet_key = Curve25519PublicKey::from(*b"ratchetkeyhereprettyplease123456");
+
+        let mut encoded = Message::new_aead(ratchet_key, 1);
+        encoded.ciphertext = b"ciphertext".to_vec();
+        encoded.mac = (*b"TAGHERETAGHERE!!").into();
+
+        assert!(encoded.uses_aead());
+        assert_eq!(encoded.to_aead_associated_data(b"headers"), aead_data.as_ref());
+
+        let decoded = Message::from_bytes(&encoded.to_bytes()).expect("Can't decode the message");
+        assert_eq!(decoded, encoded);
+        assert_eq!(decoded.to_bytes(), message_tag.as_ref());
+    }
 }
@@ -17,7 +17,7 @@ use serde::{Deserialize, Serialize};
 
 use super::Message;
 use crate::{
-    olm::SessionKeys,
+    olm::{OneTimeKeyKind, SessionKeys},
     utilities::{base64_decode, base64_encode},
     Curve25519PublicKey, DecodeError,
 };
@@ -211,7 +211,12 @@ impl TryFrom<&[u8]> for PreKeyMessage {
 
             let message = decoded.message.try_into()?;
 
-            let session_keys = SessionKeys { one_time_key, identity_key, base_key };
+            let session_keys = SessionKeys {
+                one_time_key,
+                identity_key,
+                base_key,
+                one_time_key_kind: OneTimeKeyKind::Unknown,
+            };
 
             Ok(Self { session_keys, message })
         }
@@ -102,13 +102,25 @@ mod messages;
 pub(crate) mod session;
 mod session_config;
 mod session_keys;
+mod session_recovery;
+mod session_set;
 mod shared_secret;
 
 pub use account::{
-    Account, AccountPickle, IdentityKeys, InboundCreationResult, OneTimeKeyGenerationResult,
-    SessionCreationError,
+    Account, AccountPickle, ClaimedKeyError, ConsumedKey, DehydratedDevice, DehydratedDeviceError,
+    DehydratedDeviceKey, FallbackKeyPolicy, IdentityKeys, InboundCreationResult, OneTimeKeyConfig,
+    OneTimeKeyConfigError, OneTimeKeyEvictionStrategy, OneTimeKeyGenerationResult,
+    OutboundCreationResult, PublicSignedPreKey, SessionCreationError, SignedKey, SignedKeys,
+    SignedPreKeyPolicy,
 };
 pub use messages::{Message, MessageType, OlmMessage, PreKeyMessage};
-pub use session::{ratchet::RatchetPublicKey, DecryptionError, Session, SessionPickle};
-pub use session_config::SessionConfig;
-pub use session_keys::SessionKeys;
+pub use session::{
+    ratchet::RatchetPublicKey, DecryptedMessage, DecryptionError, PendingDecryption,
+    ReceivingChainStats, Session, SessionPickle, SessionStats,
+};
+pub use session_config::{SessionConfig, SessionConfigError};
+pub use session_keys::{OneTimeKeyKind, SessionKeys};
+pub use session_recovery::{FailureKind, FailureStreak, RecoveryAction, RecoveryPolicy};
+pub use session_set::{SessionSet, SessionSetDecryptionResult, SessionSetError, SessionSetPickle};
+
+pub use crate::cipher::{PaddingError, PaddingScheme};
@@ -71,6 +71,11 @@ impl RemoteChainKey {
         self.index
     }
 
+    #[cfg(feature = "libolm-compat")]
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
     #[cfg(feature = "libolm-compat")]
     pub fn from_bytes_and_index(bytes: Box<[u8; 32]>, index: u32) -> Self {
         Self { key: bytes, index: index.into() }
@@ -102,6 +107,15 @@ impl ChainKey {
         Self { key: bytes, index: index.into() }
     }
 
+    #[cfg(feature = "libolm-compat")]
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
     pub fn advance(&mut self) {
         let output = advance(&self.key).into_bytes();
         self.key.copy_from_slice(output.as_slice());
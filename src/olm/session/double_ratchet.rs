@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use rand::{thread_rng, CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "libolm-compat")]
+use super::ratchet::RatchetKey;
 use super::{
     chain_key::ChainKey,
     message_key::MessageKey,
@@ -21,7 +24,10 @@ use super::{
     receiver_chain::ReceiverChain,
     root_key::{RemoteRootKey, RootKey},
 };
-use crate::olm::{messages::Message, shared_secret::Shared3DHSecret};
+use crate::{
+    cipher::PaddingScheme,
+    olm::{messages::Message, shared_secret::Shared3DHSecret},
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(transparent)]
@@ -30,10 +36,10 @@ pub(super) struct DoubleRatchet {
 }
 
 impl DoubleRatchet {
-    pub fn next_message_key(&mut self) -> MessageKey {
+    pub fn next_message_key(&mut self, rng: &mut (impl RngCore + CryptoRng)) -> MessageKey {
         match &mut self.inner {
             DoubleRatchetState::Inactive(ratchet) => {
-                let mut ratchet = ratchet.activate();
+                let mut ratchet = ratchet.activate(rng);
 
                 let message_key = ratchet.next_message_key();
                 self.inner = DoubleRatchetState::Active(ratchet);
@@ -44,22 +50,54 @@ impl DoubleRatchet {
         }
     }
 
-    pub fn encrypt(&mut self, plaintext: &[u8]) -> Message {
-        self.next_message_key().encrypt(plaintext)
+    pub fn encrypt(&mut self, plaintext: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> Message {
+        self.next_message_key(rng).encrypt(plaintext)
+    }
+
+    pub fn encrypt_truncated_mac(
+        &mut self,
+        plaintext: &[u8],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Message {
+        self.next_message_key(rng).encrypt_truncated_mac(plaintext)
+    }
+
+    pub fn encrypt_with_ad(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Message {
+        self.next_message_key(rng).encrypt_with_ad(plaintext, associated_data)
     }
 
-    pub fn encrypt_truncated_mac(&mut self, plaintext: &[u8]) -> Message {
-        self.next_message_key().encrypt_truncated_mac(plaintext)
+    pub fn encrypt_aead(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Message {
+        self.next_message_key(rng).encrypt_aead(plaintext, associated_data)
     }
 
-    pub fn active(shared_secret: Shared3DHSecret) -> Self {
+    pub fn encrypt_padded(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+        padding_scheme: PaddingScheme,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Message {
+        self.next_message_key(rng).encrypt_padded(plaintext, associated_data, padding_scheme)
+    }
+
+    pub fn active(shared_secret: Shared3DHSecret, rng: &mut (impl RngCore + CryptoRng)) -> Self {
         let (root_key, chain_key) = shared_secret.expand();
 
         let root_key = RootKey::new(root_key);
         let chain_key = ChainKey::new(chain_key);
 
         let ratchet = ActiveDoubleRatchet {
-            active_ratchet: Ratchet::new(root_key),
+            active_ratchet: Ratchet::new(root_key, rng),
             symmetric_key_ratchet: chain_key,
         };
 
@@ -77,6 +115,33 @@ impl DoubleRatchet {
         }
     }
 
+    #[cfg(feature = "libolm-compat")]
+    pub fn root_key(&self) -> &[u8; 32] {
+        match &self.inner {
+            DoubleRatchetState::Inactive(r) => &r.root_key.key,
+            DoubleRatchetState::Active(r) => &r.active_ratchet.root_key().key,
+        }
+    }
+
+    #[cfg(feature = "libolm-compat")]
+    pub fn sender_chain(&self) -> Option<(&RatchetKey, &ChainKey)> {
+        match &self.inner {
+            DoubleRatchetState::Inactive(_) => None,
+            DoubleRatchetState::Active(r) => {
+                Some((r.active_ratchet.ratchet_key(), &r.symmetric_key_ratchet))
+            }
+        }
+    }
+
+    /// The index of the next message key of our sending chain, `None` if the
+    /// ratchet hasn't been activated yet.
+    pub fn sending_chain_index(&self) -> Option<u64> {
+        match &self.inner {
+            DoubleRatchetState::Inactive(_) => None,
+            DoubleRatchetState::Active(r) => Some(r.symmetric_key_ratchet.index()),
+        }
+    }
+
     pub fn inactive(root_key: RemoteRootKey, ratchet_key: RemoteRatchetKey) -> Self {
         let ratchet = InactiveDoubleRatchet { root_key, ratchet_key };
 
@@ -87,7 +152,7 @@ impl DoubleRatchet {
         let (ratchet, receiver_chain) = match &self.inner {
             DoubleRatchetState::Active(r) => r.advance(ratchet_key),
             DoubleRatchetState::Inactive(r) => {
-                let ratchet = r.activate();
+                let ratchet = r.activate(&mut thread_rng());
                 // Advancing an inactive ratchet shouldn't be possible since the
                 // other side did not yet receive our new ratchet key.
                 //
@@ -133,8 +198,8 @@ struct InactiveDoubleRatchet {
 }
 
 impl InactiveDoubleRatchet {
-    fn activate(&self) -> ActiveDoubleRatchet {
-        let (root_key, chain_key, ratchet_key) = self.root_key.advance(&self.ratchet_key);
+    fn activate(&self, rng: &mut (impl RngCore + CryptoRng)) -> ActiveDoubleRatchet {
+        let (root_key, chain_key, ratchet_key) = self.root_key.advance(&self.ratchet_key, rng);
         let active_ratchet = Ratchet::new_with_ratchet_key(root_key, ratchet_key);
 
         ActiveDoubleRatchet { active_ratchet, symmetric_key_ratchet: chain_key }
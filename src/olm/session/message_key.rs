@@ -17,7 +17,7 @@ use zeroize::Zeroize;
 
 use super::{ratchet::RatchetPublicKey, DecryptionError};
 use crate::{
-    cipher::{Cipher, Mac},
+    cipher::{unpad, AeadCipher, Cipher, Mac, MessageMac, PaddingScheme},
     olm::messages::Message,
 };
 
@@ -77,6 +77,56 @@ impl MessageKey {
         message
     }
 
+    pub fn encrypt_with_ad(self, plaintext: &[u8], associated_data: &[u8]) -> Message {
+        let cipher = Cipher::new(&self.key);
+
+        let ciphertext = cipher.encrypt(plaintext);
+
+        let mut message = Message::new_with_ad(*self.ratchet_key.as_ref(), self.index, ciphertext);
+
+        let mac = cipher.mac(&message.to_mac_bytes_with_ad(associated_data));
+        message.set_mac(mac);
+
+        message
+    }
+
+    pub fn encrypt_aead(self, plaintext: &[u8], associated_data: &[u8]) -> Message {
+        let message = Message::new_aead(*self.ratchet_key.as_ref(), self.index);
+
+        self.encrypt_aead_helper(message, plaintext, associated_data)
+    }
+
+    pub fn encrypt_padded(
+        self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+        padding_scheme: PaddingScheme,
+    ) -> Message {
+        let message = Message::new_padded(*self.ratchet_key.as_ref(), self.index);
+        let mut padded = padding_scheme.pad(plaintext);
+
+        let message = self.encrypt_aead_helper(message, &padded, associated_data);
+        padded.zeroize();
+
+        message
+    }
+
+    fn encrypt_aead_helper(
+        self,
+        mut message: Message,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Message {
+        let cipher = AeadCipher::new(&self.key);
+
+        let (ciphertext, tag) =
+            cipher.encrypt(plaintext, &message.to_aead_associated_data(associated_data));
+        message.ciphertext = ciphertext;
+        message.mac = tag.into();
+
+        message
+    }
+
     /// Get a reference to the message key's key.
     #[cfg(feature = "low-level-api")]
     pub fn key(&self) -> &[u8; 32] {
@@ -108,22 +158,90 @@ impl RemoteMessageKey {
     pub fn decrypt_truncated_mac(&self, message: &Message) -> Result<Vec<u8>, DecryptionError> {
         let cipher = Cipher::new(&self.key);
 
-        if let crate::cipher::MessageMac::Truncated(m) = &message.mac {
+        if let MessageMac::Truncated(m) = &message.mac {
             cipher.verify_truncated_mac(&message.to_mac_bytes(), m)?;
             Ok(cipher.decrypt(&message.ciphertext)?)
         } else {
-            Err(DecryptionError::InvalidMACLength(Mac::TRUNCATED_LEN, Mac::LENGTH))
+            Err(DecryptionError::InvalidMACLength(Mac::TRUNCATED_LEN, message.mac.as_bytes().len()))
         }
     }
 
     pub fn decrypt(&self, message: &Message) -> Result<Vec<u8>, DecryptionError> {
         let cipher = Cipher::new(&self.key);
 
-        if let crate::cipher::MessageMac::Full(m) = &message.mac {
+        if let MessageMac::Full(m) = &message.mac {
             cipher.verify_mac(&message.to_mac_bytes(), m)?;
             Ok(cipher.decrypt(&message.ciphertext)?)
         } else {
-            Err(DecryptionError::InvalidMACLength(Mac::LENGTH, Mac::TRUNCATED_LEN))
+            Err(DecryptionError::InvalidMACLength(Mac::LENGTH, message.mac.as_bytes().len()))
+        }
+    }
+
+    pub fn decrypt_with_ad(
+        &self,
+        message: &Message,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DecryptionError> {
+        let cipher = Cipher::new(&self.key);
+
+        if let MessageMac::Full(m) = &message.mac {
+            // A tampered message can't be told apart from mismatching associated
+            // data, the error covers both.
+            cipher.verify_mac(&message.to_mac_bytes_with_ad(associated_data), m).map_err(|e| {
+                if message.mac_covers_associated_data() {
+                    DecryptionError::AssociatedDataMismatch
+                } else {
+                    DecryptionError::InvalidMAC(e)
+                }
+            })?;
+            Ok(cipher.decrypt(&message.ciphertext)?)
+        } else {
+            Err(DecryptionError::InvalidMACLength(Mac::LENGTH, message.mac.as_bytes().len()))
+        }
+    }
+
+    pub fn decrypt_aead(
+        &self,
+        message: &Message,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DecryptionError> {
+        if message.is_padded() {
+            Err(DecryptionError::MessageVersionMismatch(message.version()))
+        } else {
+            self.decrypt_aead_helper(message, associated_data)
+        }
+    }
+
+    pub fn decrypt_padded(
+        &self,
+        message: &Message,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DecryptionError> {
+        if message.is_padded() {
+            Ok(unpad(self.decrypt_aead_helper(message, associated_data)?)?)
+        } else {
+            Err(DecryptionError::MessageVersionMismatch(message.version()))
+        }
+    }
+
+    fn decrypt_aead_helper(
+        &self,
+        message: &Message,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DecryptionError> {
+        let cipher = AeadCipher::new(&self.key);
+
+        if let MessageMac::Tag(t) = &message.mac {
+            // Like for a MAC covering associated data, a tampered message can't
+            // be told apart from mismatching associated data.
+            cipher
+                .decrypt(&message.ciphertext, t, &message.to_aead_associated_data(associated_data))
+                .map_err(|_| DecryptionError::AssociatedDataMismatch)
+        } else {
+            Err(DecryptionError::InvalidMACLength(
+                AeadCipher::TAG_LENGTH,
+                message.mac.as_bytes().len(),
+            ))
         }
     }
 }
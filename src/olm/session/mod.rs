@@ -21,19 +21,18 @@ mod receiver_chain;
 mod root_key;
 
 use aes::cipher::block_padding::UnpadError;
-use arrayvec::ArrayVec;
 use chain_key::RemoteChainKey;
 use double_ratchet::DoubleRatchet;
 use hmac::digest::MacError;
+use rand::{thread_rng, CryptoRng, RngCore};
 use ratchet::RemoteRatchetKey;
-use receiver_chain::ReceiverChain;
+use receiver_chain::{ChainUpdate, ReceiverChain};
 use root_key::RemoteRootKey;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use zeroize::Zeroize;
 
 use super::{
-    session_config::Version,
+    session_config::{SessionConfigError, Version},
     session_keys::SessionKeys,
     shared_secret::{RemoteShared3DHSecret, Shared3DHSecret},
     SessionConfig,
@@ -41,13 +40,12 @@ use super::{
 #[cfg(feature = "low-level-api")]
 use crate::hazmat::olm::MessageKey;
 use crate::{
+    cipher::PaddingError,
     olm::messages::{Message, OlmMessage, PreKeyMessage},
     utilities::{pickle, unpickle},
     Curve25519PublicKey, PickleError,
 };
 
-const MAX_RECEIVING_CHAINS: usize = 5;
-
 /// Error type for Olm-based decryption failuers.
 #[derive(Error, Debug)]
 pub enum DecryptionError {
@@ -61,6 +59,10 @@ pub enum DecryptionError {
     /// The ciphertext of the message isn't padded correctly.
     #[error("Failed decrypting Olm message, invalid padding")]
     InvalidPadding(#[from] UnpadError),
+    /// The plaintext of a message using a padded Olm version isn't padded
+    /// correctly.
+    #[error("Failed decrypting Olm message, invalid plaintext padding: {0}")]
+    InvalidPlaintextPadding(#[from] PaddingError),
     /// The session is missing the correct message key to decrypt the message,
     /// either because it was already used up, or because the Session has been
     /// ratcheted forwards and the message key has been discarded.
@@ -69,22 +71,38 @@ pub enum DecryptionError {
     /// Too many messages have been skipped to attempt decrypting this message.
     #[error("The message gap was too big, got {0}, max allowed {1}")]
     TooBigMessageGap(u64, u64),
+    /// The MAC or the authentication tag of a message covering associated data
+    /// was invalid. Either the
+    /// associated data differs from the one the message was encrypted with,
+    /// or the message was tampered with or encrypted using a different
+    /// session.
+    #[error("Failed decrypting Olm message, the associated data doesn't match")]
+    AssociatedDataMismatch,
+    /// Associated data was given, but the session uses an Olm version whose
+    /// messages don't authenticate associated data.
+    #[error("Failed decrypting Olm message, Olm version {0} doesn't support associated data")]
+    AssociatedDataUnsupported(u8),
+    /// The message uses a message version that doesn't match the Olm version
+    /// of the session.
+    #[error("Failed decrypting Olm message, the message version {0} doesn't match the session")]
+    MessageVersionMismatch(u8),
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct ChainStore {
-    inner: ArrayVec<ReceiverChain, MAX_RECEIVING_CHAINS>,
+    inner: Vec<ReceiverChain>,
 }
 
 impl ChainStore {
     fn new() -> Self {
-        Self { inner: ArrayVec::new() }
+        Self { inner: Vec::new() }
     }
 
-    fn push(&mut self, ratchet: ReceiverChain) {
-        if self.inner.is_full() {
-            self.inner.pop_at(0);
-        }
+    /// Store a receiving chain, removing the oldest chains if more than
+    /// `max_chains` chains would be stored.
+    fn push(&mut self, ratchet: ReceiverChain, max_chains: usize) {
+        let excess = (self.inner.len() + 1).saturating_sub(max_chains);
+        self.inner.drain(..excess.min(self.inner.len()));
 
         self.inner.push(ratchet)
     }
@@ -99,11 +117,15 @@ impl ChainStore {
     }
 
     #[cfg(feature = "libolm-compat")]
-    pub fn get(&self, index: usize) -> Option<&ReceiverChain> {
-        self.inner.get(index)
+    pub fn newest(&self) -> Option<&ReceiverChain> {
+        self.inner.last()
+    }
+
+    fn find_ratchet(&self, ratchet_key: &RemoteRatchetKey) -> Option<&ReceiverChain> {
+        self.inner.iter().find(|r| r.belongs_to(ratchet_key))
     }
 
-    fn find_ratchet(&mut self, ratchet_key: &RemoteRatchetKey) -> Option<&mut ReceiverChain> {
+    fn find_ratchet_mut(&mut self, ratchet_key: &RemoteRatchetKey) -> Option<&mut ReceiverChain> {
         self.inner.iter_mut().find(|r| r.belongs_to(ratchet_key))
     }
 }
@@ -114,6 +136,129 @@ impl Default for ChainStore {
     }
 }
 
+/// The changes a successful decryption makes to a [`Session`].
+enum SessionUpdate {
+    /// The message was decrypted using one of our existing receiving chains.
+    ExistingChain { ratchet_key: RemoteRatchetKey, update: ChainUpdate },
+    /// The message was encrypted using a new ratchet key of the other side, a
+    /// new receiving chain is created and our sending ratchet advanced.
+    NewChain(Box<(DoubleRatchet, ReceiverChain)>),
+}
+
+impl SessionUpdate {
+    fn used_skipped_message_key(&self) -> bool {
+        matches!(
+            self,
+            SessionUpdate::ExistingChain { update: ChainUpdate::RemoveSkippedKey(_), .. }
+        )
+    }
+
+    fn created_receiving_chain(&self) -> bool {
+        matches!(self, SessionUpdate::NewChain(_))
+    }
+}
+
+/// Statistics about the state of a [`Session`], returned by
+/// [`Session::stats()`].
+///
+/// This is the equivalent of libolm's `olm_session_describe()`, it contains no
+/// key material and is safe to log or to include in bug reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionStats {
+    /// The ID of the session.
+    pub session_id: String,
+    /// Is our sending ratchet active. The ratchet gets activated when we
+    /// encrypt the first message after having received a new ratchet key from
+    /// the other side.
+    pub sending_ratchet_active: bool,
+    /// The index of the next message key of our sending chain, `None` if the
+    /// sending ratchet isn't active.
+    pub sending_chain_index: Option<u64>,
+    /// The receiving chains of the session, the oldest one first.
+    pub receiving_chains: Vec<ReceivingChainStats>,
+    /// The number of messages this session encrypted.
+    pub messages_sent: u64,
+    /// The number of messages this session decrypted.
+    pub messages_received: u64,
+}
+
+/// Statistics about a receiving chain of a [`Session`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivingChainStats {
+    /// The public ratchet key of the other side this chain belongs to.
+    pub ratchet_key: Curve25519PublicKey,
+    /// The index of the next message key of the chain.
+    pub chain_index: u64,
+    /// The number of message keys which were skipped and are stored to
+    /// decrypt messages arriving out of order.
+    pub skipped_message_keys: usize,
+}
+
+/// The number of messages a [`Session`] encrypted and decrypted.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+struct MessageCounters {
+    sent: u64,
+    received: u64,
+}
+
+/// The plaintext of an Olm message decrypted by
+/// [`Session::decrypt_with_info()`] together with some information about how
+/// the message was decrypted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptedMessage {
+    /// The decrypted plaintext.
+    pub plaintext: Vec<u8>,
+    /// The public part of the ratchet key the other side used to encrypt the
+    /// message.
+    pub ratchet_key: Curve25519PublicKey,
+    /// The index of the message in the chain of the ratchet key.
+    pub chain_index: u64,
+    /// Was the message decrypted using a message key we skipped earlier, i.e.
+    /// did the message arrive out of order.
+    pub out_of_order: bool,
+    /// Did the message contain a new ratchet key of the other side, i.e. did
+    /// decrypting it perform a Diffie-Hellman ratchet step and create a new
+    /// receiving chain.
+    pub new_receiving_chain: bool,
+}
+
+/// A message that was decrypted by [`Session::decrypt_pending()`], without the
+/// [`Session`] having been modified yet.
+///
+/// The [`Session`] is only advanced once [`PendingDecryption::commit()`] is
+/// called. If the `PendingDecryption` is dropped instead, for example because
+/// the plaintext couldn't be processed or persisted, the [`Session`] stays
+/// unchanged and the message can be decrypted again.
+pub struct PendingDecryption<'a> {
+    session: &'a mut Session,
+    plaintext: Vec<u8>,
+    update: SessionUpdate,
+}
+
+impl PendingDecryption<'_> {
+    /// The plaintext of the decrypted message.
+    pub fn plaintext(&self) -> &[u8] {
+        &self.plaintext
+    }
+
+    /// Apply the changes of the decryption to the [`Session`] and return the
+    /// plaintext.
+    ///
+    /// Once committed, the message key that was used to decrypt the message is
+    /// gone, the message can't be decrypted again.
+    pub fn commit(self) -> Vec<u8> {
+        self.session.apply_update(self.update);
+
+        self.plaintext
+    }
+}
+
+impl std::fmt::Debug for PendingDecryption<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PendingDecryption").field("session", &self.session).finish_non_exhaustive()
+    }
+}
+
 /// An Olm session represents one end of an encrypted communication channel
 /// between two participants.
 ///
@@ -148,6 +293,7 @@ pub struct Session {
     sending_ratchet: DoubleRatchet,
     receiving_chains: ChainStore,
     config: SessionConfig,
+    message_counters: MessageCounters,
 }
 
 impl std::fmt::Debug for Session {
@@ -161,14 +307,16 @@ impl Session {
         config: SessionConfig,
         shared_secret: Shared3DHSecret,
         session_keys: SessionKeys,
+        rng: &mut (impl RngCore + CryptoRng),
     ) -> Self {
-        let local_ratchet = DoubleRatchet::active(shared_secret);
+        let local_ratchet = DoubleRatchet::active(shared_secret, rng);
 
         Self {
             session_keys,
             sending_ratchet: local_ratchet,
             receiving_chains: Default::default(),
             config,
+            message_counters: Default::default(),
         }
     }
 
@@ -188,13 +336,14 @@ impl Session {
         let remote_ratchet = ReceiverChain::new(remote_ratchet_key, remote_chain_key);
 
         let mut ratchet_store = ChainStore::new();
-        ratchet_store.push(remote_ratchet);
+        ratchet_store.push(remote_ratchet, config.max_receiving_chains());
 
         Self {
             session_keys,
             sending_ratchet: local_ratchet,
             receiving_chains: ratchet_store,
             config,
+            message_counters: Default::default(),
         }
     }
 
@@ -213,6 +362,69 @@ impl Session {
         !self.receiving_chains.is_empty()
     }
 
+    /// The number of messages we encrypted since our last Diffie-Hellman
+    /// ratchet step.
+    ///
+    /// This is only an indicator, there's no method to force a new ratchet
+    /// key on a `Session`.
+    ///
+    /// Only a Diffie-Hellman ratchet step heals the session after a
+    /// compromise of its keys, i.e. provides post-compromise security. A step
+    /// can't be forced by the sending side alone, the other side throws away
+    /// the private part of its ratchet key once it has received our current
+    /// one. Instead, we perform a step with the first message we encrypt
+    /// after having received a message with a new ratchet key.
+    ///
+    /// A session that is only used to send messages never heals. If this
+    /// number grows large, the other side should be asked to reply, for
+    /// example with a dummy message. Returns 0 if the next message we encrypt
+    /// will perform a ratchet step.
+    pub fn messages_since_ratchet_step(&self) -> u64 {
+        self.sending_ratchet.sending_chain_index().unwrap_or(0)
+    }
+
+    /// Get statistics about the state of the session, like the state of the
+    /// ratchets and the number of skipped message keys.
+    ///
+    /// The statistics don't contain any key material, they are meant to help
+    /// diagnose why messages can't be decrypted.
+    pub fn stats(&self) -> SessionStats {
+        let sending_chain_index = self.sending_ratchet.sending_chain_index();
+
+        SessionStats {
+            session_id: self.session_id(),
+            sending_ratchet_active: sending_chain_index.is_some(),
+            sending_chain_index,
+            receiving_chains: self
+                .receiving_chains
+                .inner
+                .iter()
+                .map(|c| ReceivingChainStats {
+                    ratchet_key: *c.ratchet_key().as_ref(),
+                    chain_index: c.chain_index(),
+                    skipped_message_keys: c.skipped_message_key_count(),
+                })
+                .collect(),
+            messages_sent: self.message_counters.sent,
+            messages_received: self.message_counters.received,
+        }
+    }
+
+    /// Check if the given pre-key message belongs to this session, i.e. if it
+    /// was sent using the keys that established this session.
+    ///
+    /// This is the equivalent of libolm's `olm_matches_inbound_session()`.
+    /// Pre-key messages for an existing session should be decrypted by the
+    /// session, creating a new session using
+    /// [`Account::create_inbound_session()`] would either fail, since the
+    /// one-time key has already been used up, or create a second session for
+    /// the same pre-key message.
+    ///
+    /// [`Account::create_inbound_session()`]: crate::olm::Account::create_inbound_session
+    pub fn matches_pre_key_message(&self, message: &PreKeyMessage) -> bool {
+        self.session_keys == message.session_keys()
+    }
+
     /// Encrypt the `plaintext` and construct an [`OlmMessage`].
     ///
     /// The message will either be a pre-key message or a normal message,
@@ -220,11 +432,63 @@ impl Session {
     /// fully established once you receive (and decrypt) at least one
     /// message from the other side.
     pub fn encrypt(&mut self, plaintext: impl AsRef<[u8]>) -> OlmMessage {
+        self.encrypt_with_rng(plaintext, &mut thread_rng())
+    }
+
+    /// Encrypt the `plaintext` and construct an [`OlmMessage`], using the given
+    /// random number generator if a new ratchet key needs to be created.
+    ///
+    /// This behaves exactly like [`Session::encrypt()`] and is only useful to
+    /// create deterministic test vectors.
+    pub fn encrypt_with_rng(
+        &mut self,
+        plaintext: impl AsRef<[u8]>,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> OlmMessage {
+        self.encrypt_helper(plaintext.as_ref(), &[], rng)
+    }
+
+    /// Encrypt the `plaintext` and construct an [`OlmMessage`] whose MAC
+    /// additionally covers the given `associated_data`.
+    ///
+    /// The associated data isn't part of the message, the other side needs to
+    /// pass the same associated data to [`Session::decrypt_with_ad()`] to be
+    /// able to decrypt the message. Only sessions using
+    /// [`SessionConfig::version_3()`] or a later version
+    /// support associated data.
+    pub fn encrypt_with_ad(
+        &mut self,
+        plaintext: impl AsRef<[u8]>,
+        associated_data: impl AsRef<[u8]>,
+    ) -> Result<OlmMessage, SessionConfigError> {
+        if self.config.supports_associated_data() {
+            Ok(self.encrypt_helper(plaintext.as_ref(), associated_data.as_ref(), &mut thread_rng()))
+        } else {
+            Err(SessionConfigError::AssociatedDataUnsupported(self.config.version()))
+        }
+    }
+
+    fn encrypt_helper(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> OlmMessage {
         let message = match self.config.version {
-            Version::V1 => self.sending_ratchet.encrypt_truncated_mac(plaintext.as_ref()),
-            Version::V2 => self.sending_ratchet.encrypt(plaintext.as_ref()),
+            Version::V1 => self.sending_ratchet.encrypt_truncated_mac(plaintext, rng),
+            Version::V2 => self.sending_ratchet.encrypt(plaintext, rng),
+            Version::V3 => self.sending_ratchet.encrypt_with_ad(plaintext, associated_data, rng),
+            Version::V4 => self.sending_ratchet.encrypt_aead(plaintext, associated_data, rng),
+            Version::V5 => self.sending_ratchet.encrypt_padded(
+                plaintext,
+                associated_data,
+                self.config.padding_scheme().unwrap_or_default(),
+                rng,
+            ),
         };
 
+        self.message_counters.sent = self.message_counters.sent.saturating_add(1);
+
         if self.has_received_message() {
             OlmMessage::Normal(message)
         } else {
@@ -243,6 +507,28 @@ impl Session {
         self.config
     }
 
+    /// Replace the [`SessionConfig`] of this session, changing the limits on
+    /// skipped messages and receiving chains or the padding scheme.
+    ///
+    /// Sessions created by [`Account::create_inbound_session()`] use the
+    /// default limits and padding scheme, this allows them to be changed
+    /// afterwards. Lowered
+    /// limits take effect once the next message key or receiving chain gets
+    /// stored.
+    ///
+    /// The version of the config needs to match the version of the session,
+    /// the version of an existing session can't be changed.
+    ///
+    /// [`Account::create_inbound_session()`]: crate::olm::Account::create_inbound_session
+    pub fn set_session_config(&mut self, config: SessionConfig) -> Result<(), SessionConfigError> {
+        if config.version != self.config.version {
+            Err(SessionConfigError::VersionMismatch(self.config.version(), config.version()))
+        } else {
+            self.config = config;
+            Ok(())
+        }
+    }
+
     /// Get the [`MessageKey`] to encrypt the next message.
     ///
     /// **Note**: Each key obtained in this way should be used to encrypt
@@ -254,7 +540,8 @@ impl Session {
     /// undecryptable messages.
     #[cfg(feature = "low-level-api")]
     pub fn next_message_key(&mut self) -> MessageKey {
-        self.sending_ratchet.next_message_key()
+        self.message_counters.sent = self.message_counters.sent.saturating_add(1);
+        self.sending_ratchet.next_message_key(&mut thread_rng())
     }
 
     /// Try to decrypt an Olm message, which will either return the plaintext or
@@ -270,23 +557,143 @@ impl Session {
         Ok(decrypted)
     }
 
+    /// Try to decrypt an Olm message whose MAC covers the given
+    /// `associated_data`, which will either return the plaintext or result in
+    /// a [`DecryptionError`].
+    ///
+    /// The associated data needs to be the same as the one that was passed to
+    /// [`Session::encrypt_with_ad()`], otherwise decryption fails with
+    /// [`DecryptionError::AssociatedDataMismatch`]. Only sessions using
+    /// [`SessionConfig::version_3()`] or a later version
+    /// support associated data.
+    pub fn decrypt_with_ad(
+        &mut self,
+        message: &OlmMessage,
+        associated_data: impl AsRef<[u8]>,
+    ) -> Result<Vec<u8>, DecryptionError> {
+        let message = match message {
+            OlmMessage::Normal(m) => m,
+            OlmMessage::PreKey(m) => &m.message,
+        };
+
+        self.decrypt_decoded_with_ad(message, associated_data.as_ref())
+    }
+
+    /// Try to decrypt an Olm message, returning the plaintext together with
+    /// information about the ratchet state that was used to decrypt it.
+    ///
+    /// This behaves exactly like [`Session::decrypt()`], the returned
+    /// [`DecryptedMessage`] contains only public information about the
+    /// message, no key material.
+    pub fn decrypt_with_info(
+        &mut self,
+        message: &OlmMessage,
+    ) -> Result<DecryptedMessage, DecryptionError> {
+        let message = match message {
+            OlmMessage::Normal(m) => m,
+            OlmMessage::PreKey(m) => &m.message,
+        };
+
+        let (plaintext, update) = self.decrypt_pending_decoded(message, &[])?;
+
+        let decrypted = DecryptedMessage {
+            plaintext,
+            ratchet_key: message.ratchet_key,
+            chain_index: message.chain_index,
+            out_of_order: update.used_skipped_message_key(),
+            new_receiving_chain: update.created_receiving_chain(),
+        };
+
+        self.apply_update(update);
+
+        Ok(decrypted)
+    }
+
+    /// Try to decrypt an Olm message without advancing the [`Session`].
+    ///
+    /// This behaves like [`Session::decrypt()`], but the changes to the
+    /// ratchet state are only applied once the returned
+    /// [`PendingDecryption`] gets committed. This allows the plaintext to be
+    /// processed and persisted before the message key gets thrown away. If
+    /// that fails, dropping the [`PendingDecryption`] leaves the [`Session`]
+    /// as it was, and the message can be decrypted again later on.
+    pub fn decrypt_pending(
+        &mut self,
+        message: &OlmMessage,
+    ) -> Result<PendingDecryption<'_>, DecryptionError> {
+        let message = match message {
+            OlmMessage::Normal(m) => m,
+            OlmMessage::PreKey(m) => &m.message,
+        };
+
+        let (plaintext, update) = self.decrypt_pending_decoded(message, &[])?;
+
+        Ok(PendingDecryption { session: self, plaintext, update })
+    }
+
     pub(super) fn decrypt_decoded(
         &mut self,
         message: &Message,
     ) -> Result<Vec<u8>, DecryptionError> {
+        let (plaintext, update) = self.decrypt_pending_decoded(message, &[])?;
+        self.apply_update(update);
+
+        Ok(plaintext)
+    }
+
+    pub(super) fn decrypt_decoded_with_ad(
+        &mut self,
+        message: &Message,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, DecryptionError> {
+        if self.config.supports_associated_data() {
+            let (plaintext, update) = self.decrypt_pending_decoded(message, associated_data)?;
+            self.apply_update(update);
+
+            Ok(plaintext)
+        } else {
+            Err(DecryptionError::AssociatedDataUnsupported(self.config.version()))
+        }
+    }
+
+    fn decrypt_pending_decoded(
+        &mut self,
+        message: &Message,
+        associated_data: &[u8],
+    ) -> Result<(Vec<u8>, SessionUpdate), DecryptionError> {
         let ratchet_key = RemoteRatchetKey::from(message.ratchet_key);
 
         if let Some(ratchet) = self.receiving_chains.find_ratchet(&ratchet_key) {
-            ratchet.decrypt(message, &self.config)
+            let (plaintext, update) =
+                ratchet.decrypt_pending(message, associated_data, &self.config)?;
+
+            Ok((plaintext, SessionUpdate::ExistingChain { ratchet_key, update }))
         } else {
             let (sending_ratchet, mut remote_ratchet) = self.sending_ratchet.advance(ratchet_key);
 
-            let plaintext = remote_ratchet.decrypt(message, &self.config)?;
+            let (plaintext, update) =
+                remote_ratchet.decrypt_pending(message, associated_data, &self.config)?;
+            remote_ratchet.apply(update, &self.config);
+
+            Ok((plaintext, SessionUpdate::NewChain(Box::new((sending_ratchet, remote_ratchet)))))
+        }
+    }
 
-            self.sending_ratchet = sending_ratchet;
-            self.receiving_chains.push(remote_ratchet);
+    fn apply_update(&mut self, update: SessionUpdate) {
+        self.message_counters.received = self.message_counters.received.saturating_add(1);
 
-            Ok(plaintext)
+        match update {
+            SessionUpdate::ExistingChain { ratchet_key, update } => {
+                if let Some(ratchet) = self.receiving_chains.find_ratchet_mut(&ratchet_key) {
+                    ratchet.apply(update, &self.config);
+                }
+            }
+            SessionUpdate::NewChain(update) => {
+                let (sending_ratchet, remote_ratchet) = *update;
+
+                self.sending_ratchet = sending_ratchet;
+                self.receiving_chains.push(remote_ratchet, self.config.max_receiving_chains());
+            }
         }
     }
 
@@ -298,6 +705,7 @@ impl Session {
             sending_ratchet: self.sending_ratchet.clone(),
             receiving_chains: self.receiving_chains.clone(),
             config: self.config,
+            message_counters: self.message_counters,
         }
     }
 
@@ -311,149 +719,40 @@ impl Session {
     ///
     /// Such pickles are encrypted and need to first be decrypted using
     /// `pickle_key`.
+    ///
+    /// Both the current libolm session pickle format and the older format,
+    /// which stored an additional chain index, are supported.
     #[cfg(feature = "libolm-compat")]
     pub fn from_libolm_pickle(
         pickle: &str,
         pickle_key: &[u8],
     ) -> Result<Self, crate::LibolmPickleError> {
-        use chain_key::ChainKey;
-        use matrix_pickle::Decode;
-        use message_key::RemoteMessageKey;
-        use ratchet::{Ratchet, RatchetKey};
-        use root_key::RootKey;
-
-        use crate::{types::Curve25519SecretKey, utilities::unpickle_libolm};
-
-        #[derive(Debug, Decode, Zeroize)]
-        #[zeroize(drop)]
-        struct SenderChain {
-            public_ratchet_key: [u8; 32],
-            #[secret]
-            secret_ratchet_key: Box<[u8; 32]>,
-            chain_key: Box<[u8; 32]>,
-            chain_key_index: u32,
-        }
-
-        #[derive(Debug, Decode, Zeroize)]
-        #[zeroize(drop)]
-        struct ReceivingChain {
-            public_ratchet_key: [u8; 32],
-            #[secret]
-            chain_key: Box<[u8; 32]>,
-            chain_key_index: u32,
-        }
+        use self::libolm::{Pickle, PICKLE_VERSIONS};
+        use crate::utilities::unpickle_libolm;
 
-        impl From<&ReceivingChain> for ReceiverChain {
-            fn from(chain: &ReceivingChain) -> Self {
-                let ratchet_key = RemoteRatchetKey::from(chain.public_ratchet_key);
-                let chain_key = RemoteChainKey::from_bytes_and_index(
-                    chain.chain_key.clone(),
-                    chain.chain_key_index,
-                );
-
-                ReceiverChain::new(ratchet_key, chain_key)
-            }
-        }
-
-        #[derive(Debug, Decode, Zeroize)]
-        #[zeroize(drop)]
-        struct MessageKey {
-            ratchet_key: [u8; 32],
-            #[secret]
-            message_key: Box<[u8; 32]>,
-            index: u32,
-        }
-
-        impl From<&MessageKey> for RemoteMessageKey {
-            fn from(key: &MessageKey) -> Self {
-                RemoteMessageKey { key: key.message_key.clone(), index: key.index.into() }
-            }
-        }
-
-        #[derive(Decode)]
-        struct Pickle {
-            #[allow(dead_code)]
-            version: u32,
-            #[allow(dead_code)]
-            received_message: bool,
-            session_keys: SessionKeys,
-            #[secret]
-            root_key: Box<[u8; 32]>,
-            sender_chains: Vec<SenderChain>,
-            receiver_chains: Vec<ReceivingChain>,
-            message_keys: Vec<MessageKey>,
-        }
-
-        impl Drop for Pickle {
-            fn drop(&mut self) {
-                self.root_key.zeroize();
-                self.sender_chains.zeroize();
-                self.receiver_chains.zeroize();
-                self.message_keys.zeroize();
-            }
-        }
-
-        impl TryFrom<Pickle> for Session {
-            type Error = crate::LibolmPickleError;
-
-            fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
-                let mut receiving_chains = ChainStore::new();
-
-                for chain in &pickle.receiver_chains {
-                    receiving_chains.push(chain.into())
-                }
-
-                for key in &pickle.message_keys {
-                    let ratchet_key =
-                        RemoteRatchetKey::from(Curve25519PublicKey::from(key.ratchet_key));
-
-                    if let Some(receiving_chain) = receiving_chains.find_ratchet(&ratchet_key) {
-                        receiving_chain.insert_message_key(key.into())
-                    }
-                }
+        unpickle_libolm::<Pickle, _>(pickle, pickle_key, PICKLE_VERSIONS)
+    }
 
-                if let Some(chain) = pickle.sender_chains.get(0) {
-                    // XXX: Passing in secret array as value.
-                    let ratchet_key = RatchetKey::from(Curve25519SecretKey::from_slice(
-                        chain.secret_ratchet_key.as_ref(),
-                    ));
-                    let chain_key = ChainKey::from_bytes_and_index(
-                        chain.chain_key.clone(),
-                        chain.chain_key_index,
-                    );
-
-                    let root_key = RootKey::new(pickle.root_key.clone());
-
-                    let ratchet = Ratchet::new_with_ratchet_key(root_key, ratchet_key);
-                    let sending_ratchet =
-                        DoubleRatchet::from_ratchet_and_chain_key(ratchet, chain_key);
-
-                    Ok(Self {
-                        session_keys: pickle.session_keys,
-                        sending_ratchet,
-                        receiving_chains,
-                        config: SessionConfig::version_1(),
-                    })
-                } else if let Some(chain) = receiving_chains.get(0) {
-                    let sending_ratchet = DoubleRatchet::inactive(
-                        RemoteRootKey::new(pickle.root_key.clone()),
-                        chain.ratchet_key(),
-                    );
-
-                    Ok(Self {
-                        session_keys: pickle.session_keys,
-                        sending_ratchet,
-                        receiving_chains,
-                        config: SessionConfig::version_1(),
-                    })
-                } else {
-                    Err(crate::LibolmPickleError::InvalidSession)
-                }
-            }
-        }
+    /// Pickle a [`Session`] into the libolm legacy pickle format.
+    ///
+    /// The pickle can be restored using the [`Session::from_libolm_pickle`]
+    /// method, or can be used in the [`libolm`] C library.
+    ///
+    /// The pickle will be encrypted using the pickle key.
+    ///
+    /// Only sessions using version 1 of the [`SessionConfig`] can be pickled,
+    /// since libolm doesn't support any other version.
+    ///
+    /// *Note*: This method might be lossy, libolm can hold fewer skipped
+    /// message keys than vodozemac, the oldest ones will be discarded.
+    ///
+    /// [`libolm`]: https://gitlab.matrix.org/matrix-org/olm/
+    #[cfg(feature = "libolm-compat")]
+    pub fn to_libolm_pickle(&self, pickle_key: &[u8]) -> Result<String, crate::LibolmPickleError> {
+        use self::libolm::Pickle;
+        use crate::utilities::pickle_libolm;
 
-        const PICKLE_VERSION: u32 = 1;
-        unpickle_libolm::<Pickle, _>(pickle, pickle_key, PICKLE_VERSION)
+        pickle_libolm::<Pickle>(self.try_into()?, pickle_key)
     }
 }
 
@@ -466,6 +765,8 @@ pub struct SessionPickle {
     receiving_chains: ChainStore,
     #[serde(default = "default_config")]
     config: SessionConfig,
+    #[serde(default)]
+    message_counters: MessageCounters,
 }
 
 fn default_config() -> SessionConfig {
@@ -496,13 +797,270 @@ impl From<SessionPickle> for Session {
             sending_ratchet: pickle.sending_ratchet,
             receiving_chains: pickle.receiving_chains,
             config: pickle.config,
+            message_counters: pickle.message_counters,
+        }
+    }
+}
+
+#[cfg(feature = "libolm-compat")]
+mod libolm {
+    use matrix_pickle::{Decode, DecodeError, Encode};
+    use zeroize::Zeroize;
+
+    use super::{
+        chain_key::{ChainKey, RemoteChainKey},
+        double_ratchet::DoubleRatchet,
+        message_key::RemoteMessageKey,
+        ratchet::{Ratchet, RatchetKey, RatchetPublicKey, RemoteRatchetKey},
+        receiver_chain::ReceiverChain,
+        root_key::{RemoteRootKey, RootKey},
+        ChainStore, Session,
+    };
+    use crate::{
+        olm::{session_config::Version, SessionConfig, SessionKeys},
+        types::Curve25519SecretKey,
+        Curve25519PublicKey, LibolmPickleError,
+    };
+
+    pub(super) const PICKLE_VERSION: u32 = 1;
+
+    /// An older version of the session pickle which stored a, now unused,
+    /// chain index after the skipped message keys.
+    const CHAIN_INDEX_PICKLE_VERSION: u32 = 0x8000_0001;
+
+    pub(super) const PICKLE_VERSIONS: &[u32] = &[PICKLE_VERSION, CHAIN_INDEX_PICKLE_VERSION];
+
+    /// The maximum number of skipped message keys a libolm session can hold.
+    const MAX_MESSAGE_KEYS: usize = 40;
+
+    #[derive(Debug, Encode, Decode, Zeroize)]
+    #[zeroize(drop)]
+    struct SenderChain {
+        public_ratchet_key: [u8; 32],
+        #[secret]
+        secret_ratchet_key: Box<[u8; 32]>,
+        chain_key: Box<[u8; 32]>,
+        chain_key_index: u32,
+    }
+
+    impl From<(&RatchetKey, &ChainKey)> for SenderChain {
+        fn from((ratchet_key, chain_key): (&RatchetKey, &ChainKey)) -> Self {
+            SenderChain {
+                public_ratchet_key: RatchetPublicKey::from(ratchet_key).as_ref().to_bytes(),
+                secret_ratchet_key: Box::new(ratchet_key.secret_key().to_bytes()),
+                chain_key: Box::new(*chain_key.key()),
+                chain_key_index: chain_key.index() as u32,
+            }
+        }
+    }
+
+    #[derive(Debug, Encode, Decode, Zeroize)]
+    #[zeroize(drop)]
+    struct ReceivingChain {
+        public_ratchet_key: [u8; 32],
+        #[secret]
+        chain_key: Box<[u8; 32]>,
+        chain_key_index: u32,
+    }
+
+    impl From<&ReceivingChain> for ReceiverChain {
+        fn from(chain: &ReceivingChain) -> Self {
+            let ratchet_key = RemoteRatchetKey::from(chain.public_ratchet_key);
+            let chain_key = RemoteChainKey::from_bytes_and_index(
+                chain.chain_key.clone(),
+                chain.chain_key_index,
+            );
+
+            ReceiverChain::new(ratchet_key, chain_key)
+        }
+    }
+
+    impl From<&ReceiverChain> for ReceivingChain {
+        fn from(chain: &ReceiverChain) -> Self {
+            ReceivingChain {
+                public_ratchet_key: chain.ratchet_key().as_ref().to_bytes(),
+                chain_key: Box::new(*chain.chain_key().key()),
+                chain_key_index: chain.chain_key().chain_index() as u32,
+            }
+        }
+    }
+
+    #[derive(Debug, Encode, Decode, Zeroize)]
+    #[zeroize(drop)]
+    struct MessageKey {
+        ratchet_key: [u8; 32],
+        #[secret]
+        message_key: Box<[u8; 32]>,
+        index: u32,
+    }
+
+    impl From<&MessageKey> for RemoteMessageKey {
+        fn from(key: &MessageKey) -> Self {
+            RemoteMessageKey { key: key.message_key.clone(), index: key.index.into() }
+        }
+    }
+
+    #[derive(Encode)]
+    pub(super) struct Pickle {
+        version: u32,
+        received_message: bool,
+        session_keys: SessionKeys,
+        root_key: Box<[u8; 32]>,
+        sender_chains: Vec<SenderChain>,
+        receiver_chains: Vec<ReceivingChain>,
+        message_keys: Vec<MessageKey>,
+    }
+
+    impl Decode for Pickle {
+        fn decode(reader: &mut impl std::io::Read) -> Result<Self, DecodeError> {
+            let version = u32::decode(reader)?;
+            let received_message = bool::decode(reader)?;
+            let session_keys = SessionKeys::decode(reader)?;
+            let root_key = <Box<[u8; 32]>>::decode(reader)?;
+            let sender_chains = Vec::<SenderChain>::decode(reader)?;
+            let receiver_chains = Vec::<ReceivingChain>::decode(reader)?;
+            let message_keys = Vec::<MessageKey>::decode(reader)?;
+
+            if version == CHAIN_INDEX_PICKLE_VERSION {
+                // The chain index isn't used for anything, skip it.
+                let _chain_index = u32::decode(reader)?;
+            }
+
+            Ok(Self {
+                version,
+                received_message,
+                session_keys,
+                root_key,
+                sender_chains,
+                receiver_chains,
+                message_keys,
+            })
+        }
+    }
+
+    impl Drop for Pickle {
+        fn drop(&mut self) {
+            self.root_key.zeroize();
+            self.sender_chains.zeroize();
+            self.receiver_chains.zeroize();
+            self.message_keys.zeroize();
+        }
+    }
+
+    impl TryFrom<&Session> for Pickle {
+        type Error = LibolmPickleError;
+
+        fn try_from(session: &Session) -> Result<Self, Self::Error> {
+            if session.config.version != Version::V1 {
+                return Err(LibolmPickleError::UnsupportedSessionConfig);
+            }
+
+            let chains = &session.receiving_chains.inner;
+
+            // libolm keeps the newest receiver chain at the front.
+            let receiver_chains = chains.iter().rev().map(|c| c.into()).collect();
+
+            let mut message_keys: Vec<MessageKey> = chains
+                .iter()
+                .flat_map(|chain| {
+                    let ratchet_key = chain.ratchet_key().as_ref().to_bytes();
+
+                    chain.skipped_message_keys().iter().map(move |key| MessageKey {
+                        ratchet_key,
+                        message_key: key.key.clone(),
+                        index: key.index as u32,
+                    })
+                })
+                .collect();
+
+            // Keep only the newest message keys if libolm can't hold all of
+            // them.
+            let excess = message_keys.len().saturating_sub(MAX_MESSAGE_KEYS);
+            message_keys.drain(..excess);
+
+            Ok(Self {
+                version: PICKLE_VERSION,
+                received_message: session.has_received_message(),
+                session_keys: session.session_keys,
+                root_key: Box::new(*session.sending_ratchet.root_key()),
+                sender_chains: session
+                    .sending_ratchet
+                    .sender_chain()
+                    .map(|c| c.into())
+                    .into_iter()
+                    .collect(),
+                receiver_chains,
+                message_keys,
+            })
+        }
+    }
+
+    impl TryFrom<Pickle> for Session {
+        type Error = LibolmPickleError;
+
+        fn try_from(pickle: Pickle) -> Result<Self, Self::Error> {
+            let config = SessionConfig::version_1();
+            let mut receiving_chains = ChainStore::new();
+
+            // libolm keeps the newest receiver chain at the front, push the
+            // oldest one first so the newest chains are kept if there are more
+            // than we store.
+            for chain in pickle.receiver_chains.iter().rev() {
+                receiving_chains.push(chain.into(), config.max_receiving_chains())
+            }
+
+            for key in &pickle.message_keys {
+                let ratchet_key =
+                    RemoteRatchetKey::from(Curve25519PublicKey::from(key.ratchet_key));
+
+                if let Some(receiving_chain) = receiving_chains.find_ratchet_mut(&ratchet_key) {
+                    receiving_chain.insert_message_key(key.into(), config.max_message_keys())
+                }
+            }
+
+            if let Some(chain) = pickle.sender_chains.get(0) {
+                // XXX: Passing in secret array as value.
+                let ratchet_key = RatchetKey::from(Curve25519SecretKey::from_slice(
+                    chain.secret_ratchet_key.as_ref(),
+                ));
+                let chain_key =
+                    ChainKey::from_bytes_and_index(chain.chain_key.clone(), chain.chain_key_index);
+
+                let root_key = RootKey::new(pickle.root_key.clone());
+
+                let ratchet = Ratchet::new_with_ratchet_key(root_key, ratchet_key);
+                let sending_ratchet = DoubleRatchet::from_ratchet_and_chain_key(ratchet, chain_key);
+
+                Ok(Self {
+                    session_keys: pickle.session_keys,
+                    sending_ratchet,
+                    receiving_chains,
+                    config,
+                    message_counters: Default::default(),
+                })
+            } else if let Some(chain) = receiving_chains.newest() {
+                let sending_ratchet = DoubleRatchet::inactive(
+                    RemoteRootKey::new(pickle.root_key.clone()),
+                    chain.ratchet_key(),
+                );
+
+                Ok(Self {
+                    session_keys: pickle.session_keys,
+                    sending_ratchet,
+                    receiving_chains,
+                    config,
+                    message_counters: Default::default(),
+                })
+            } else {
+                Err(LibolmPickleError::InvalidSession)
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use anyhow::{bail, Result};
+    use anyhow::{bail, Context, Result};
     use olm_rs::{
         account::OlmAccount,
         session::{OlmMessage, OlmSession},
@@ -621,6 +1179,728 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_pickling() -> Result<()> {
+        let (_, _, mut session, olm) = sessions()?;
+
+        let message = olm.encrypt("Hello").into();
+        session.decrypt(&message)?;
+
+        let skipped_message = olm.encrypt("Skipped");
+        let message = olm.encrypt("Hello").into();
+        session.decrypt(&message)?;
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let pickle = session.to_libolm_pickle(key)?;
+
+        let unpickled =
+            OlmSession::unpickle(pickle, olm_rs::PicklingMode::Encrypted { key: key.to_vec() })?;
+
+        assert_eq!(session.session_id(), unpickled.session_id());
+        assert_eq!("Skipped", unpickled.decrypt(skipped_message)?);
+
+        let plaintext = "It's a secret to everybody";
+        let message = unpickled.encrypt(plaintext);
+        assert_eq!(plaintext, olm.decrypt(message)?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_pickling_roundtrip() -> Result<()> {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().expect("Missing one-time key");
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_1(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let key = b"DEFAULT_PICKLE_KEY";
+
+        // An outbound session which didn't yet receive a message only has a
+        // sending chain.
+        let pickle = alice_session.to_libolm_pickle(key)?;
+        let unpickled = Session::from_libolm_pickle(&pickle, key)?;
+        assert_eq!(alice_session.session_id(), unpickled.session_id());
+
+        let message = alice_session.encrypt("Hello");
+        let mut bob_session = if let crate::olm::OlmMessage::PreKey(m) = message {
+            bob.create_inbound_session(alice.curve25519_key(), &m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+
+        // An inbound session which didn't yet send a message only has a
+        // receiving chain.
+        let pickle = bob_session.to_libolm_pickle(key)?;
+        let unpickled = Session::from_libolm_pickle(&pickle, key)?;
+        assert_eq!(bob_session.session_id(), unpickled.session_id());
+
+        let skipped_message = alice_session.encrypt("Skipped");
+        let message = alice_session.encrypt("Hello");
+        bob_session.decrypt(&message)?;
+
+        let reply = bob_session.encrypt("Reply");
+        alice_session.decrypt(&reply)?;
+
+        let pickle = bob_session.to_libolm_pickle(key)?;
+        let mut unpickled = Session::from_libolm_pickle(&pickle, key)?;
+
+        assert_eq!(unpickled.decrypt(&skipped_message)?, b"Skipped");
+
+        let plaintext = "It's a secret to everybody";
+        let message = unpickled.encrypt(plaintext);
+        assert_eq!(alice_session.decrypt(&message)?, plaintext.as_bytes());
+
+        let session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+        assert!(matches!(
+            session.to_libolm_pickle(key),
+            Err(crate::LibolmPickleError::UnsupportedSessionConfig)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_pickling_keeps_receiving_chain_order() -> Result<()> {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().expect("Missing one-time key");
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_1(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let message = alice_session.encrypt("Hello");
+        let mut bob_session = if let crate::olm::OlmMessage::PreKey(m) = message {
+            bob.create_inbound_session(alice.curve25519_key(), &m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+
+        // Every reply ratchets the session forward, leaving Bob with more
+        // receiving chains than a session stores.
+        let mut skipped_message = None;
+
+        for _ in 0..7 {
+            let reply = bob_session.encrypt("Reply");
+            alice_session.decrypt(&reply)?;
+
+            skipped_message = Some(alice_session.encrypt("Skipped"));
+            let message = alice_session.encrypt("Hello");
+            bob_session.decrypt(&message)?;
+        }
+
+        let ratchet_keys = |session: &Session| -> Vec<Curve25519PublicKey> {
+            session.receiving_chains.inner.iter().map(|c| *c.ratchet_key().as_ref()).collect()
+        };
+
+        let key = b"DEFAULT_PICKLE_KEY";
+        let pickle = bob_session.to_libolm_pickle(key)?;
+        let mut unpickled = Session::from_libolm_pickle(&pickle, key)?;
+
+        assert_eq!(ratchet_keys(&bob_session).len(), 5);
+        assert_eq!(ratchet_keys(&unpickled), ratchet_keys(&bob_session));
+
+        // The newest chain still holds its skipped message key.
+        let skipped_message = skipped_message.context("Missing skipped message")?;
+        assert_eq!(unpickled.decrypt(&skipped_message)?, b"Skipped");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "libolm-compat")]
+    fn libolm_legacy_pickle_version() -> Result<()> {
+        use matrix_pickle::Encode;
+
+        use super::libolm::Pickle;
+        use crate::utilities::encrypt_libolm_pickle;
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().expect("Missing one-time key");
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_1(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let message = alice_session.encrypt("Hello");
+        let mut bob_session = if let crate::olm::OlmMessage::PreKey(m) = message {
+            bob.create_inbound_session(alice.curve25519_key(), &m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+
+        let key = b"DEFAULT_PICKLE_KEY";
+
+        // The legacy session pickle uses a different version and stores an
+        // additional chain index at the end of the pickle.
+        let mut encoded = Pickle::try_from(&alice_session)?.encode_to_vec()?;
+        encoded[..4].copy_from_slice(&0x8000_0001u32.to_be_bytes());
+        encoded.extend_from_slice(&0u32.to_be_bytes());
+
+        let pickle = encrypt_libolm_pickle(&encoded, key);
+        let mut unpickled = Session::from_libolm_pickle(&pickle, key)?;
+
+        assert_eq!(alice_session.session_id(), unpickled.session_id());
+
+        let plaintext = "It's a secret to everybody";
+        let message = unpickled.encrypt(plaintext);
+        assert_eq!(bob_session.decrypt(&message)?, plaintext.as_bytes());
+
+        // Unknown pickle versions are still rejected.
+        encoded[..4].copy_from_slice(&2u32.to_be_bytes());
+        let pickle = encrypt_libolm_pickle(&encoded, key);
+
+        assert!(matches!(
+            Session::from_libolm_pickle(&pickle, key),
+            Err(crate::LibolmPickleError::Version(1, 2))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_pre_key_message() -> Result<()> {
+        use crate::olm::OlmMessage;
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(2);
+
+        let mut pre_key_messages = Vec::new();
+
+        for one_time_key in bob.one_time_keys().into_values() {
+            let mut session = alice.create_outbound_session(
+                SessionConfig::version_2(),
+                bob.curve25519_key(),
+                one_time_key,
+            );
+
+            if let OlmMessage::PreKey(m) = session.encrypt("It's a secret to everybody") {
+                pre_key_messages.push(m);
+            } else {
+                bail!("Invalid message type");
+            }
+        }
+
+        let bob_session =
+            bob.create_inbound_session(alice.curve25519_key(), &pre_key_messages[0])?.session;
+
+        assert!(bob_session.matches_pre_key_message(&pre_key_messages[0]));
+        assert!(!bob_session.matches_pre_key_message(&pre_key_messages[1]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn pending_decryption() -> Result<()> {
+        use crate::olm::OlmMessage;
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let first = alice_session.encrypt("First");
+        let second = alice_session.encrypt("Second");
+
+        let mut bob_session = if let OlmMessage::PreKey(m) = &second {
+            bob.create_inbound_session(alice.curve25519_key(), m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+
+        // Dropping the pending decryption leaves the session untouched, the
+        // skipped message key is still there.
+        let pending = bob_session.decrypt_pending(&first)?;
+        assert_eq!(pending.plaintext(), b"First");
+        drop(pending);
+
+        assert_eq!(bob_session.decrypt_pending(&first)?.commit(), b"First");
+        bob_session.decrypt_pending(&first).expect_err("The message key should be used up");
+
+        // The same goes for a message of a new receiving chain.
+        alice_session.decrypt(&bob_session.encrypt("Reply"))?;
+        let message = alice_session.encrypt("Third");
+
+        bob_session.decrypt_pending(&message)?;
+        assert_eq!(bob_session.receiving_chains.len(), 1);
+
+        let pending = bob_session.decrypt_pending(&message)?;
+        assert_eq!(pending.commit(), b"Third");
+        assert_eq!(bob_session.receiving_chains.len(), 2);
+        bob_session.decrypt(&message).expect_err("The message key should be used up");
+
+        Ok(())
+    }
+
+    #[test]
+    fn decryption_info() -> Result<()> {
+        use crate::olm::OlmMessage;
+
+        fn ratchet_key(message: &OlmMessage) -> Curve25519PublicKey {
+            match message {
+                OlmMessage::Normal(m) => m.ratchet_key(),
+                OlmMessage::PreKey(m) => m.message().ratchet_key(),
+            }
+        }
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let first = alice_session.encrypt("First");
+        let second = alice_session.encrypt("Second");
+        let third = alice_session.encrypt("Third");
+
+        let mut bob_session = if let OlmMessage::PreKey(m) = &first {
+            bob.create_inbound_session(alice.curve25519_key(), m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+
+        let decrypted = bob_session.decrypt_with_info(&third)?;
+        assert_eq!(decrypted.plaintext, b"Third");
+        assert_eq!(decrypted.chain_index, 2);
+        assert_eq!(decrypted.ratchet_key, ratchet_key(&third));
+        assert!(!decrypted.out_of_order);
+        assert!(!decrypted.new_receiving_chain);
+
+        let decrypted = bob_session.decrypt_with_info(&second)?;
+        assert_eq!(decrypted.plaintext, b"Second");
+        assert_eq!(decrypted.chain_index, 1);
+        assert!(decrypted.out_of_order);
+        assert!(!decrypted.new_receiving_chain);
+
+        alice_session.decrypt(&bob_session.encrypt("Reply"))?;
+
+        let fourth = alice_session.encrypt("Fourth");
+        let decrypted = bob_session.decrypt_with_info(&fourth)?;
+        assert_eq!(decrypted.chain_index, 0);
+        assert_eq!(decrypted.ratchet_key, ratchet_key(&fourth));
+        assert_ne!(decrypted.ratchet_key, ratchet_key(&third));
+        assert!(!decrypted.out_of_order);
+        assert!(decrypted.new_receiving_chain);
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_stats() -> Result<()> {
+        use crate::olm::OlmMessage;
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let stats = alice_session.stats();
+        assert_eq!(stats.session_id, alice_session.session_id());
+        assert!(stats.sending_ratchet_active);
+        assert_eq!(stats.sending_chain_index, Some(0));
+        assert!(stats.receiving_chains.is_empty());
+
+        let _skipped = alice_session.encrypt("Skipped");
+        let message = alice_session.encrypt("Hello");
+
+        let mut bob_session = if let OlmMessage::PreKey(m) = &message {
+            bob.create_inbound_session(alice.curve25519_key(), m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+
+        let stats = bob_session.stats();
+        assert!(!stats.sending_ratchet_active);
+        assert_eq!(stats.sending_chain_index, None);
+        assert_eq!(stats.receiving_chains.len(), 1);
+        assert_eq!(stats.receiving_chains[0].chain_index, 2);
+        assert_eq!(stats.receiving_chains[0].skipped_message_keys, 1);
+        assert_eq!(stats.messages_sent, 0);
+        assert_eq!(stats.messages_received, 1);
+
+        // Receiving a new ratchet key deactivates the sending ratchet until
+        // the next message is encrypted.
+        alice_session.decrypt(&bob_session.encrypt("Reply"))?;
+        let stats = alice_session.stats();
+        assert!(!stats.sending_ratchet_active);
+        assert_eq!(stats.sending_chain_index, None);
+        assert_eq!(stats.receiving_chains.len(), 1);
+        assert_eq!(stats.messages_sent, 2);
+        assert_eq!(stats.messages_received, 1);
+
+        // The counters survive a pickle roundtrip.
+        let bob_session = Session::from_pickle(bob_session.pickle());
+        let stats = bob_session.stats();
+        assert!(stats.sending_ratchet_active);
+        assert_eq!(stats.sending_chain_index, Some(1));
+        assert_eq!(stats.messages_sent, 1);
+        assert_eq!(stats.messages_received, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn configurable_limits() -> Result<()> {
+        use super::DecryptionError;
+        use crate::olm::{OlmMessage, SessionConfigError};
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let messages: Vec<_> = (0..60).map(|i| alice_session.encrypt(i.to_string())).collect();
+
+        let mut bob_session = if let OlmMessage::PreKey(m) = &messages[59] {
+            bob.create_inbound_session(alice.curve25519_key(), m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+
+        // Only the last 40 skipped message keys are stored by default.
+        bob_session.decrypt(&messages[0]).expect_err("The message key should be gone");
+        assert_eq!(bob_session.decrypt(&messages[19])?, b"19");
+
+        let config =
+            SessionConfig::version_2().with_max_message_keys(100)?.with_max_message_gap(50)?;
+        bob_session.set_session_config(config)?;
+        assert!(matches!(
+            bob_session.set_session_config(SessionConfig::version_1()),
+            Err(SessionConfigError::VersionMismatch(2, 1))
+        ));
+
+        let messages: Vec<_> = (0..80).map(|i| alice_session.encrypt(i.to_string())).collect();
+
+        // The message gap is now limited to 50.
+        assert!(matches!(
+            bob_session.decrypt(&messages[79]),
+            Err(DecryptionError::TooBigMessageGap(79, 50))
+        ));
+        assert_eq!(bob_session.decrypt(&messages[49])?, b"49");
+
+        // All of the skipped message keys are still there.
+        assert_eq!(bob_session.decrypt(&messages[0])?, b"0");
+        assert_eq!(bob_session.stats().receiving_chains[0].skipped_message_keys, 87);
+
+        // The config, and with it the limits, gets pickled.
+        let bob_session = Session::from_pickle(bob_session.pickle());
+        assert_eq!(bob_session.session_config(), config);
+
+        Ok(())
+    }
+
+    #[test]
+    fn messages_since_ratchet_step() -> Result<()> {
+        use crate::olm::OlmMessage;
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+        assert_eq!(alice_session.messages_since_ratchet_step(), 0);
+
+        let message = alice_session.encrypt("Hello");
+        alice_session.encrypt("Hello again");
+        assert_eq!(alice_session.messages_since_ratchet_step(), 2);
+
+        let mut bob_session = if let OlmMessage::PreKey(m) = &message {
+            bob.create_inbound_session(alice.curve25519_key(), m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+        assert_eq!(bob_session.messages_since_ratchet_step(), 0);
+
+        // The reply of the other side lets us perform a ratchet step with our
+        // next message.
+        alice_session.decrypt(&bob_session.encrypt("Reply"))?;
+        assert_eq!(bob_session.messages_since_ratchet_step(), 1);
+        assert_eq!(alice_session.messages_since_ratchet_step(), 0);
+
+        alice_session.encrypt("Healed");
+        assert_eq!(alice_session.messages_since_ratchet_step(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn associated_data() -> Result<()> {
+        use crate::olm::{
+            DecryptionError, FailureKind, OlmMessage, SessionConfigError, SessionCreationError,
+        };
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_3(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let message = alice_session.encrypt_with_ad("Hello", "header")?;
+
+        let pre_key_message = if let OlmMessage::PreKey(m) = &message {
+            assert!(m.message().mac_covers_associated_data());
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+
+        assert!(matches!(
+            bob.create_inbound_session_with_ad(alice.curve25519_key(), pre_key_message, "other"),
+            Err(SessionCreationError::Decryption(DecryptionError::AssociatedDataMismatch))
+        ));
+
+        let result =
+            bob.create_inbound_session_with_ad(alice.curve25519_key(), pre_key_message, "header")?;
+        let mut bob_session = result.session;
+        assert_eq!(result.plaintext, b"Hello");
+        assert_eq!(bob_session.session_config(), SessionConfig::version_3());
+
+        let message = bob_session.encrypt_with_ad("Reply", "header")?;
+        let error = alice_session
+            .decrypt_with_ad(&message, "other")
+            .expect_err("Decryption with the wrong associated data should fail");
+        assert!(matches!(error, DecryptionError::AssociatedDataMismatch));
+        // Without a signature to check first, a wrong associated data looks
+        // exactly like a message from a session that is out of sync.
+        assert_eq!(FailureKind::from(&error), FailureKind::Permanent);
+        // A message whose MAC didn't match doesn't use up the message key.
+        assert!(matches!(
+            alice_session.decrypt(&message),
+            Err(DecryptionError::AssociatedDataMismatch)
+        ));
+        assert_eq!(alice_session.decrypt_with_ad(&message, "header")?, b"Reply");
+
+        // Encrypting without associated data is the same as using empty
+        // associated data.
+        let message = alice_session.encrypt("No associated data");
+        assert_eq!(bob_session.decrypt_with_ad(&message, "")?, b"No associated data");
+
+        // Older versions don't authenticate associated data at all.
+        bob.generate_one_time_keys(1);
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut session = alice.create_outbound_session(
+            SessionConfig::version_2(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+        assert!(matches!(
+            session.encrypt_with_ad("Hello", "header"),
+            Err(SessionConfigError::AssociatedDataUnsupported(2))
+        ));
+        let message = session.encrypt("Hello");
+        assert!(matches!(
+            session.decrypt_with_ad(&message, "header"),
+            Err(DecryptionError::AssociatedDataUnsupported(2))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn aead_session() -> Result<()> {
+        use crate::olm::{DecryptionError, OlmMessage};
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_4(),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let plaintext = "It's a secret to everybody";
+        let message = alice_session.encrypt(plaintext);
+
+        let bob_session = if let OlmMessage::PreKey(m) = &message {
+            // The ciphertext isn't padded.
+            assert!(m.message().uses_aead());
+            assert_eq!(m.message().ciphertext().len(), plaintext.len());
+
+            bob.create_inbound_session(alice.curve25519_key(), m)?.session
+        } else {
+            bail!("Invalid message type");
+        };
+        assert_eq!(bob_session.session_config(), SessionConfig::version_4());
+
+        // The session, including its version, survives a pickle roundtrip.
+        let mut bob_session = Session::from_pickle(SessionPickle::from_encrypted(
+            &bob_session.pickle().encrypt(&PICKLE_KEY),
+            &PICKLE_KEY,
+        )?);
+        assert_eq!(bob_session.session_config(), SessionConfig::version_4());
+
+        let message = bob_session.encrypt_with_ad("Reply", "header")?;
+
+        let mut tampered = if let OlmMessage::Normal(m) = &message {
+            m.clone()
+        } else {
+            bail!("Invalid message type");
+        };
+        tampered.chain_index += 1;
+        assert!(matches!(
+            alice_session.decrypt(&OlmMessage::Normal(tampered)),
+            Err(DecryptionError::AssociatedDataMismatch)
+        ));
+        assert!(matches!(
+            alice_session.decrypt_with_ad(&message, "other"),
+            Err(DecryptionError::AssociatedDataMismatch)
+        ));
+        assert_eq!(alice_session.decrypt_with_ad(&message, "header")?, b"Reply");
+
+        let message = alice_session.encrypt(plaintext);
+        assert_eq!(bob_session.decrypt(&message)?, plaintext.as_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn padded_session() -> Result<()> {
+        use crate::olm::{DecryptionError, OlmMessage, PaddingScheme};
+
+        let alice = Account::new();
+        let mut bob = Account::new();
+        bob.generate_one_time_keys(1);
+
+        let one_time_key = *bob.one_time_keys().values().next().context("Missing one-time key")?;
+        let mut alice_session = alice.create_outbound_session(
+            SessionConfig::version_5(PaddingScheme::PowerOfTwo),
+            bob.curve25519_key(),
+            one_time_key,
+        );
+
+        let plaintext = "It's a secret to everybody";
+        let message = alice_session.encrypt(plaintext);
+
+        let bob_session = if let OlmMessage::PreKey(m) = &message {
+            // The plaintext got padded to the minimal padded length.
+            assert!(m.message().is_padded());
+            assert_eq!(m.message().ciphertext().len(), 32);
+
+            let result = bob.create_inbound_session(alice.curve25519_key(), m)?;
+            assert_eq!(result.plaintext, plaintext.as_bytes());
+
+            result.session
+        } else {
+            bail!("Invalid message type");
+        };
+        assert_eq!(bob_session.session_config(), SessionConfig::version_5(PaddingScheme::Padme));
+
+        // The session, including its version and padding scheme, survives a
+        // pickle roundtrip.
+        let mut alice_session = Session::from_pickle(SessionPickle::from_encrypted(
+            &alice_session.pickle().encrypt(&PICKLE_KEY),
+            &PICKLE_KEY,
+        )?);
+        assert_eq!(
+            alice_session.session_config(),
+            SessionConfig::version_5(PaddingScheme::PowerOfTwo)
+        );
+
+        let mut bob_session = bob_session;
+        let reply = "A".repeat(100);
+        let message = bob_session.encrypt_with_ad(&reply, "header")?;
+
+        if let OlmMessage::Normal(m) = &message {
+            assert_eq!(m.ciphertext().len(), 104);
+        } else {
+            bail!("Invalid message type");
+        }
+        assert!(matches!(
+            alice_session.decrypt_with_ad(&message, "other"),
+            Err(DecryptionError::AssociatedDataMismatch)
+        ));
+        assert_eq!(alice_session.decrypt_with_ad(&message, "header")?, reply.as_bytes());
+
+        let message = alice_session.encrypt(&reply);
+        if let OlmMessage::Normal(m) = &message {
+            assert_eq!(m.ciphertext().len(), 128);
+        } else {
+            bail!("Invalid message type");
+        }
+        assert_eq!(bob_session.decrypt(&message)?, reply.as_bytes());
+
+        // The padding scheme of an existing session can be changed.
+        bob_session.set_session_config(SessionConfig::version_5(PaddingScheme::PowerOfTwo))?;
+        let message = bob_session.encrypt(&reply);
+        if let OlmMessage::Normal(m) = &message {
+            assert_eq!(m.ciphertext().len(), 128);
+        } else {
+            bail!("Invalid message type");
+        }
+        assert_eq!(alice_session.decrypt(&message)?, reply.as_bytes());
+
+        // Unpadded messages aren't accepted by a session expecting padded ones.
+        let mut tampered = if let OlmMessage::Normal(m) = bob_session.encrypt(&reply) {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+        tampered.version = 6;
+        assert!(matches!(
+            alice_session.decrypt(&OlmMessage::Normal(tampered)),
+            Err(DecryptionError::MessageVersionMismatch(6))
+        ));
+
+        Ok(())
+    }
+
     #[test]
     fn session_pickling_roundtrip_is_identity() -> Result<()> {
         let (_, _, session, _) = sessions()?;
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use matrix_pickle::Decode;
+use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 use x25519_dalek::SharedSecret;
 
@@ -34,13 +35,18 @@ pub struct RatchetPublicKey(Curve25519PublicKey);
 pub struct RemoteRatchetKey(Curve25519PublicKey);
 
 impl RatchetKey {
-    pub fn new() -> Self {
-        Self(Curve25519SecretKey::new())
+    pub fn new(rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        Self(Curve25519SecretKey::new_with_rng(rng))
     }
 
     pub fn diffie_hellman(&self, other: &RemoteRatchetKey) -> SharedSecret {
         self.0.diffie_hellman(&other.0)
     }
+
+    #[cfg(feature = "libolm-compat")]
+    pub fn secret_key(&self) -> &Curve25519SecretKey {
+        &self.0
+    }
 }
 
 impl From<Curve25519SecretKey> for RatchetKey {
@@ -73,6 +79,12 @@ impl AsRef<Curve25519PublicKey> for RatchetPublicKey {
     }
 }
 
+impl AsRef<Curve25519PublicKey> for RemoteRatchetKey {
+    fn as_ref(&self) -> &Curve25519PublicKey {
+        &self.0
+    }
+}
+
 impl From<&RatchetKey> for RatchetPublicKey {
     fn from(r: &RatchetKey) -> Self {
         RatchetPublicKey(Curve25519PublicKey::from(&r.0))
@@ -86,8 +98,8 @@ pub(super) struct Ratchet {
 }
 
 impl Ratchet {
-    pub fn new(root_key: RootKey) -> Self {
-        let ratchet_key = RatchetKey::new();
+    pub fn new(root_key: RootKey, rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        let ratchet_key = RatchetKey::new(rng);
 
         Self { root_key, ratchet_key }
     }
@@ -106,4 +118,9 @@ impl Ratchet {
     pub fn ratchet_key(&self) -> &RatchetKey {
         &self.ratchet_key
     }
+
+    #[cfg(feature = "libolm-compat")]
+    pub fn root_key(&self) -> &RootKey {
+        &self.root_key
+    }
 }
@@ -12,7 +12,6 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use arrayvec::ArrayVec;
 use serde::{Deserialize, Serialize};
 
 use super::{
@@ -21,30 +20,28 @@ use super::{
 };
 use crate::olm::{messages::Message, session_config::Version, SessionConfig};
 
-const MAX_MESSAGE_GAP: u64 = 2000;
-const MAX_MESSAGE_KEYS: usize = 40;
-
 #[derive(Serialize, Deserialize, Clone)]
-struct MessageKeyStore {
-    inner: ArrayVec<RemoteMessageKey, MAX_MESSAGE_KEYS>,
+pub(super) struct MessageKeyStore {
+    inner: Vec<RemoteMessageKey>,
 }
 
 impl MessageKeyStore {
     fn new() -> Self {
-        Self { inner: ArrayVec::new() }
+        Self { inner: Vec::new() }
     }
 
-    fn push(&mut self, message_key: RemoteMessageKey) {
-        if self.inner.is_full() {
-            self.inner.pop_at(0);
-        }
+    /// Store a message key, removing the oldest keys if more than `max_keys`
+    /// keys would be stored.
+    fn push(&mut self, message_key: RemoteMessageKey, max_keys: usize) {
+        let excess = (self.inner.len() + 1).saturating_sub(max_keys);
+        self.inner.drain(..excess.min(self.inner.len()));
 
         self.inner.push(message_key)
     }
 
-    fn merge(&mut self, mut store: MessageKeyStore) {
+    fn merge(&mut self, mut store: MessageKeyStore, max_keys: usize) {
         for key in store.inner.drain(..) {
-            self.push(key);
+            self.push(key, max_keys);
         }
     }
 
@@ -72,6 +69,7 @@ impl FoundMessageKey<'_> {
     fn decrypt(
         &self,
         message: &Message,
+        associated_data: &[u8],
         config: &SessionConfig,
     ) -> Result<Vec<u8>, DecryptionError> {
         let message_key = match self {
@@ -82,10 +80,21 @@ impl FoundMessageKey<'_> {
         match config.version {
             Version::V1 => message_key.decrypt_truncated_mac(message),
             Version::V2 => message_key.decrypt(message),
+            Version::V3 => message_key.decrypt_with_ad(message, associated_data),
+            Version::V4 => message_key.decrypt_aead(message, associated_data),
+            Version::V5 => message_key.decrypt_padded(message, associated_data),
         }
     }
 }
 
+/// The changes a successful decryption makes to a [`ReceiverChain`].
+pub(super) enum ChainUpdate {
+    /// A skipped message key was used up.
+    RemoveSkippedKey(u64),
+    /// The chain was advanced, possibly skipping some message keys.
+    Advance(Box<(RemoteChainKey, MessageKeyStore)>),
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub(super) struct ReceiverChain {
     ratchet_key: RemoteRatchetKey,
@@ -102,11 +111,16 @@ impl ReceiverChain {
         }
     }
 
-    fn find_message_key(&self, chain_index: u64) -> Result<FoundMessageKey<'_>, DecryptionError> {
+    fn find_message_key(
+        &self,
+        chain_index: u64,
+        config: &SessionConfig,
+    ) -> Result<FoundMessageKey<'_>, DecryptionError> {
         let message_gap = chain_index.saturating_sub(self.hkdf_ratchet.chain_index());
+        let max_message_keys = config.max_message_keys();
 
-        if message_gap > MAX_MESSAGE_GAP {
-            Err(DecryptionError::TooBigMessageGap(message_gap, MAX_MESSAGE_GAP))
+        if message_gap > config.max_message_gap() {
+            Err(DecryptionError::TooBigMessageGap(message_gap, config.max_message_gap()))
         } else if self.hkdf_ratchet.chain_index() > chain_index {
             self.skipped_message_keys
                 .get_message_key(chain_index)
@@ -118,11 +132,11 @@ impl ReceiverChain {
 
             // Advance the ratchet up until our desired point.
             while ratchet.chain_index() < chain_index {
-                if chain_index - ratchet.chain_index() > MAX_MESSAGE_KEYS as u64 {
+                if chain_index - ratchet.chain_index() > max_message_keys as u64 {
                     ratchet.advance();
                 } else {
                     let key = ratchet.create_message_key();
-                    skipped_keys.push(key);
+                    skipped_keys.push(key, max_message_keys);
                 }
             }
 
@@ -133,40 +147,73 @@ impl ReceiverChain {
         }
     }
 
-    pub fn decrypt(
-        &mut self,
+    /// Decrypt the message without modifying the chain, the returned
+    /// [`ChainUpdate`] needs to be applied using [`ReceiverChain::apply()`]
+    /// once the decryption should take effect.
+    ///
+    /// The `associated_data` is only checked if the [`SessionConfig`] uses
+    /// messages that authenticate associated data.
+    pub fn decrypt_pending(
+        &self,
         message: &Message,
+        associated_data: &[u8],
         config: &SessionConfig,
-    ) -> Result<Vec<u8>, DecryptionError> {
+    ) -> Result<(Vec<u8>, ChainUpdate), DecryptionError> {
         let chain_index = message.chain_index;
-        let message_key = self.find_message_key(chain_index)?;
+        let message_key = self.find_message_key(chain_index, config)?;
 
-        let plaintext = message_key.decrypt(message, config)?;
+        let plaintext = message_key.decrypt(message, associated_data, config)?;
 
-        match message_key {
-            FoundMessageKey::Existing(m) => {
-                let chain_index = m.chain_index();
-                self.skipped_message_keys.remove_message_key(chain_index)
-            }
+        let update = match message_key {
+            FoundMessageKey::Existing(m) => ChainUpdate::RemoveSkippedKey(m.chain_index()),
             FoundMessageKey::New(m) => {
                 let (ratchet, skipped_keys, _) = *m;
+                ChainUpdate::Advance(Box::new((ratchet, skipped_keys)))
+            }
+        };
+
+        Ok((plaintext, update))
+    }
+
+    pub fn apply(&mut self, update: ChainUpdate, config: &SessionConfig) {
+        match update {
+            ChainUpdate::RemoveSkippedKey(chain_index) => {
+                self.skipped_message_keys.remove_message_key(chain_index)
+            }
+            ChainUpdate::Advance(m) => {
+                let (ratchet, skipped_keys) = *m;
 
                 self.hkdf_ratchet = ratchet;
-                self.skipped_message_keys.merge(skipped_keys);
+                self.skipped_message_keys.merge(skipped_keys, config.max_message_keys());
             }
         }
-
-        Ok(plaintext)
     }
 
-    #[cfg(feature = "libolm-compat")]
     pub fn ratchet_key(&self) -> RemoteRatchetKey {
         self.ratchet_key
     }
 
+    pub fn chain_index(&self) -> u64 {
+        self.hkdf_ratchet.chain_index()
+    }
+
+    pub fn skipped_message_key_count(&self) -> usize {
+        self.skipped_message_keys.inner.len()
+    }
+
+    #[cfg(feature = "libolm-compat")]
+    pub fn chain_key(&self) -> &RemoteChainKey {
+        &self.hkdf_ratchet
+    }
+
+    #[cfg(feature = "libolm-compat")]
+    pub fn skipped_message_keys(&self) -> &[RemoteMessageKey] {
+        &self.skipped_message_keys.inner
+    }
+
     #[cfg(feature = "libolm-compat")]
-    pub fn insert_message_key(&mut self, message_key: RemoteMessageKey) {
-        self.skipped_message_keys.push(message_key)
+    pub fn insert_message_key(&mut self, message_key: RemoteMessageKey, max_keys: usize) {
+        self.skipped_message_keys.push(message_key, max_keys)
     }
 
     pub fn belongs_to(&self, ratchet_key: &RemoteRatchetKey) -> bool {
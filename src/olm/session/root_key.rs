@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use hkdf::Hkdf;
+use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use zeroize::Zeroize;
@@ -59,8 +60,9 @@ impl RemoteRootKey {
     pub(super) fn advance(
         &self,
         remote_ratchet_key: &RemoteRatchetKey,
+        rng: &mut (impl RngCore + CryptoRng),
     ) -> (RootKey, ChainKey, RatchetKey) {
-        let ratchet_key = RatchetKey::new();
+        let ratchet_key = RatchetKey::new(rng);
         let output = kdf(&self.key, &ratchet_key, remote_ratchet_key);
 
         let mut chain_key = Box::new([0u8; 32]);
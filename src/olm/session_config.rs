@@ -13,21 +13,108 @@
 // limitations under the License.
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cipher::PaddingScheme;
+
+/// The default maximum number of messages a single message may skip, see
+/// [`SessionConfig::max_message_gap()`].
+const DEFAULT_MAX_MESSAGE_GAP: u64 = 2000;
+/// The default number of skipped message keys a receiving chain stores, see
+/// [`SessionConfig::max_message_keys()`].
+const DEFAULT_MAX_MESSAGE_KEYS: usize = 40;
+/// The default number of receiving chains a session stores, see
+/// [`SessionConfig::max_receiving_chains()`].
+const DEFAULT_MAX_RECEIVING_CHAINS: usize = 5;
+
+/// Error type describing an invalid [`SessionConfig`] limit or an invalid
+/// change of a [`SessionConfig`].
+#[derive(Debug, Error)]
+pub enum SessionConfigError {
+    /// The maximum message gap is bigger than we allow.
+    #[error("The maximum message gap is too big, got {0}, max allowed {1}")]
+    TooBigMessageGap(u64, u64),
+    /// The number of skipped message keys to store is zero or bigger than we
+    /// allow.
+    #[error("The number of stored message keys needs to be between 1 and {1}, got {0}")]
+    InvalidMessageKeyCount(usize, usize),
+    /// The number of receiving chains to store is zero or bigger than we
+    /// allow.
+    #[error("The number of stored receiving chains needs to be between 1 and {1}, got {0}")]
+    InvalidReceivingChainCount(usize, usize),
+    /// The config uses a different Olm version than the session, the version
+    /// of an existing session can't be changed.
+    #[error("The Olm version of a session can't be changed, expected {0}, got {1}")]
+    VersionMismatch(u8, u8),
+    /// The session uses an Olm version whose messages don't authenticate
+    /// associated data.
+    #[error("Olm version {0} doesn't support associated data")]
+    AssociatedDataUnsupported(u8),
+    /// The config has a padding scheme while its Olm version doesn't pad
+    /// messages, or it's missing the padding scheme for a version that does.
+    #[error("The padding scheme doesn't match the Olm version {0}")]
+    PaddingSchemeMismatch(u8),
+}
 
 /// A struct to configure how Olm sessions should work under the hood.
-/// Currently only the MAC truncation behaviour can be configured.
+///
+/// The cipher, the MAC truncation behaviour, whether the MAC covers associated
+/// data and whether plaintexts get padded are decided by the version of the
+/// config, the limits on how many messages can be skipped and how many skipped
+/// message keys and receiving chains are kept around can be configured on top
+/// of that.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "SessionConfigPickle")]
 pub struct SessionConfig {
     pub(super) version: Version,
+    max_message_gap: u64,
+    max_message_keys: usize,
+    max_receiving_chains: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    padding_scheme: Option<PaddingScheme>,
+}
+
+fn default_max_message_gap() -> u64 {
+    DEFAULT_MAX_MESSAGE_GAP
+}
+
+fn default_max_message_keys() -> usize {
+    DEFAULT_MAX_MESSAGE_KEYS
+}
+
+fn default_max_receiving_chains() -> usize {
+    DEFAULT_MAX_RECEIVING_CHAINS
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub(super) enum Version {
     V1 = 1,
     V2 = 2,
+    V3 = 3,
+    V4 = 4,
+    V5 = 5,
 }
 
 impl SessionConfig {
+    /// The upper bound for [`SessionConfig::max_message_gap()`]. Every skipped
+    /// message costs a hash computation, allowing bigger gaps would let the
+    /// other side make us do an unbounded amount of work.
+    pub const MAX_MESSAGE_GAP_LIMIT: u64 = 100_000;
+    /// The upper bound for [`SessionConfig::max_message_keys()`].
+    pub const MAX_MESSAGE_KEYS_LIMIT: usize = 1000;
+    /// The upper bound for [`SessionConfig::max_receiving_chains()`].
+    pub const MAX_RECEIVING_CHAINS_LIMIT: usize = 20;
+
+    fn new(version: Version) -> Self {
+        SessionConfig {
+            version,
+            max_message_gap: DEFAULT_MAX_MESSAGE_GAP,
+            max_message_keys: DEFAULT_MAX_MESSAGE_KEYS,
+            max_receiving_chains: DEFAULT_MAX_RECEIVING_CHAINS,
+            padding_scheme: None,
+        }
+    }
+
     /// Get the numeric version of this `SessionConfig`.
     pub fn version(&self) -> u8 {
         self.version as u8
@@ -37,14 +124,125 @@ impl SessionConfig {
     /// use AES-256 and HMAC with a truncated MAC to encrypt individual
     /// messages. The MAC will be truncated to 8 bytes.
     pub fn version_1() -> Self {
-        SessionConfig { version: Version::V1 }
+        Self::new(Version::V1)
     }
 
     /// Create a `SessionConfig` for the Olm version 2. This version of Olm will
     /// use AES-256 and HMAC to encrypt individual messages. The MAC won't be
     /// truncated.
     pub fn version_2() -> Self {
-        SessionConfig { version: Version::V2 }
+        Self::new(Version::V2)
+    }
+
+    /// Create a `SessionConfig` for the Olm version 3. This version of Olm will
+    /// use AES-256 and HMAC to encrypt individual messages, the MAC won't be
+    /// truncated and additionally covers the associated data passed to
+    /// [`Session::encrypt_with_ad()`] and [`Session::decrypt_with_ad()`].
+    ///
+    /// [`Session::encrypt_with_ad()`]: crate::olm::Session::encrypt_with_ad
+    /// [`Session::decrypt_with_ad()`]: crate::olm::Session::decrypt_with_ad
+    pub fn version_3() -> Self {
+        Self::new(Version::V3)
+    }
+
+    /// Create a `SessionConfig` for the Olm version 4. This version of Olm will
+    /// use ChaCha20-Poly1305 to encrypt and authenticate individual messages.
+    /// The ciphertext isn't padded and a 16 byte authentication tag takes the
+    /// place of the MAC. Like version 3, this version supports associated
+    /// data.
+    pub fn version_4() -> Self {
+        Self::new(Version::V4)
+    }
+
+    /// Create a `SessionConfig` for the Olm version 5. This version of Olm
+    /// works like version 4, but the plaintext gets padded before it gets
+    /// encrypted, hiding its exact length. The given [`PaddingScheme`] decides
+    /// how much padding is added, the other side strips the padding
+    /// regardless of the scheme.
+    pub fn version_5(padding_scheme: PaddingScheme) -> Self {
+        Self { padding_scheme: Some(padding_scheme), ..Self::new(Version::V5) }
+    }
+
+    pub(super) fn supports_associated_data(&self) -> bool {
+        matches!(self.version, Version::V3 | Version::V4 | Version::V5)
+    }
+
+    /// The [`PaddingScheme`] messages get padded with, only used by the Olm
+    /// version 5.
+    pub fn padding_scheme(&self) -> Option<PaddingScheme> {
+        self.padding_scheme
+    }
+
+    /// The maximum number of messages a single message may skip in a
+    /// receiving chain, messages with a bigger gap are rejected with
+    /// [`DecryptionError::TooBigMessageGap`]. Defaults to 2000.
+    ///
+    /// [`DecryptionError::TooBigMessageGap`]: crate::olm::DecryptionError::TooBigMessageGap
+    pub fn max_message_gap(&self) -> u64 {
+        self.max_message_gap
+    }
+
+    /// The maximum number of skipped message keys a receiving chain stores to
+    /// decrypt messages that arrive out of order. Once the limit is reached,
+    /// the oldest message keys are thrown away. Defaults to 40.
+    pub fn max_message_keys(&self) -> usize {
+        self.max_message_keys
+    }
+
+    /// The maximum number of receiving chains a session stores. Once the limit
+    /// is reached, the oldest chain and its skipped message keys are thrown
+    /// away. Defaults to 5.
+    pub fn max_receiving_chains(&self) -> usize {
+        self.max_receiving_chains
+    }
+
+    /// Set the maximum message gap, it may be at most
+    /// [`SessionConfig::MAX_MESSAGE_GAP_LIMIT`].
+    pub fn with_max_message_gap(
+        mut self,
+        max_message_gap: u64,
+    ) -> Result<Self, SessionConfigError> {
+        if max_message_gap > Self::MAX_MESSAGE_GAP_LIMIT {
+            Err(SessionConfigError::TooBigMessageGap(max_message_gap, Self::MAX_MESSAGE_GAP_LIMIT))
+        } else {
+            self.max_message_gap = max_message_gap;
+            Ok(self)
+        }
+    }
+
+    /// Set the maximum number of stored skipped message keys per receiving
+    /// chain, it needs to be between 1 and
+    /// [`SessionConfig::MAX_MESSAGE_KEYS_LIMIT`].
+    pub fn with_max_message_keys(
+        mut self,
+        max_message_keys: usize,
+    ) -> Result<Self, SessionConfigError> {
+        if max_message_keys == 0 || max_message_keys > Self::MAX_MESSAGE_KEYS_LIMIT {
+            Err(SessionConfigError::InvalidMessageKeyCount(
+                max_message_keys,
+                Self::MAX_MESSAGE_KEYS_LIMIT,
+            ))
+        } else {
+            self.max_message_keys = max_message_keys;
+            Ok(self)
+        }
+    }
+
+    /// Set the maximum number of stored receiving chains, it needs to be
+    /// between 1 and [`SessionConfig::MAX_RECEIVING_CHAINS_LIMIT`].
+    pub fn with_max_receiving_chains(
+        mut self,
+        max_receiving_chains: usize,
+    ) -> Result<Self, SessionConfigError> {
+        if max_receiving_chains == 0 || max_receiving_chains > Self::MAX_RECEIVING_CHAINS_LIMIT {
+            Err(SessionConfigError::InvalidReceivingChainCount(
+                max_receiving_chains,
+                Self::MAX_RECEIVING_CHAINS_LIMIT,
+            ))
+        } else {
+            self.max_receiving_chains = max_receiving_chains;
+            Ok(self)
+        }
     }
 }
 
@@ -53,3 +251,118 @@ impl Default for SessionConfig {
         Self::version_2()
     }
 }
+
+/// The serialized form of [`SessionConfig`], older pickles don't contain the
+/// limits. The limits are checked against the same bounds the
+/// `SessionConfig::with_*()` methods enforce, and only the version 5 may, and
+/// has to, come with a padding scheme.
+#[derive(Deserialize)]
+struct SessionConfigPickle {
+    version: Version,
+    #[serde(default = "default_max_message_gap")]
+    max_message_gap: u64,
+    #[serde(default = "default_max_message_keys")]
+    max_message_keys: usize,
+    #[serde(default = "default_max_receiving_chains")]
+    max_receiving_chains: usize,
+    #[serde(default)]
+    padding_scheme: Option<PaddingScheme>,
+}
+
+impl TryFrom<SessionConfigPickle> for SessionConfig {
+    type Error = SessionConfigError;
+
+    fn try_from(pickle: SessionConfigPickle) -> Result<Self, Self::Error> {
+        if (pickle.version == Version::V5) != pickle.padding_scheme.is_some() {
+            return Err(SessionConfigError::PaddingSchemeMismatch(pickle.version as u8));
+        }
+
+        SessionConfig { padding_scheme: pickle.padding_scheme, ..Self::new(pickle.version) }
+            .with_max_message_gap(pickle.max_message_gap)?
+            .with_max_message_keys(pickle.max_message_keys)?
+            .with_max_receiving_chains(pickle.max_receiving_chains)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SessionConfig, SessionConfigError};
+    use crate::cipher::PaddingScheme;
+
+    #[test]
+    fn limits() -> Result<(), SessionConfigError> {
+        let config = SessionConfig::version_2();
+        assert_eq!(config.max_message_gap(), 2000);
+        assert_eq!(config.max_message_keys(), 40);
+        assert_eq!(config.max_receiving_chains(), 5);
+
+        let config = config
+            .with_max_message_gap(10_000)?
+            .with_max_message_keys(500)?
+            .with_max_receiving_chains(10)?;
+        assert_eq!(config.max_message_gap(), 10_000);
+        assert_eq!(config.max_message_keys(), 500);
+        assert_eq!(config.max_receiving_chains(), 10);
+        assert_eq!(config.version(), 2);
+
+        assert!(matches!(
+            config.with_max_message_gap(SessionConfig::MAX_MESSAGE_GAP_LIMIT + 1),
+            Err(SessionConfigError::TooBigMessageGap(..))
+        ));
+        assert!(matches!(
+            config.with_max_message_keys(0),
+            Err(SessionConfigError::InvalidMessageKeyCount(..))
+        ));
+        assert!(matches!(
+            config.with_max_receiving_chains(SessionConfig::MAX_RECEIVING_CHAINS_LIMIT + 1),
+            Err(SessionConfigError::InvalidReceivingChainCount(..))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_without_limits() -> Result<(), serde_json::Error> {
+        let config: SessionConfig = serde_json::from_str(r#"{"version":"V1"}"#)?;
+        assert_eq!(config, SessionConfig::version_1());
+
+        Ok(())
+    }
+
+    #[test]
+    fn deserialized_limits_are_checked() -> Result<(), serde_json::Error> {
+        let config = SessionConfig::version_2().with_max_message_gap(10_000).expect("Valid gap");
+        let deserialized: SessionConfig = serde_json::from_str(&serde_json::to_string(&config)?)?;
+        assert_eq!(deserialized, config);
+
+        for invalid in [
+            r#"{"version":"V2","max_message_gap":18446744073709551615}"#,
+            r#"{"version":"V2","max_message_keys":0}"#,
+            r#"{"version":"V2","max_receiving_chains":0}"#,
+            r#"{"version":"V2","max_receiving_chains":1000}"#,
+        ] {
+            serde_json::from_str::<SessionConfig>(invalid)
+                .expect_err("Limits outside of the allowed bounds should be rejected");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn deserialized_padding_scheme_is_checked() -> Result<(), serde_json::Error> {
+        let config = SessionConfig::version_5(PaddingScheme::PowerOfTwo);
+        let deserialized: SessionConfig = serde_json::from_str(&serde_json::to_string(&config)?)?;
+        assert_eq!(deserialized, config);
+
+        for invalid in [
+            r#"{"version":"V2","padding_scheme":"Padme"}"#,
+            r#"{"version":"V4","padding_scheme":"PowerOfTwo"}"#,
+            r#"{"version":"V5"}"#,
+        ] {
+            serde_json::from_str::<SessionConfig>(invalid)
+                .expect_err("A padding scheme not matching the version should be rejected");
+        }
+
+        Ok(())
+    }
+}
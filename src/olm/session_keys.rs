@@ -13,18 +13,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_pickle::Decode;
+use matrix_pickle::{Decode, DecodeError, Encode, EncodeError};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{utilities::base64_encode, Curve25519PublicKey};
 
+/// The kind of key the other side used as the one-time key to establish an Olm
+/// Session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OneTimeKeyKind {
+    /// It isn't known which kind of key was used. This is the case for
+    /// Sessions created from a bare Curve25519 key, or Sessions which were
+    /// restored from a pickle that didn't record the kind of the key.
+    #[default]
+    Unknown,
+    /// A one-time key, which has been used up by the Session.
+    OneTimeKey,
+    /// An unsigned fallback key.
+    FallbackKey,
+    /// A signed pre-key.
+    SignedPreKey,
+}
+
 /// The set of keys that were used to establish the Olm Session,
-#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Decode)]
+#[derive(Clone, Copy, Deserialize, Serialize)]
 pub struct SessionKeys {
     pub identity_key: Curve25519PublicKey,
     pub base_key: Curve25519PublicKey,
     pub one_time_key: Curve25519PublicKey,
+    #[serde(default)]
+    pub(crate) one_time_key_kind: OneTimeKeyKind,
 }
 
 impl SessionKeys {
@@ -48,6 +67,16 @@ impl SessionKeys {
 
         base64_encode(digest)
     }
+
+    /// The kind of key that was used as the `one_time_key`.
+    ///
+    /// This is local information, only the side which created the session
+    /// knows which kind of key was used. It isn't part of pre-key messages and
+    /// doesn't influence the session ID, so it's ignored when two
+    /// `SessionKeys` are compared.
+    pub fn one_time_key_kind(&self) -> OneTimeKeyKind {
+        self.one_time_key_kind
+    }
 }
 
 impl std::fmt::Debug for SessionKeys {
@@ -56,6 +85,41 @@ impl std::fmt::Debug for SessionKeys {
             .field("identity_key", &self.identity_key.to_base64())
             .field("base_key", &self.base_key.to_base64())
             .field("one_time_key", &self.one_time_key.to_base64())
+            .field("one_time_key_kind", &self.one_time_key_kind)
             .finish()
     }
 }
+
+// The kind of the one-time key is left out, the `SessionKeys` of the two sides
+// of a session, or of a session and its pre-key messages, need to compare
+// equal even though only one side knows the kind of the key.
+impl PartialEq for SessionKeys {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity_key == other.identity_key
+            && self.base_key == other.base_key
+            && self.one_time_key == other.one_time_key
+    }
+}
+
+impl Eq for SessionKeys {}
+
+impl Encode for SessionKeys {
+    fn encode(&self, writer: &mut impl std::io::Write) -> Result<usize, EncodeError> {
+        let mut ret = self.identity_key.encode(writer)?;
+        ret += self.base_key.encode(writer)?;
+        ret += self.one_time_key.encode(writer)?;
+
+        Ok(ret)
+    }
+}
+
+impl Decode for SessionKeys {
+    fn decode(reader: &mut impl std::io::Read) -> Result<Self, DecodeError> {
+        Ok(Self {
+            identity_key: Curve25519PublicKey::decode(reader)?,
+            base_key: Curve25519PublicKey::decode(reader)?,
+            one_time_key: Curve25519PublicKey::decode(reader)?,
+            one_time_key_kind: OneTimeKeyKind::Unknown,
+        })
+    }
+}
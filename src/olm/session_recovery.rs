@@ -0,0 +1,190 @@
+// Copyright 2026 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use super::DecryptionError;
+
+/// How likely it is that a decryption failure will go away on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The failure is likely caused by the message itself, for example a
+    /// message that was delivered twice. Later messages will likely decrypt
+    /// just fine.
+    Transient,
+    /// The failure suggests that the two sides of the session are out of sync,
+    /// for example because the other side lost its state. Later messages will
+    /// likely fail to decrypt as well.
+    Permanent,
+}
+
+impl From<&DecryptionError> for FailureKind {
+    fn from(error: &DecryptionError) -> Self {
+        match error {
+            // The message key was already used up, this is what a duplicate
+            // message looks like.
+            DecryptionError::MissingMessageKey(_) => FailureKind::Transient,
+            // This depends on the associated data the caller passed in, not on
+            // the state of the session.
+            DecryptionError::AssociatedDataUnsupported(_) => FailureKind::Transient,
+            // Messages which fail the MAC check were encrypted using keys we
+            // don't have, while a too big gap means that the chains drifted
+            // apart too far for us to catch up. A MAC covering associated data
+            // fails the same way if the session is out of sync, so a mismatch
+            // can't be assumed to be the fault of the caller.
+            DecryptionError::InvalidMAC(_)
+            | DecryptionError::AssociatedDataMismatch
+            | DecryptionError::InvalidMACLength(..)
+            | DecryptionError::InvalidPadding(_)
+            | DecryptionError::InvalidPlaintextPadding(_)
+            | DecryptionError::MessageVersionMismatch(_)
+            | DecryptionError::TooBigMessageGap(..) => FailureKind::Permanent,
+        }
+    }
+}
+
+/// The policy deciding when a [`SessionSet`] should recover from a wedged
+/// session by establishing a new one.
+///
+/// [`SessionSet`]: super::SessionSet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// The number of consecutive [`FailureKind::Permanent`] failures after
+    /// which a new session should be established.
+    ///
+    /// Anyone can send us a message which fails the MAC check, a single such
+    /// failure shouldn't be enough to replace a session. Defaults to 3.
+    pub permanent_failure_threshold: u32,
+    /// The number of consecutive failures, of either kind, after which a new
+    /// session should be established.
+    pub transient_failure_threshold: u32,
+    /// The minimal time between two recoveries. Every recovery uses up a
+    /// one-time key of the other side, undecryptable messages sent by an
+    /// attacker shouldn't be able to exhaust them.
+    pub min_recovery_interval: Duration,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            permanent_failure_threshold: 3,
+            transient_failure_threshold: 5,
+            min_recovery_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// The action that should be taken to recover from decryption failures,
+/// returned by [`SessionSet::recovery_action()`].
+///
+/// [`SessionSet::recovery_action()`]: super::SessionSet::recovery_action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryAction {
+    /// Nothing needs to be done, the active session isn't considered to be
+    /// wedged.
+    None,
+    /// The active session is wedged. A new session should be established,
+    /// using a freshly claimed one-time key of the other side, and a message,
+    /// for example a dummy message, should be sent over it so the other side
+    /// learns about the new session.
+    EstablishNewSession,
+    /// The active session is wedged, but the last recovery happened too
+    /// recently. A new session may be established once the given time has
+    /// been reached.
+    RateLimited {
+        /// The earliest time at which the next recovery may happen.
+        retry_at: SystemTime,
+    },
+}
+
+/// The consecutive decryption failures of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureStreak {
+    /// The number of consecutive failures, of either kind.
+    pub failures: u32,
+    /// The number of consecutive [`FailureKind::Permanent`] failures.
+    pub permanent_failures: u32,
+}
+
+impl FailureStreak {
+    pub(super) fn record(&mut self, kind: FailureKind) {
+        self.failures = self.failures.saturating_add(1);
+
+        match kind {
+            FailureKind::Transient => self.permanent_failures = 0,
+            FailureKind::Permanent => {
+                self.permanent_failures = self.permanent_failures.saturating_add(1)
+            }
+        }
+    }
+
+    pub(super) fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub(super) fn is_wedged(&self, policy: &RecoveryPolicy) -> bool {
+        self.permanent_failures >= policy.permanent_failure_threshold.max(1)
+            || self.failures >= policy.transient_failure_threshold.max(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FailureKind, FailureStreak, RecoveryPolicy};
+    use crate::olm::DecryptionError;
+
+    #[test]
+    fn failure_classification() {
+        assert_eq!(
+            FailureKind::from(&DecryptionError::MissingMessageKey(0)),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            FailureKind::from(&DecryptionError::TooBigMessageGap(3000, 2000)),
+            FailureKind::Permanent
+        );
+        assert_eq!(
+            FailureKind::from(&DecryptionError::InvalidMACLength(32, 8)),
+            FailureKind::Permanent
+        );
+    }
+
+    #[test]
+    fn failure_streak() {
+        let policy = RecoveryPolicy { permanent_failure_threshold: 2, ..Default::default() };
+        let mut streak = FailureStreak::default();
+
+        streak.record(FailureKind::Permanent);
+        assert!(!streak.is_wedged(&policy));
+
+        // A transient failure interrupts the streak of permanent failures.
+        streak.record(FailureKind::Transient);
+        streak.record(FailureKind::Permanent);
+        assert!(!streak.is_wedged(&policy));
+
+        streak.record(FailureKind::Permanent);
+        assert!(streak.is_wedged(&policy));
+        assert_eq!(streak.failures, 4);
+
+        streak.reset();
+        assert!(!streak.is_wedged(&policy));
+
+        for _ in 0..policy.transient_failure_threshold {
+            streak.record(FailureKind::Transient);
+        }
+        assert!(streak.is_wedged(&policy));
+    }
+}
@@ -0,0 +1,664 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    messages::OlmMessage,
+    session::Session,
+    session_recovery::{FailureStreak, RecoveryAction, RecoveryPolicy},
+    FailureKind, SessionPickle,
+};
+use crate::{
+    utilities::{pickle, unpickle},
+    Curve25519PublicKey, PickleError,
+};
+
+/// The maximal number of sessions a [`SessionSet`] keeps, the least recently
+/// active session gets removed once this number is exceeded.
+const MAX_SESSIONS: usize = 10;
+
+/// Error type describing failures when decrypting a message using a
+/// [`SessionSet`].
+#[derive(Error, Debug)]
+pub enum SessionSetError {
+    /// The pre-key message, or the inbound session, belongs to a different
+    /// identity key than the one of the [`SessionSet`].
+    #[error("The session belongs to a different identity key: expected {0}, got {1}")]
+    MismatchedIdentityKey(Curve25519PublicKey, Curve25519PublicKey),
+    /// None of the sessions in the [`SessionSet`] could decrypt the message.
+    #[error("None of the sessions could decrypt the message")]
+    NoMatchingSession,
+}
+
+/// The result of a successful decryption using a [`SessionSet`].
+#[derive(Debug)]
+pub struct SessionSetDecryptionResult {
+    /// The ID of the session that decrypted the message.
+    pub session_id: String,
+    /// The plaintext of the message.
+    pub plaintext: Vec<u8>,
+}
+
+struct SessionEntry {
+    session: Session,
+    /// The ID of the session, cached since calculating it requires hashing
+    /// the session keys.
+    session_id: String,
+    last_activity: u64,
+    failure_streak: FailureStreak,
+}
+
+/// A set of Olm [`Session`]s with a single other device, identified by its
+/// Curve25519 identity key.
+///
+/// The set decides which of its sessions should be used to encrypt messages,
+/// the so called active session. Both sides of a conversation use the same
+/// rules to pick the active session, so they will settle on the same session,
+/// similar to the [Sesame] algorithm:
+///
+/// * A session becomes the active one when we create it, or when it
+///   successfully decrypts a message.
+/// * If a session created by the other side arrives while our own active
+///   session has not yet received any messages, both sides created a session at
+///   the same time. In that case both sessions are considered equally recent
+///   and the one with the smaller session ID becomes the active one.
+///
+/// The set also keeps track of the decryption failures of its sessions, and
+/// decides when a wedged session should be replaced with a new one, see
+/// [`SessionSet::recovery_action()`].
+///
+/// [Sesame]: https://signal.org/docs/specifications/sesame/
+pub struct SessionSet {
+    identity_key: Curve25519PublicKey,
+    sessions: Vec<SessionEntry>,
+    activity_counter: u64,
+    last_recovery: Option<SystemTime>,
+}
+
+impl SessionSet {
+    /// Create a new, empty, `SessionSet` for the device with the given
+    /// Curve25519 identity key.
+    pub fn new(identity_key: Curve25519PublicKey) -> Self {
+        Self { identity_key, sessions: Vec::new(), activity_counter: 0, last_recovery: None }
+    }
+
+    /// The Curve25519 identity key of the other side of the sessions.
+    pub fn identity_key(&self) -> Curve25519PublicKey {
+        self.identity_key
+    }
+
+    /// The number of sessions in this set.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Is this set empty.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Iterate over all the sessions in this set, starting with the most
+    /// recently active one.
+    pub fn sessions(&self) -> impl Iterator<Item = &Session> {
+        self.sorted_entries().into_iter().map(|i| &self.sessions[i].session)
+    }
+
+    /// Get the session with the given session ID.
+    pub fn get(&self, session_id: &str) -> Option<&Session> {
+        self.position(session_id).map(|i| &self.sessions[i].session)
+    }
+
+    /// Get the session with the given session ID mutably.
+    pub fn get_mut(&mut self, session_id: &str) -> Option<&mut Session> {
+        self.position(session_id).map(move |i| &mut self.sessions[i].session)
+    }
+
+    /// Remove the session with the given session ID from the set.
+    pub fn remove(&mut self, session_id: &str) -> Option<Session> {
+        self.position(session_id).map(|i| self.sessions.remove(i).session)
+    }
+
+    /// Add a session we created, using [`Account::create_outbound_session()`],
+    /// to the set. The session becomes the active session.
+    ///
+    /// [`Account::create_outbound_session()`]: super::Account::create_outbound_session
+    pub fn insert_outbound(&mut self, session: Session) {
+        let last_activity = self.next_activity();
+        self.insert(session, last_activity);
+    }
+
+    /// Add a session the other side created, using
+    /// [`Account::create_inbound_session()`], to the set.
+    ///
+    /// The session usually becomes the active session, unless we created a
+    /// session at the same time, see the [`SessionSet`] docs for the details.
+    ///
+    /// Returns an error if the session was created by a different device than
+    /// the one this set belongs to.
+    ///
+    /// [`Account::create_inbound_session()`]: super::Account::create_inbound_session
+    pub fn insert_inbound(&mut self, session: Session) -> Result<(), SessionSetError> {
+        let identity_key = session.session_keys().identity_key;
+
+        if identity_key != self.identity_key {
+            return Err(SessionSetError::MismatchedIdentityKey(self.identity_key, identity_key));
+        }
+
+        let last_activity = match self.active_entry() {
+            // Our own session has not been confirmed by the other side yet, so
+            // both sides created a session at the same time. Let the tie-break
+            // decide which one wins.
+            Some(active) if !active.session.has_received_message() => active.last_activity,
+            _ => self.next_activity(),
+        };
+
+        self.insert(session, last_activity);
+
+        Ok(())
+    }
+
+    /// Get the active session, the session that should be used to encrypt
+    /// messages.
+    pub fn active_session(&self) -> Option<&Session> {
+        self.active_entry().map(|e| &e.session)
+    }
+
+    /// Get the active session mutably, the session that should be used to
+    /// encrypt messages.
+    pub fn active_session_mut(&mut self) -> Option<&mut Session> {
+        let index = self.sorted_entries().into_iter().next()?;
+        Some(&mut self.sessions[index].session)
+    }
+
+    /// Encrypt the `plaintext` using the active session.
+    ///
+    /// Returns `None` if the set doesn't contain any sessions.
+    pub fn encrypt(&mut self, plaintext: impl AsRef<[u8]>) -> Option<OlmMessage> {
+        self.active_session_mut().map(|s| s.encrypt(plaintext))
+    }
+
+    /// Try to decrypt the message using the sessions in this set.
+    ///
+    /// Pre-key messages are only decrypted by the session they belong to,
+    /// normal messages are tried with every session, starting with the most
+    /// recently active one. The session that decrypted the message becomes the
+    /// active session.
+    ///
+    /// If none of the sessions can decrypt the message, the failure is
+    /// recorded for the session the message was most likely meant for, the
+    /// session of the pre-key message or the active session respectively.
+    pub fn decrypt(
+        &mut self,
+        message: &OlmMessage,
+    ) -> Result<SessionSetDecryptionResult, SessionSetError> {
+        let candidates = match message {
+            OlmMessage::PreKey(m) => {
+                if m.identity_key() != self.identity_key {
+                    return Err(SessionSetError::MismatchedIdentityKey(
+                        self.identity_key,
+                        m.identity_key(),
+                    ));
+                }
+
+                self.position(&m.session_id()).into_iter().collect()
+            }
+            OlmMessage::Normal(_) => self.sorted_entries(),
+        };
+
+        let mut first_failure = None;
+
+        for &index in &candidates {
+            match self.sessions[index].session.decrypt(message) {
+                Ok(plaintext) => {
+                    let activity = self.next_activity();
+                    let entry = &mut self.sessions[index];
+
+                    entry.last_activity = activity;
+                    entry.failure_streak.reset();
+
+                    let session_id = entry.session_id.clone();
+
+                    return Ok(SessionSetDecryptionResult { session_id, plaintext });
+                }
+                Err(e) => {
+                    first_failure.get_or_insert(FailureKind::from(&e));
+                }
+            }
+        }
+
+        if let (Some(&index), Some(kind)) = (candidates.first(), first_failure) {
+            self.sessions[index].failure_streak.record(kind);
+        }
+
+        Err(SessionSetError::NoMatchingSession)
+    }
+
+    /// Get the consecutive decryption failures of the session with the given
+    /// session ID.
+    pub fn failure_streak(&self, session_id: &str) -> Option<FailureStreak> {
+        self.position(session_id).map(|i| self.sessions[i].failure_streak)
+    }
+
+    /// Decide if the active session is wedged and a new session should be
+    /// established, `now` being the current time.
+    ///
+    /// A session is considered to be wedged once its streak of decryption
+    /// failures reaches one of the thresholds of the [`RecoveryPolicy`]. This
+    /// usually happens if the other side lost its state, it will keep on
+    /// sending messages we can't decrypt until a new session is established.
+    ///
+    /// Recoveries are rate-limited for the whole set: if a recovery was
+    /// recommended within the last [`RecoveryPolicy::min_recovery_interval`],
+    /// [`RecoveryAction::RateLimited`] is returned instead. This way
+    /// undecryptable messages can't be used to make us exhaust the one-time
+    /// keys of the other side.
+    ///
+    /// Once [`RecoveryAction::EstablishNewSession`] has been returned, the
+    /// recovery is considered to be done, the new session should be added to
+    /// the set using [`SessionSet::insert_outbound()`].
+    pub fn recovery_action(&mut self, policy: &RecoveryPolicy, now: SystemTime) -> RecoveryAction {
+        let index = match self.sorted_entries().into_iter().next() {
+            Some(i) if self.sessions[i].failure_streak.is_wedged(policy) => i,
+            _ => return RecoveryAction::None,
+        };
+
+        let retry_at =
+            self.last_recovery.and_then(|last| last.checked_add(policy.min_recovery_interval));
+
+        match retry_at {
+            Some(retry_at) if now < retry_at => RecoveryAction::RateLimited { retry_at },
+            _ => {
+                self.last_recovery = Some(now);
+                self.sessions[index].failure_streak.reset();
+
+                RecoveryAction::EstablishNewSession
+            }
+        }
+    }
+
+    /// Convert the set into a struct which implements [`serde::Serialize`]
+    /// and [`serde::Deserialize`].
+    pub fn pickle(&self) -> SessionSetPickle {
+        SessionSetPickle {
+            identity_key: self.identity_key,
+            sessions: self
+                .sessions
+                .iter()
+                .map(|e| SessionEntryPickle {
+                    session: e.session.pickle(),
+                    last_activity: e.last_activity,
+                    failure_streak: e.failure_streak,
+                })
+                .collect(),
+            activity_counter: self.activity_counter,
+            last_recovery: self.last_recovery,
+        }
+    }
+
+    /// Restore a [`SessionSet`] from a previously saved [`SessionSetPickle`].
+    pub fn from_pickle(pickle: SessionSetPickle) -> Self {
+        pickle.into()
+    }
+
+    fn next_activity(&mut self) -> u64 {
+        self.activity_counter += 1;
+        self.activity_counter
+    }
+
+    fn insert(&mut self, session: Session, last_activity: u64) {
+        let session_id = session.session_id();
+
+        if let Some(index) = self.position(&session_id) {
+            self.sessions.remove(index);
+        }
+
+        self.sessions.push(SessionEntry {
+            session,
+            session_id,
+            last_activity,
+            failure_streak: FailureStreak::default(),
+        });
+
+        if self.sessions.len() > MAX_SESSIONS {
+            if let Some(index) = self.sorted_entries().pop() {
+                self.sessions.remove(index);
+            }
+        }
+    }
+
+    fn position(&self, session_id: &str) -> Option<usize> {
+        self.sessions.iter().position(|e| e.session_id == session_id)
+    }
+
+    fn active_entry(&self) -> Option<&SessionEntry> {
+        self.sorted_entries().into_iter().next().map(|i| &self.sessions[i])
+    }
+
+    /// The indices of the sessions, sorted by their last activity, most recent
+    /// first, ties are broken by picking the smaller session ID.
+    fn sorted_entries(&self) -> Vec<usize> {
+        let mut entries: Vec<_> = self
+            .sessions
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (e.last_activity, e.session_id.as_str(), i))
+            .collect();
+
+        entries.sort_unstable_by(|(a_activity, a_id, _), (b_activity, b_id, _)| {
+            b_activity.cmp(a_activity).then_with(|| a_id.cmp(b_id))
+        });
+
+        entries.into_iter().map(|(_, _, i)| i).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionEntryPickle {
+    session: SessionPickle,
+    last_activity: u64,
+    #[serde(default)]
+    failure_streak: FailureStreak,
+}
+
+/// A format suitable for serialization which implements [`serde::Serialize`]
+/// and [`serde::Deserialize`]. Obtainable by calling [`SessionSet::pickle`].
+#[derive(Serialize, Deserialize)]
+pub struct SessionSetPickle {
+    identity_key: Curve25519PublicKey,
+    sessions: Vec<SessionEntryPickle>,
+    activity_counter: u64,
+    #[serde(default)]
+    last_recovery: Option<SystemTime>,
+}
+
+impl SessionSetPickle {
+    /// Serialize and encrypt the pickle using the given key.
+    ///
+    /// This is the inverse of [`SessionSetPickle::from_encrypted`].
+    pub fn encrypt(self, pickle_key: &[u8; 32]) -> String {
+        pickle(&self, pickle_key)
+    }
+
+    /// Obtain a pickle from a ciphertext by decrypting and deserializing using
+    /// the given key.
+    ///
+    /// This is the inverse of [`SessionSetPickle::encrypt`].
+    pub fn from_encrypted(ciphertext: &str, pickle_key: &[u8; 32]) -> Result<Self, PickleError> {
+        unpickle(ciphertext, pickle_key)
+    }
+}
+
+impl From<SessionSetPickle> for SessionSet {
+    fn from(pickle: SessionSetPickle) -> Self {
+        Self {
+            identity_key: pickle.identity_key,
+            sessions: pickle
+                .sessions
+                .into_iter()
+                .map(|e| {
+                    let session = Session::from_pickle(e.session);
+
+                    SessionEntry {
+                        session_id: session.session_id(),
+                        session,
+                        last_activity: e.last_activity,
+                        failure_streak: e.failure_streak,
+                    }
+                })
+                .collect(),
+            activity_counter: pickle.activity_counter,
+            last_recovery: pickle.last_recovery,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use anyhow::{bail, Result};
+
+    use super::{SessionSet, SessionSetError, SessionSetPickle, MAX_SESSIONS};
+    use crate::olm::{
+        Account, FailureStreak, OlmMessage, RecoveryAction, RecoveryPolicy, Session, SessionConfig,
+    };
+
+    const PICKLE_KEY: [u8; 32] = [0u8; 32];
+
+    fn create_session(sender: &Account, receiver: &mut Account) -> Result<(Session, Session)> {
+        receiver.generate_one_time_keys(1);
+        let one_time_key = *receiver.one_time_keys().values().next().expect("Missing one-time key");
+        receiver.mark_keys_as_published();
+
+        let mut outbound = sender.create_outbound_session(
+            SessionConfig::version_2(),
+            receiver.curve25519_key(),
+            one_time_key,
+        );
+
+        let message = if let OlmMessage::PreKey(m) = outbound.encrypt("Hello") {
+            m
+        } else {
+            bail!("Invalid message type");
+        };
+
+        let inbound = receiver.create_inbound_session(sender.curve25519_key(), &message)?.session;
+
+        Ok((outbound, inbound))
+    }
+
+    #[test]
+    fn encryption_roundtrip() -> Result<()> {
+        let mut alice = Account::new();
+        let mut bob = Account::new();
+
+        let mut alice_set = SessionSet::new(bob.curve25519_key());
+        let mut bob_set = SessionSet::new(alice.curve25519_key());
+
+        assert!(alice_set.encrypt("Hello").is_none());
+
+        let (outbound, inbound) = create_session(&alice, &mut bob)?;
+        alice_set.insert_outbound(outbound);
+        bob_set.insert_inbound(inbound)?;
+
+        let message = bob_set.encrypt("It's a secret to everybody").expect("Missing session");
+        let result = alice_set.decrypt(&message)?;
+        assert_eq!(result.plaintext, b"It's a secret to everybody");
+
+        // A second session, created by Bob, takes over once it's used.
+        let (outbound, inbound) = create_session(&bob, &mut alice)?;
+        let new_session_id = outbound.session_id();
+        bob_set.insert_outbound(outbound);
+        alice_set.insert_inbound(inbound)?;
+
+        assert_eq!(alice_set.len(), 2);
+        assert_eq!(bob_set.active_session().map(|s| s.session_id()), Some(new_session_id.clone()));
+        assert_eq!(
+            alice_set.active_session().map(|s| s.session_id()),
+            Some(new_session_id.clone())
+        );
+
+        // A message arriving on the older session makes it active again.
+        let old_session_id = alice_set.sessions().nth(1).expect("Missing session").session_id();
+        let message =
+            bob_set.get_mut(&old_session_id).expect("Missing session").encrypt("Old session");
+
+        let result = alice_set.decrypt(&message)?;
+        assert_eq!(result.session_id, old_session_id);
+        assert_eq!(alice_set.active_session().map(|s| s.session_id()), Some(old_session_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn simultaneous_session_creation() -> Result<()> {
+        let mut alice = Account::new();
+        let mut bob = Account::new();
+
+        let mut alice_set = SessionSet::new(bob.curve25519_key());
+        let mut bob_set = SessionSet::new(alice.curve25519_key());
+
+        let (alice_outbound, bob_inbound) = create_session(&alice, &mut bob)?;
+        let (bob_outbound, alice_inbound) = create_session(&bob, &mut alice)?;
+
+        let expected = std::cmp::min(alice_outbound.session_id(), bob_outbound.session_id());
+
+        alice_set.insert_outbound(alice_outbound);
+        bob_set.insert_outbound(bob_outbound);
+        alice_set.insert_inbound(alice_inbound)?;
+        bob_set.insert_inbound(bob_inbound)?;
+
+        // Both sides pick the same session.
+        assert_eq!(alice_set.active_session().map(|s| s.session_id()), Some(expected.clone()));
+        assert_eq!(bob_set.active_session().map(|s| s.session_id()), Some(expected.clone()));
+
+        let message = alice_set.encrypt("Hello").expect("Missing session");
+        let result = bob_set.decrypt(&message)?;
+        assert_eq!(result.session_id, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pre_key_messages_from_other_devices_are_rejected() -> Result<()> {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        let malory = Account::new();
+
+        let (mut outbound, inbound) = create_session(&alice, &mut bob)?;
+        let mut set = SessionSet::new(malory.curve25519_key());
+
+        assert!(matches!(
+            set.insert_inbound(inbound),
+            Err(SessionSetError::MismatchedIdentityKey(..))
+        ));
+        assert!(set.is_empty());
+
+        let message = outbound.encrypt("Hello");
+        assert!(matches!(set.decrypt(&message), Err(SessionSetError::MismatchedIdentityKey(..))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_limit() -> Result<()> {
+        let alice = Account::new();
+        let mut bob = Account::new();
+
+        let mut set = SessionSet::new(bob.curve25519_key());
+        let (first, _) = create_session(&alice, &mut bob)?;
+        let first_session_id = first.session_id();
+        set.insert_outbound(first);
+
+        for _ in 0..MAX_SESSIONS {
+            let (session, _) = create_session(&alice, &mut bob)?;
+            set.insert_outbound(session);
+        }
+
+        assert_eq!(set.len(), MAX_SESSIONS);
+        assert!(set.get(&first_session_id).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn wedged_session_recovery() -> Result<()> {
+        let alice = Account::new();
+        let mut bob = Account::new();
+        let malory = Account::new();
+
+        let policy = RecoveryPolicy::default();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let (mut outbound, inbound) = create_session(&alice, &mut bob)?;
+        let session_id = outbound.session_id();
+        let mut set = SessionSet::new(alice.curve25519_key());
+        set.insert_inbound(inbound)?;
+
+        // A message the session can't decrypt, like the ones the other side
+        // sends after losing its state.
+        let (mut unrelated, _) = create_session(&malory, &mut bob)?;
+        let undecryptable = if let OlmMessage::PreKey(m) = unrelated.encrypt("Wedged") {
+            OlmMessage::Normal(m.message().clone())
+        } else {
+            bail!("Invalid message type");
+        };
+
+        assert_eq!(set.recovery_action(&policy, now), RecoveryAction::None);
+        assert!(set.decrypt(&undecryptable).is_err());
+        assert_eq!(
+            set.failure_streak(&session_id),
+            Some(FailureStreak { failures: 1, permanent_failures: 1 })
+        );
+
+        // A single undecryptable message isn't enough to consider the session
+        // to be wedged.
+        assert_eq!(set.recovery_action(&policy, now), RecoveryAction::None);
+
+        for _ in 1..policy.permanent_failure_threshold {
+            assert!(set.decrypt(&undecryptable).is_err());
+        }
+
+        assert_eq!(set.recovery_action(&policy, now), RecoveryAction::EstablishNewSession);
+        assert_eq!(set.recovery_action(&policy, now), RecoveryAction::None);
+
+        // Further recoveries are rate-limited, even across a pickle roundtrip.
+        for _ in 0..policy.permanent_failure_threshold {
+            assert!(set.decrypt(&undecryptable).is_err());
+        }
+        let mut set = SessionSet::from_pickle(set.pickle());
+
+        let retry_at = now + policy.min_recovery_interval;
+        assert_eq!(
+            set.recovery_action(&policy, now + Duration::from_secs(60)),
+            RecoveryAction::RateLimited { retry_at }
+        );
+        assert_eq!(set.recovery_action(&policy, retry_at), RecoveryAction::EstablishNewSession);
+
+        // A successfully decrypted message ends the streak.
+        assert!(set.decrypt(&undecryptable).is_err());
+        set.decrypt(&outbound.encrypt("Hello"))?;
+        assert_eq!(set.failure_streak(&session_id), Some(FailureStreak::default()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn pickling_roundtrip() -> Result<()> {
+        let mut alice = Account::new();
+        let mut bob = Account::new();
+
+        let mut set = SessionSet::new(bob.curve25519_key());
+        let (outbound, _) = create_session(&alice, &mut bob)?;
+        let (_, inbound) = create_session(&bob, &mut alice)?;
+        set.insert_outbound(outbound);
+        set.insert_inbound(inbound)?;
+
+        let pickle = set.pickle().encrypt(&PICKLE_KEY);
+        let unpickled =
+            SessionSet::from_pickle(SessionSetPickle::from_encrypted(&pickle, &PICKLE_KEY)?);
+
+        assert_eq!(unpickled.identity_key(), set.identity_key());
+        assert_eq!(
+            unpickled.sessions().map(|s| s.session_id()).collect::<Vec<_>>(),
+            set.sessions().map(|s| s.session_id()).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+}
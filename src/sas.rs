@@ -53,7 +53,7 @@
 
 use hkdf::Hkdf;
 use hmac::{digest::MacError, Hmac, Mac as _};
-use rand::thread_rng;
+use rand::{thread_rng, CryptoRng, RngCore};
 use sha2::Sha256;
 use thiserror::Error;
 use x25519_dalek::{EphemeralSecret, SharedSecret};
@@ -221,8 +221,16 @@ impl Sas {
     /// This creates an ephemeral curve25519 keypair that can be used to
     /// establish a shared secret.
     pub fn new() -> Self {
-        let rng = thread_rng();
+        Self::new_with_rng(&mut thread_rng())
+    }
 
+    /// Create a new verification object using the given random number
+    /// generator.
+    ///
+    /// This is only useful to create deterministic test vectors, the ephemeral
+    /// key of a verification should otherwise always be random, use
+    /// [`Sas::new()`] instead.
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng)) -> Self {
         let secret_key = EphemeralSecret::new(rng);
         let public_key = Curve25519PublicKey::from(&secret_key);
 
@@ -441,6 +449,29 @@ mod test {
         );
     }
 
+    #[test]
+    fn deterministic_sas() -> Result<()> {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let alice = Sas::new_with_rng(&mut StdRng::seed_from_u64(1));
+        let other_alice = Sas::new_with_rng(&mut StdRng::seed_from_u64(1));
+        let bob = Sas::new_with_rng(&mut StdRng::seed_from_u64(2));
+
+        assert_eq!(alice.public_key(), other_alice.public_key());
+        assert_ne!(alice.public_key(), bob.public_key());
+
+        let bob_public_key = bob.public_key();
+        let established = alice.diffie_hellman(bob_public_key)?;
+        let other_established = other_alice.diffie_hellman(bob_public_key)?;
+
+        assert_eq!(
+            established.bytes("AGREEMENT").emoji_indices(),
+            other_established.bytes("AGREEMENT").emoji_indices()
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn libolm_and_vodozemac_generate_same_bytes() -> Result<()> {
         let mut olm = OlmSas::new();
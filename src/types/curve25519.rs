@@ -14,8 +14,8 @@
 
 use std::fmt::Display;
 
-use matrix_pickle::{Decode, DecodeError};
-use rand::thread_rng;
+use matrix_pickle::{Decode, DecodeError, Encode, EncodeError};
+use rand::{thread_rng, CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 use x25519_dalek::{EphemeralSecret, PublicKey, ReusableSecret, SharedSecret, StaticSecret};
 
@@ -30,8 +30,12 @@ pub struct Curve25519SecretKey(Box<StaticSecret>);
 impl Curve25519SecretKey {
     /// Generate a new, random, Curve25519SecretKey.
     pub fn new() -> Self {
-        let rng = thread_rng();
+        Self::new_with_rng(&mut thread_rng())
+    }
 
+    /// Generate a new Curve25519SecretKey using the given random number
+    /// generator.
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng)) -> Self {
         Self(Box::new(StaticSecret::new(rng)))
     }
 
@@ -72,14 +76,13 @@ pub(crate) struct Curve25519Keypair {
 }
 
 impl Curve25519Keypair {
-    pub fn new() -> Self {
-        let secret_key = Curve25519SecretKey::new();
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        let secret_key = Curve25519SecretKey::new_with_rng(rng);
         let public_key = Curve25519PublicKey::from(&secret_key);
 
         Self { secret_key, public_key }
     }
 
-    #[cfg(feature = "libolm-compat")]
     pub fn from_secret_key(key: &[u8; 32]) -> Self {
         let secret_key = Curve25519SecretKey::from_slice(key);
         let public_key = Curve25519PublicKey::from(&secret_key);
@@ -111,6 +114,12 @@ impl Decode for Curve25519PublicKey {
     }
 }
 
+impl Encode for Curve25519PublicKey {
+    fn encode(&self, writer: &mut impl std::io::Write) -> Result<usize, EncodeError> {
+        self.as_bytes().encode(writer)
+    }
+}
+
 impl Curve25519PublicKey {
     /// The number of bytes a Curve25519 public key has.
     pub const LENGTH: usize = 32;
@@ -20,12 +20,15 @@ use ed25519_dalek::{
     ExpandedSecretKey, Keypair, PublicKey, SecretKey, Signature, PUBLIC_KEY_LENGTH,
     SIGNATURE_LENGTH,
 };
-use rand::thread_rng;
+use rand::{thread_rng, CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use zeroize::Zeroize;
 
-use crate::utilities::{base64_decode, base64_encode};
+use crate::{
+    canonical_json::{self, JsonSignatureError},
+    utilities::{base64_decode, base64_encode},
+};
 
 /// Error type describing signature verification failures.
 #[derive(Debug, Error)]
@@ -50,13 +53,25 @@ pub struct Ed25519Keypair {
 impl Ed25519Keypair {
     /// Create a new, random, `Ed25519Keypair`.
     pub fn new() -> Self {
-        let mut rng = thread_rng();
-        let keypair = Keypair::generate(&mut rng);
+        Self::new_with_rng(&mut thread_rng())
+    }
+
+    /// Create a new `Ed25519Keypair` using the given random number generator.
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        let keypair = Keypair::generate(rng);
 
         Self { secret_key: keypair.secret.into(), public_key: Ed25519PublicKey(keypair.public) }
     }
 
-    #[cfg(feature = "libolm-compat")]
+    /// Create a `Ed25519Keypair` from the given 32 bytes of a secret key.
+    pub(crate) fn from_secret_key(secret_key: &[u8; 32]) -> Self {
+        let secret_key = SecretKey::from_bytes(secret_key)
+            .expect("A 32 byte array should always be a valid Ed25519 secret key");
+        let public_key = Ed25519PublicKey(PublicKey::from(&secret_key));
+
+        Self { secret_key: secret_key.into(), public_key }
+    }
+
     pub(crate) fn from_expanded_key(secret_key: &[u8; 64]) -> Result<Self, crate::KeyError> {
         let secret_key = ExpandedSecretKey::from_bytes(secret_key).map_err(SignatureError::from)?;
         let public_key = Ed25519PublicKey(PublicKey::from(&secret_key));
@@ -64,6 +79,22 @@ impl Ed25519Keypair {
         Ok(Self { secret_key: secret_key.into(), public_key })
     }
 
+    /// Get the secret key in its expanded form, the form libolm uses to
+    /// persist Ed25519 keys.
+    pub(crate) fn expanded_secret_key(&self) -> Box<[u8; 64]> {
+        let mut key = Box::new([0u8; 64]);
+
+        let mut bytes = match &self.secret_key {
+            SecretKeys::Normal(k) => ExpandedSecretKey::from(k.as_ref()).to_bytes(),
+            SecretKeys::Expanded(k) => k.to_bytes(),
+        };
+
+        key.copy_from_slice(&bytes);
+        bytes.zeroize();
+
+        key
+    }
+
     /// Get the public Ed25519 key of this keypair.
     pub fn public_key(&self) -> Ed25519PublicKey {
         self.public_key
@@ -89,8 +120,13 @@ pub struct Ed25519SecretKey(Box<SecretKey>);
 impl Ed25519SecretKey {
     /// Create a new random `Ed25519SecretKey`.
     pub fn new() -> Self {
-        let mut rng = thread_rng();
-        let key = Box::new(SecretKey::generate(&mut rng));
+        Self::new_with_rng(&mut thread_rng())
+    }
+
+    /// Create a new `Ed25519SecretKey` using the given random number
+    /// generator.
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng)) -> Self {
+        let key = Box::new(SecretKey::generate(rng));
 
         Self(key)
     }
@@ -258,6 +294,23 @@ impl Ed25519PublicKey {
     ) -> Result<(), SignatureError> {
         Ok(())
     }
+
+    /// Verify the signature of a signed JSON object.
+    ///
+    /// The signature is taken from the
+    /// `signatures.{user_id}.{ed25519:device_id}` field of the object, it's
+    /// checked against the [canonical JSON] form of the object without its
+    /// `signatures` and `unsigned` fields.
+    ///
+    /// [canonical JSON]: https://spec.matrix.org/v1.2/appendices/#canonical-json
+    pub fn verify_json(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        value: &serde_json::Value,
+    ) -> Result<(), JsonSignatureError> {
+        canonical_json::verify_json(self, user_id, device_id, value)
+    }
 }
 
 impl Display for Ed25519PublicKey {
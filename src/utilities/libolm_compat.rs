@@ -14,10 +14,10 @@
 
 use std::io::Cursor;
 
-use matrix_pickle::Decode;
+use matrix_pickle::{Decode, Encode};
 use zeroize::Zeroize;
 
-use super::base64_decode;
+use super::{base64_decode, base64_encode};
 use crate::{cipher::Cipher, LibolmPickleError};
 
 /// Decrypt and decode the given pickle with the given pickle key.
@@ -26,12 +26,13 @@ use crate::{cipher::Cipher, LibolmPickleError};
 ///
 /// * pickle - The base64-encoded and encrypted libolm pickle string
 /// * pickle_key - The key that was used to encrypt the libolm pickle
-/// * pickle_version - The expected version of the pickle. Unpickling will fail
-///   if the version in the pickle doesn't match this one.
+/// * pickle_versions - The pickle versions the pickle type `P` knows how to
+///   decode, starting with the current version. Unpickling will fail if the
+///   version in the pickle isn't one of them.
 pub(crate) fn unpickle_libolm<P: Decode, T: TryFrom<P, Error = LibolmPickleError>>(
     pickle: &str,
     pickle_key: &[u8],
-    pickle_version: u32,
+    pickle_versions: &[u32],
 ) -> Result<T, LibolmPickleError> {
     /// Fetch the pickle version from the given pickle source.
     fn get_version(source: &[u8]) -> Option<u32> {
@@ -50,22 +51,52 @@ pub(crate) fn unpickle_libolm<P: Decode, T: TryFrom<P, Error = LibolmPickleError
     let mut decrypted = cipher.decrypt_pickle(&decoded)?;
 
     // A pickle starts with a version, which will decide how we need to decode.
-    // We only support the latest version so bail out if it isn't the expected
-    // pickle version.
+    // The pickle type itself dispatches on the version, so we only need to
+    // bail out if it isn't one of the versions the pickle type supports.
     let version = get_version(&decrypted).ok_or(LibolmPickleError::MissingVersion)?;
 
-    if version == pickle_version {
+    if pickle_versions.contains(&version) {
         let mut cursor = Cursor::new(&decrypted);
         let pickle = P::decode(&mut cursor)?;
 
         decrypted.zeroize();
         pickle.try_into()
     } else {
-        Err(LibolmPickleError::Version(pickle_version, version))
+        let current_version = pickle_versions.first().copied().unwrap_or_default();
+        Err(LibolmPickleError::Version(current_version, version))
     }
 }
 
-#[derive(Zeroize, Decode)]
+/// Encode and encrypt the given pickle with the given pickle key.
+///
+/// This is the inverse of [`unpickle_libolm`], the pickle is expected to
+/// contain the pickle version as its first field.
+///
+/// # Arguments
+///
+/// * pickle - The libolm pickle that should be encoded and encrypted
+/// * pickle_key - The key that should be used to encrypt the libolm pickle
+pub(crate) fn pickle_libolm<P: Encode>(
+    pickle: P,
+    pickle_key: &[u8],
+) -> Result<String, LibolmPickleError> {
+    let mut encoded = pickle.encode_to_vec()?;
+    let encrypted = encrypt_libolm_pickle(&encoded, pickle_key);
+
+    encoded.zeroize();
+
+    Ok(encrypted)
+}
+
+/// Encrypt an already encoded libolm pickle and encode it as base64.
+pub(crate) fn encrypt_libolm_pickle(pickle: &[u8], pickle_key: &[u8]) -> String {
+    let cipher = Cipher::new_pickle(pickle_key);
+    let encrypted = cipher.encrypt_pickle(pickle);
+
+    base64_encode(encrypted)
+}
+
+#[derive(Zeroize, Encode, Decode)]
 #[zeroize(drop)]
 pub(crate) struct LibolmEd25519Keypair {
     pub public_key: [u8; 32],
@@ -17,8 +17,10 @@
 mod libolm_compat;
 
 pub use base64::DecodeError;
+#[cfg(all(test, feature = "libolm-compat"))]
+pub(crate) use libolm_compat::encrypt_libolm_pickle;
 #[cfg(feature = "libolm-compat")]
-pub(crate) use libolm_compat::{unpickle_libolm, LibolmEd25519Keypair};
+pub(crate) use libolm_compat::{pickle_libolm, unpickle_libolm, LibolmEd25519Keypair};
 
 /// Decode the input as base64 with no padding.
 pub fn base64_decode(input: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodeError> {
@@ -78,6 +80,14 @@ pub(crate) fn extract_mac(slice: &[u8], truncated: bool) -> crate::cipher::Messa
     }
 }
 
+pub(crate) fn extract_tag(slice: &[u8]) -> crate::cipher::MessageMac {
+    use crate::cipher::AeadCipher;
+
+    let mut tag = [0u8; AeadCipher::TAG_LENGTH];
+    tag.copy_from_slice(&slice[0..AeadCipher::TAG_LENGTH]);
+    tag.into()
+}
+
 // The integer encoding logic here has been taken from the integer-encoding[1]
 // crate and is under the MIT license.
 //